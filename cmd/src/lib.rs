@@ -3,28 +3,44 @@ use clap::Parser;
 
 #[derive(Parser)]
 pub struct Cli {
-    pub path: Option<PathBuf>,
-    #[arg(trailing_var_arg = true)]
-    pub args: Vec<String>
+    /// How many functions can be running at once, before calls raise a stack overflow error
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+    /// The script, followed by its arguments, which are passed to it as they are
+    /// Options after the script are the script's, not the interpreter's
+    #[arg(value_name = "SCRIPT [ARGS]", trailing_var_arg = true, allow_hyphen_values = true)]
+    script: Vec<String>,
 }
 
 impl Cli {
+    /// The path of the script to run
+    pub fn path(&self) -> Option<PathBuf> {
+        self.script.first().map(PathBuf::from)
+    }
+
+    /// The arguments given to the script
+    pub fn script_args(&self) -> &[String] {
+        self.script.get(1..).unwrap_or_default()
+    }
+
     // TODO: Errors!
     pub fn read(&self) -> Option<String> {
-        if let Some(path) = &self.path {
+        if let Some(path) = &self.path() {
             read_to_string(path).ok()
         } else { None }
     }
 
     pub fn args(&self) -> Vec<(i64, String)> {
         let mut idx = 1;
-        let mut v = self.args.iter().map(|s| {
+        let mut v = self.script_args().iter().map(|s| {
             idx += 1;
             (idx - 1, s.to_string())
         }).collect::<Vec<_>>();
-        if let Some(p) = self.path.as_ref() {
-            v.push((0, p.to_str().unwrap().to_string()));
+        if let Some(p) = self.script.first() {
+            v.push((0, p.to_string()));
         }
         v
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests;
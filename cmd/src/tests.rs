@@ -0,0 +1,25 @@
+// test parsing the command line
+
+use clap::Parser;
+
+use crate::Cli;
+
+fn parse(args: &[&str]) -> Cli {
+    Cli::try_parse_from(["lua"].iter().chain(args)).unwrap_or_else(|err| panic!("{args:?} failed to parse: {err}"))
+}
+
+#[test]
+fn options_stop_at_script() {
+    let cli = parse(&["--max-depth", "5", "r.lua", "--max-depth", "6", "x"]);
+    assert_eq!(cli.max_depth, Some(5));
+    assert_eq!(cli.path(), Some("r.lua".into()));
+    assert_eq!(cli.script_args(), ["--max-depth", "6", "x"]);
+    assert_eq!(cli.args(), [(1, "--max-depth".to_string()), (2, "6".to_string()), (3, "x".to_string()), (0, "r.lua".to_string())]);
+}
+
+#[test]
+fn dash_arguments() {
+    let cli = parse(&["r.lua", "--foo", "-x", "--", "-"]);
+    assert_eq!(cli.path(), Some("r.lua".into()));
+    assert_eq!(cli.script_args(), ["--foo", "-x", "--", "-"]);
+}
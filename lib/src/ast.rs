@@ -1,6 +1,6 @@
-use std::{collections::HashMap, fmt::Display};
+use std::fmt::Display;
 
-use crate::{ast::context::Ctx, lexer::{identifier::Identifier, keyword::Keyword, seperator, Lexeme, Lexer}, value::Value};
+use crate::{ast::{context::Ctx, error::LuaError}, lexer::{identifier::Identifier, seperator, Lexeme}, parser::{error::ParseError, Parser}, value::Value};
 
 /// I'm making this a trait for right now, obviously when we want to speed it up it can be made an enum
 pub trait AstNode : Display {
@@ -8,12 +8,42 @@ pub trait AstNode : Display {
 }
 
 pub mod context;
+pub mod error;
 pub mod function;
 pub mod statement;
 pub mod expression;
 
+#[cfg(test)]
+mod tests;
+
 // Maybe move these to a submodule?
 
+/// How execution continues after running a statement or block
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlFlow {
+    /// Continue with the next statement
+    Normal,
+    /// Leave the function with these values
+    Return(Vec<Value>),
+    /// Leave the function, and have the caller make this call in its place
+    TailCall(Box<function::TailCall>),
+    /// Exit the innermost loop
+    Break,
+    /// Continue after the label in the innermost block that has it
+    Goto(Identifier),
+}
+
+impl ControlFlow {
+    /// How execution continues after a loop whose body ended with this, or `None` if the loop keeps going
+    pub fn loop_exit(self) -> Option<ControlFlow> {
+        match self {
+            ControlFlow::Normal => None,
+            ControlFlow::Break => Some(ControlFlow::Normal),
+            flow => Some(flow),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Block {
     statements: Vec<statement::Statement>
@@ -24,24 +54,33 @@ impl Block {
         Block { statements: Vec::new() }
     }
 
-    fn initial(base: statement::Statement) -> Block {
-        Block { statements: vec![base] }
-    }
-
     pub fn push_statement(&mut self, st: statement::Statement) {
         self.statements.push(st);
     }
 
-    pub fn parse(lex: &mut Lexer) -> Option<Block> {
-        // try to parse at least one statement
-        if let Some(base) = statement::parse_statement(lex) {
-            let mut block = Block::initial(base);
-            while let Some(st) = statement::parse_statement(lex) {
-                block.push_statement(st);
-            }
-            Some(block)
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Parse statements up to the end of the block
+    /// The lexeme closing the block (`end`, `else`, ...) is left for the caller to consume
+    pub fn parse(lex: &mut Parser) -> Result<Block, ParseError> {
+        // locals declared in the block go out of scope at its end
+        let scope = lex.scope_len();
+        let block = Block::parse_statements(lex);
+        lex.end_scope(scope);
+        block
+    }
+
+    fn parse_statements(lex: &mut Parser) -> Result<Block, ParseError> {
+        let mut block = Block::empty();
+        while let Some(st) = statement::parse_statement(lex)? {
+            let is_return = matches!(st.kind(), statement::StatementKind::Return(_));
+            block.push_statement(st);
+            // return has to be the last statement in a block
+            if is_return { break; }
         }
-        else { None }
+        Ok(block)
     } 
 
     pub fn print_tree(&self, depth: usize) {
@@ -53,16 +92,62 @@ impl Block {
         println!("{tabs}]");
     }
 
-    pub fn walk(&self, ctx: &mut Ctx) {
+    pub fn walk(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
         ctx.enter_block();
-        for st in &self.statements {
-            st.walk(ctx);
-            if ctx.did_return() {
-                ctx.leave_block_noreturn();
-                return;
+        let flow = self.walk_statements(ctx);
+        ctx.leave_block(flow)
+    }
+
+    /// Run the block, then evaluate `test` before leaving the block's scope, as `repeat ... until` does
+    /// Returns `None` if the test failed, or how execution continues if the test passed or the block exited early
+    pub fn walk_until(&self, test: &expression::Expression, ctx: &mut Ctx) -> Result<Option<ControlFlow>, LuaError> {
+        ctx.enter_block();
+        let flow = match self.walk_statements(ctx) {
+            Ok(ControlFlow::Normal) => test.eval(ctx).map(|val| val.as_bool().then_some(ControlFlow::Normal)),
+            flow => flow.map(Some),
+        };
+        ctx.leave_block(flow)
+    }
+
+    /// Run the block with `locals` declared in its scope, as loop variables are
+    pub fn walk_with_locals(&self, locals: impl IntoIterator<Item = (Identifier, Value)>, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        ctx.enter_block();
+        for (ident, val) in locals {
+            ctx.new_local(ident, val);
+        }
+        let flow = self.walk_statements(ctx);
+        ctx.leave_block(flow)
+    }
+
+    /// Run the statements in the current scope, stopping early if one returns or jumps out of the block
+    fn walk_statements(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        let mut next = 0;
+        // where each label was first reached, so jumping back to it drops, and closes, the locals declared after it
+        let mut label_marks = Vec::new();
+        while let Some(st) = self.statements.get(next) {
+            if let statement::StatementKind::Label(_) = st.kind() {
+                match label_marks.iter().find(|(idx, _)| *idx == next) {
+                    Some(&(_, mark)) => ctx.rewind_scope(mark)?,
+                    None => label_marks.push((next, ctx.scope_mark())),
+                }
+            }
+            next += 1;
+            ctx.set_position(st.span());
+            match st.walk(ctx)? {
+                ControlFlow::Normal => {},
+                ControlFlow::Goto(label) => match self.find_label(&label) {
+                    Some(target) => next = target,
+                    None => return Ok(ControlFlow::Goto(label)),
+                },
+                flow => return Ok(flow),
             }
         }
-        ctx.leave_block_noreturn();
+        Ok(ControlFlow::Normal)
+    }
+
+    /// The index of the statement defining `label` in this block
+    fn find_label(&self, label: &Identifier) -> Option<usize> {
+        self.statements.iter().position(|st| matches!(st.kind(), statement::StatementKind::Label(l) if l == label))
     }
 }
 
@@ -76,22 +161,29 @@ impl Display for Block {
     }
 }
 
-pub fn parse_comma_list<T, F: Fn(&mut Lexer) -> Option<T>, P: Fn(&Lexeme) -> bool>
-    (lex: &mut Lexer, parse_func: F, predicate: P) -> Option<Vec<T>> 
+/// Parse a list of comma seperated items, followed by the lexeme `close`
+pub fn parse_comma_list<T, F: Fn(&mut Parser) -> Result<T, ParseError>>
+    (lex: &mut Parser, parse_func: F, close: Lexeme) -> Result<Vec<T>, ParseError> 
 {
     let mut items = Vec::new();
+    if lex.peek_lexeme().as_ref() == Some(&close) {
+        lex.next();
+        return Ok(items);
+    }
     // parse items
-    while let Some(lexeme) = lex.clone().peekable().peek() && !predicate(lexeme) {
+    loop {
         items.push(parse_func(lex)?);
-        if lex.clone().peekable().peek() == Some(&Lexeme::Seperator(seperator::Seperator::Comma)) {
-            lex.next();
+        match lex.peek_lexeme() {
+            Some(Lexeme::Seperator(seperator::Seperator::Comma)) => { lex.next(); },
+            Some(lexeme) if lexeme == close => {
+                lex.next();
+                return Ok(items);
+            },
+            _ => return Err(ParseError::expected(&format!("'{}'", close.raw()), lex)),
         }
     }
-
-    assert!(predicate(&lex.next().unwrap()));
-    Some(items)
 }
 
-pub fn parse_paren_list<T, F: Fn(&mut Lexer) -> Option<T>>(lex: &mut Lexer, parse_func: F) -> Option<Vec<T>> {
-    parse_comma_list(lex, parse_func, |lexeme| lexeme == &Lexeme::Seperator(seperator::Seperator::CloseParen))
+pub fn parse_paren_list<T, F: Fn(&mut Parser) -> Result<T, ParseError>>(lex: &mut Parser, parse_func: F) -> Result<Vec<T>, ParseError> {
+    parse_comma_list(lex, parse_func, Lexeme::Seperator(seperator::Seperator::CloseParen))
 }
@@ -1,71 +1,419 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, rc::Rc};
 
-use crate::{lexer::identifier::Identifier, value::Value};
+use crate::{ast::{error::LuaError, function::call_value}, lexer::{identifier::Identifier, span::Span}, value::{meta::{get_metatable, metamethod}, table::{index, index_error, set_index, Table}, Value}};
 
+/// A local variable
+/// Closures share the locals they capture, so a local can outlive the block declaring it
+pub type Local = Rc<RefCell<Value>>;
+
+/// The locals declared in one block
+#[derive(Default)]
+struct Scope {
+    locals: RefCell<Vec<(Identifier, Local)>>,
+    /// The values of the block's `<close>` locals, which are closed when the block ends
+    to_close: RefCell<Vec<Value>>,
+    /// The enclosing scope, as it was when this block began
+    parent: Option<Env>,
+    /// Whether this is the outermost block of a function body, so the enclosing locals are upvalues
+    is_function: bool,
+    /// How many frames there were when the block began, which its `<close>` values are closed at
+    frame_depth: usize,
+}
+
+/// The locals visible at some point of the program: a scope, and the enclosing scopes
+/// Only the locals declared before that point are visible, so a closure can't see locals declared after it
+#[derive(Clone)]
+pub struct Env {
+    scope: Rc<Scope>,
+    /// How many of the scope's locals are visible
+    len: usize,
+}
+
+impl Env {
+    /// The environment of a chunk, which sees no locals, just `env` as its `_ENV` upvalue
+    pub fn chunk(env: Value) -> Env {
+        let locals = vec![(Identifier::from("_ENV"), Rc::new(RefCell::new(env)))];
+        Env { scope: Rc::new(Scope { locals: RefCell::new(locals), ..Scope::default() }), len: 1 }
+    }
+
+    fn get(&self, ident: &Identifier) -> Option<Local> {
+        let mut env = self;
+        loop {
+            let locals = env.scope.locals.borrow();
+            // later declarations shadow earlier ones
+            if let Some((_, local)) = locals[..env.len].iter().rev().find(|(name, _)| name == ident) {
+                return Some(local.clone());
+            }
+            env = env.scope.parent.as_ref()?;
+        }
+    }
+
+    /// How error messages describe the variable `ident`: `"local"`, `"upvalue"`, or `"global"`
+    fn kind_of(&self, ident: &Identifier) -> &'static str {
+        let mut env = self;
+        let mut kind = "local";
+        loop {
+            if env.scope.locals.borrow()[..env.len].iter().any(|(name, _)| name == ident) {
+                return kind;
+            }
+            if env.scope.is_function {
+                kind = "upvalue";
+            }
+            match &env.scope.parent {
+                Some(parent) => env = parent,
+                None => return "global",
+            }
+        }
+    }
+}
+
+/// A point in a block, as how many locals and `<close>` values the block had there, for a backward `goto` to go back to
+#[derive(Clone, Copy, Debug)]
+pub struct ScopeMark {
+    locals: usize,
+    to_close: usize,
+}
+
+/// What kind of function a frame is running
+#[derive(Clone, Debug)]
+pub enum FrameKind {
+    /// The main chunk, which isn't called by Lua code
+    Main,
+    /// A Lua function, defined at the span
+    Lua(Span),
+    Builtin,
+}
+
+/// A function being run, as tracebacks show it
+#[derive(Clone, Debug)]
+struct Frame {
+    kind: FrameKind,
+    /// What the call site names the function, such as `global 'f'`
+    name: Option<String>,
+    /// Whether the function was tail called, so the frames of its callers are gone
+    tail_call: bool,
+    /// The code the function is running, as of the last statement or call it started
+    position: Option<Span>,
+}
+
+impl Frame {
+    /// The line of a traceback describing the frame
+    fn traceback_line(&self) -> String {
+        let line = self.position.as_ref().map_or("?".to_string(), |span| span.line().to_string());
+        let what = match &self.name {
+            // globals are just functions
+            Some(name) => match name.strip_prefix("global ") {
+                Some(name) => format!("function {name}"),
+                None => name.clone(),
+            },
+            None => match &self.kind {
+                FrameKind::Main => "main chunk".to_string(),
+                FrameKind::Lua(defined) => format!("function <{}:{}>", defined.file, defined.line()),
+                FrameKind::Builtin => "?".to_string(),
+            },
+        };
+        let traceback_line = match &self.kind {
+            FrameKind::Main => {
+                let file = self.position.as_ref().map_or("?", |span| &span.file);
+                format!("{file}:{line}: in {what}")
+            },
+            FrameKind::Lua(defined) => format!("{}:{line}: in {what}", defined.file),
+            FrameKind::Builtin => format!("[C]: in {what}"),
+        };
+        if self.tail_call { traceback_line + "\n\t(...tail calls...)" } else { traceback_line }
+    }
+}
 
 /// Holds current state context
 #[derive(Clone)]
 pub struct Ctx {
-    // FIXME, SHOULD BE TABLE
-    level: usize,
-    globals: HashMap<Identifier, Value>,
-    locals: HashMap<Identifier, Vec<(Value, usize)>>,
-    ret_vals: Vec<Value>, 
+    /// The global table, which is `_G`, and the `_ENV` of the main chunk unless it's replaced
+    globals: Rc<RefCell<Table>>,
+    /// The innermost block being run
+    scope: Rc<Scope>,
+    /// The extra arguments of the function being run, which `...` gives
+    varargs: Vec<Value>,
+    /// The metatable shared by all strings
+    string_metatable: Option<Rc<RefCell<Table>>>,
+    /// The functions being run, innermost last
+    /// When an error is raised, the frames stay until the error is caught, so the traceback can be built
+    frames: Vec<Frame>,
+    /// How many functions can be running at once, before calls raise a stack overflow error
+    max_depth: usize,
 }
 
 impl Ctx {
+    /// The default maximum call depth, about as many calls as the reference interpreter's stack holds
+    pub const MAX_DEPTH: usize = 200_000;
+
+    /// A context for running a main chunk
     pub fn new() -> Ctx {
-        Ctx { level: 0, globals: HashMap::new(), locals: HashMap::new(), ret_vals: Vec::new() }
+        let globals = Table::new();
+        globals.borrow_mut().insert(&Value::String("_G".into()), Value::Table(globals.clone()));
+        // the main chunk is a function body, so `_ENV` is an upvalue of it
+        let chunk = Scope { parent: Some(Env::chunk(Value::Table(globals.clone()))), is_function: true, ..Scope::default() };
+        let main = Frame { kind: FrameKind::Main, name: None, position: None, tail_call: false };
+        Ctx {
+            globals,
+            scope: Rc::new(chunk),
+            varargs: Vec::new(),
+            string_metatable: None,
+            frames: vec![main],
+            max_depth: Ctx::MAX_DEPTH,
+        }
+    }
+
+    /// The locals visible right now, for closures to capture
+    pub fn env(&self) -> Env {
+        Env { scope: self.scope.clone(), len: self.scope.locals.borrow().len() }
+    }
+
+    fn get_local(&self, ident: &Identifier) -> Option<Local> {
+        self.env().get(ident)
     }
 
-    pub fn get_var(&self, ident: &Identifier) -> Option<Value> {
-        self.locals
-            .get(ident)
-            .map_or_else( 
-        ||self.globals.get(ident), 
-                | val | val.last().map(|(val, _)| val))
-            .map(|v| v.clone())
+    /// The value of the variable `ident`, which is the field of `_ENV` with that name if there's no such local
+    pub fn get_var(&mut self, ident: &Identifier) -> Result<Value, LuaError> {
+        match self.get_local(ident) {
+            Some(local) => Ok(local.borrow().clone()),
+            None => index(&self.environment()?, &Value::String(ident.0.as_str().into()), self),
+        }
     }
 
+    /// The value of `_ENV`, which free names are fields of
+    fn environment(&self) -> Result<Value, LuaError> {
+        let ident = Identifier::from("_ENV");
+        let env = self.get_local(&ident).map_or(Value::Nil, |local| local.borrow().clone());
+        match (&env, get_metatable(&env, self)) {
+            (Value::Table(_), _) | (_, Some(_)) => Ok(env),
+            _ => Err(index_error(&env, Some(format!("{} '_ENV'", self.var_kind(&ident))))),
+        }
+    }
+
+    /// The global table, whatever `_ENV` is
+    pub fn globals(&self) -> Rc<RefCell<Table>> {
+        self.globals.clone()
+    }
+
+    /// How error messages describe the variable `ident`: `"local"`, `"upvalue"`, or `"global"`
+    pub fn var_kind(&self, ident: &Identifier) -> &'static str {
+        self.env().kind_of(ident)
+    }
+
+    /// Set the global `ident` in the global table, without metamethods
     pub fn new_global(&mut self, ident: Identifier, val: Value) {
-        self.globals.insert(ident, val);
+        self.globals.borrow_mut().insert(&Value::String(ident.0.into()), val);
     }
 
+    /// Assign to the innermost local named `ident`, or to the field of `_ENV` if there's no such local
+    pub fn set_var(&mut self, ident: Identifier, val: Value) -> Result<(), LuaError> {
+        match self.get_local(&ident) {
+            Some(local) => {
+                *local.borrow_mut() = val;
+                Ok(())
+            },
+            None => set_index(&self.environment()?, &Value::String(ident.0.into()), val, self),
+        }
+    }
+
+    /// Declare a local in the current block
     pub fn new_local(&mut self, ident: Identifier, val: Value) {
-        self.locals.entry(ident).or_insert(Vec::new()).push((val, self.level));
+        self.scope.locals.borrow_mut().push((ident, Rc::new(RefCell::new(val))));
+    }
+
+    /// Declare a `<close>` local in the current block, whose value is closed when the block ends
+    /// The value has to have a `__close` metamethod, or be nil or false, which aren't closed
+    pub fn new_close_local(&mut self, ident: Identifier, val: Value) -> Result<(), LuaError> {
+        if val.as_bool() {
+            if metamethod(&val, "__close", self).is_none() {
+                return Err(LuaError::new(format!("variable '{}' got a non-closable value", ident.0)));
+            }
+            self.scope.to_close.borrow_mut().push(val.clone());
+        }
+        self.new_local(ident, val);
+        Ok(())
+    }
+
+    /// Close the current block's `<close>` values by calling their `__close` metamethods, latest first
+    /// If the block is left by an error, the metamethods get the error object, and an error in a metamethod
+    /// replaces the error the block ended with
+    fn close_scope<T>(&mut self, result: Result<T, LuaError>) -> Result<T, LuaError> {
+        let to_close = std::mem::take(&mut *self.scope.to_close.borrow_mut());
+        self.close_values(to_close, result)
+    }
+
+    /// Close `to_close`, latest first, where `result` is how the code they were declared in ended
+    fn close_values<T>(&mut self, to_close: Vec<Value>, mut result: Result<T, LuaError>) -> Result<T, LuaError> {
+        // an error leaves the frames of the calls it came from, which the metamethods mustn't run on top of,
+        // or they couldn't run after a stack overflow
+        if result.is_err() && !to_close.is_empty() {
+            self.unwind_frames(self.scope.frame_depth);
+        }
+        for val in to_close.into_iter().rev() {
+            let Some(close) = metamethod(&val, "__close", self) else { continue };
+            let err = result.as_ref().err().map_or(Value::Nil, |err| err.value().clone());
+            if let Err(err) = call_value(&close, vec![val, err], self) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// The current point of the current block, to go back to with `rewind_scope`
+    pub fn scope_mark(&self) -> ScopeMark {
+        ScopeMark { locals: self.scope.locals.borrow().len(), to_close: self.scope.to_close.borrow().len() }
+    }
+
+    /// Go back to `mark` in the current block, as a backward `goto` does, so the locals declared since go out of scope,
+    /// and the `<close>` values among them are closed
+    /// The block gets a new scope, since closures that captured the dropped locals still see the old one
+    pub fn rewind_scope(&mut self, mark: ScopeMark) -> Result<(), LuaError> {
+        if self.scope.locals.borrow().len() == mark.locals {
+            return Ok(());
+        }
+        let locals = self.scope.locals.borrow()[..mark.locals].to_vec();
+        let closing = self.scope.to_close.borrow_mut().split_off(mark.to_close);
+        let to_close = std::mem::take(&mut *self.scope.to_close.borrow_mut());
+        self.scope = Rc::new(Scope {
+            locals: RefCell::new(locals),
+            to_close: RefCell::new(to_close),
+            parent: self.scope.parent.clone(),
+            is_function: self.scope.is_function,
+            frame_depth: self.scope.frame_depth,
+        });
+        self.close_values(closing, Ok(()))
     }
 
     pub fn enter_block(&mut self) {
-        self.level += 1;
-    }
-
-    pub fn leave_block_noreturn(&mut self) {
-        self.level -= 1;
-        // get rid of old locals
-        // there must be a better way to do this
-        let mut completely_empty = Vec::new();
-        for (ident, values) in self.locals.iter_mut() {
-            *values = values.clone().into_iter().filter(|(_, level)| *level <= self.level).collect();
-            values.sort_by(|(_, l1), (_, l2)| l1.cmp(l2));
-            if values.is_empty() {
-                completely_empty.push(ident.clone());
+        let parent = self.env();
+        self.scope = Rc::new(Scope { parent: Some(parent), frame_depth: self.frame_depth(), ..Scope::default() });
+    }
+
+    /// Leave the current block, closing its `<close>` values, where `result` is how the block ended
+    /// This happens however the block is left, by reaching its end, `break`, `return`, `goto` or an error
+    pub fn leave_block<T>(&mut self, result: Result<T, LuaError>) -> Result<T, LuaError> {
+        let result = self.close_scope(result);
+        let parent = self.scope.parent.as_ref().expect("left more blocks than were entered");
+        self.scope = parent.scope.clone();
+        result
+    }
+
+    pub fn string_metatable(&self) -> Option<Rc<RefCell<Table>>> {
+        self.string_metatable.clone()
+    }
+
+    pub fn set_string_metatable(&mut self, metatable: Rc<RefCell<Table>>) {
+        self.string_metatable = Some(metatable);
+    }
+
+    pub fn varargs(&self) -> &[Value] {
+        &self.varargs
+    }
+
+    /// Set the varargs of the main chunk, which are the script's arguments
+    pub fn set_varargs(&mut self, varargs: Vec<Value>) {
+        self.varargs = varargs;
+    }
+
+    /// Run `body` in a function's scope, which sees the locals of `env` where the function was created, but not the caller's
+    pub fn in_function_scope<T>(&mut self, env: &Env, varargs: Vec<Value>, body: impl FnOnce(&mut Ctx) -> Result<T, LuaError>) -> Result<T, LuaError> {
+        let callee = Rc::new(Scope { parent: Some(env.clone()), is_function: true, frame_depth: self.frame_depth(), ..Scope::default() });
+        let caller = std::mem::replace(&mut self.scope, callee);
+        let caller_varargs = std::mem::replace(&mut self.varargs, varargs);
+        let result = body(self);
+        let result = self.close_scope(result);
+        self.scope = caller;
+        self.varargs = caller_varargs;
+        result
+    }
+
+    /// Start running a function, which the call site names `name`
+    /// A tail called function isn't named, as its caller's frame is gone
+    pub fn push_frame(&mut self, kind: FrameKind, name: Option<String>, tail_call: bool) {
+        let name = if tail_call { None } else { name };
+        self.frames.push(Frame { kind, name, position: None, tail_call });
+    }
+
+    /// Finish running the innermost function
+    pub fn pop_frame(&mut self) {
+        self.frames.pop();
+    }
+
+    /// How many functions are being run, so an error can be caught with `unwind_frames`
+    pub fn frame_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Limit how many functions can be running at once
+    /// Each call uses the Rust stack, so the stack has to have room for this many calls
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Whether a `return` can be a tail call: it has to be in a Lua function,
+    /// and not in the scope of a `<close>` local, which is closed after the call returns
+    pub fn can_tail_call(&self) -> bool {
+        if !matches!(self.frames.last(), Some(Frame { kind: FrameKind::Lua(_), .. })) {
+            return false;
+        }
+        let mut scope = &self.scope;
+        loop {
+            if !scope.to_close.borrow().is_empty() {
+                return false;
+            }
+            match &scope.parent {
+                Some(parent) if !scope.is_function => scope = &parent.scope,
+                _ => return true,
             }
         }
-        for ident in completely_empty {
-            self.locals.remove(&ident);
+    }
+
+    /// Drop the frames left by an error that was caught `depth` frames deep
+    pub fn unwind_frames(&mut self, depth: usize) {
+        self.frames.truncate(depth);
+    }
+
+    /// Note that the innermost function is running the code at `span`
+    pub fn set_position(&mut self, span: &Span) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.position = Some(span.clone());
         }
     }
 
-    pub fn leave_block(&mut self) -> Vec<Value> {
-        self.leave_block_noreturn();
-        self.ret_vals.split_off(0)
+    /// The code the function `level` levels up is running, where 0 is the innermost function
+    /// Builtins don't run code with a position
+    pub fn position(&self, level: usize) -> Option<&Span> {
+        let idx = self.frames.len().checked_sub(level + 1)?;
+        self.frames[idx].position.as_ref()
     }
 
-    pub fn ret(&mut self, v: Vec<Value>) {
-        self.ret_vals = v;
+    /// A Lua style stack traceback of the functions being run, innermost first, leaving out the innermost `level`
+    pub fn traceback(&self, level: usize) -> String {
+        let mut traceback = "stack traceback:".to_string();
+        let frames = self.frames.iter().rev().skip(level).collect::<Vec<_>>();
+        for (idx, frame) in frames.iter().enumerate() {
+            // like Lua, deep stacks only show the innermost and outermost frames
+            if frames.len() > 22 && idx >= 10 && idx < frames.len() - 11 {
+                if idx == 10 {
+                    traceback.push_str(&format!("\n\t...\t(skipping {} levels)", frames.len() - 21));
+                }
+                continue;
+            }
+            traceback.push_str("\n\t");
+            traceback.push_str(&frame.traceback_line());
+        }
+        // the host, which runs the main chunk
+        traceback.push_str("\n\t[C]: in ?");
+        traceback
     }
+}
 
-    pub fn did_return(&self) -> bool {
-        !self.ret_vals.is_empty()
+impl Default for Ctx {
+    fn default() -> Self {
+        Ctx::new()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,78 @@
+//! Errors raised while running a chunk
+
+use std::fmt::Display;
+
+use crate::{lexer::span::Span, value::Value};
+
+/// A runtime error, which unwinds up to the nearest `pcall`, or to the host
+#[derive(Clone, Debug)]
+pub struct LuaError {
+    /// The error object, usually a message, which `pcall` returns
+    value: Value,
+    /// Where the error was raised, if it has a position
+    span: Option<Span>,
+    /// Whether the position still has to be added, by the innermost expression or statement the error leaves
+    pending: bool,
+}
+
+impl LuaError {
+    /// An error with `message`, which gets the position of the code raising it
+    pub fn new(message: impl Into<String>) -> LuaError {
+        LuaError { value: Value::String(message.into().into()), span: None, pending: true }
+    }
+
+    /// An error with the error object `value`, raised at `span`
+    /// String values get the position prepended, other values are raised as they are
+    pub fn with_value(value: Value, span: Option<&Span>) -> LuaError {
+        LuaError { value, span: None, pending: false }.positioned(span)
+    }
+
+    /// A bad argument to the builtin `fname`, where `idx` counts from 1
+    pub fn bad_argument(idx: usize, fname: &str, reason: &str) -> LuaError {
+        LuaError::new(format!("bad argument #{idx} to '{fname}' ({reason})"))
+    }
+
+    /// The argument `idx` to the builtin `fname` wasn't what it `expected`, where `arg` is `None` if it's missing
+    pub fn type_error(idx: usize, fname: &str, expected: &str, arg: Option<&Value>) -> LuaError {
+        let got = arg.map_or("no value", Value::type_name);
+        LuaError::bad_argument(idx, fname, &format!("{expected} expected, got {got}"))
+    }
+
+    /// Add the position `span` to the error, unless it already has one
+    pub fn locate(self, span: &Span) -> LuaError {
+        if !self.pending { return self; }
+        LuaError { pending: false, ..self }.positioned(Some(span))
+    }
+
+    fn positioned(mut self, span: Option<&Span>) -> LuaError {
+        if let (Some(span), Value::String(msg)) = (span, &self.value) {
+            let prefix = format!("{}:{}: ", span.file, span.line());
+            self.value = Value::String([prefix.as_bytes(), msg.as_bytes()].concat().into());
+            self.span = Some(span.clone());
+        }
+        self
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn into_value(self) -> Value {
+        self.value
+    }
+
+    pub fn span(&self) -> Option<&Span> {
+        self.span.as_ref()
+    }
+}
+
+impl Display for LuaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value.as_string() {
+            Some(msg) => write!(f, "{msg}"),
+            None => write!(f, "(error object is a {} value)", self.value.type_name()),
+        }
+    }
+}
+
+impl std::error::Error for LuaError {}
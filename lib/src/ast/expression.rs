@@ -1,6 +1,6 @@
-use std::{cmp::Ordering, collections::HashMap, fmt::{Debug, Display}, io};
+use std::{fmt::{Debug, Display}, rc::Rc};
 
-use crate::{ast::{context::Ctx, function::{FunctionCall, MethodCall}}, lexer::{self, identifier::{self, Identifier}, literal, operator, seperator, Lexeme, Lexer}, value::{table::{TableAccess, TableConstructor}, Value}};
+use crate::{ast::{context::Ctx, error::LuaError, function::{call_value, Closure, Function, FunctionCall, LuaFunction, MethodCall}, parse_paren_list, statement::parse_funcbody}, lexer::{self, identifier::{self, Identifier}, keyword::Keyword, operator, seperator, span::Span, Lexeme}, parser::{error::ParseError, Parser}, value::{meta::{binary_metamethod, metamethod}, number::{shift_left, Number}, string::LuaString, table::{TableAccess, TableConstructor}, Value}};
 
 pub struct TableExpression {}
 
@@ -27,467 +27,558 @@ enum ExpOperation {
     GreaterEqual,
     NotEqual,
     Equals,
+    BitOr,
+    BitXor,
+    BitAnd,
+    ShiftLeft,
+    ShiftRight,
     Concat,
     Plus,
     Minus,
     Star,
     Slash,
-    Not,
+    FloorDiv,
+    Mod,
     Exp,
-    OpenParen,
-    CloseParen,
+    Not,
     UnaryMinus,
+    Length,
+    BitNot,
 }
 
+/// How tightly unary operators bind, which is tighter than every binary operator except `^`
+const UNARY_PRIORITY: u8 = 12;
+
 impl ExpOperation {
-    pub fn precedence(first: ExpOperation, second: ExpOperation) -> Ordering {
-        first.rank().cmp(&second.rank())
+    /// The binary operator `lexeme` stands for, if any
+    fn binary(lexeme: Lexeme) -> Option<ExpOperation> {
+        let op = match lexeme {
+            Lexeme::Operator(op) => match op {
+                operator::Operator::LogicalOr => ExpOperation::Or,
+                operator::Operator::LogicalAnd => ExpOperation::And,
+                operator::Operator::LessEqual => ExpOperation::LessEqual,
+                operator::Operator::GreaterEqual => ExpOperation::GreaterEqual,
+                operator::Operator::NotEqual => ExpOperation::NotEqual,
+                operator::Operator::Equal => ExpOperation::Equals,
+                operator::Operator::BitOr => ExpOperation::BitOr,
+                operator::Operator::Tilde => ExpOperation::BitXor,
+                operator::Operator::BitAnd => ExpOperation::BitAnd,
+                operator::Operator::LeftShift => ExpOperation::ShiftLeft,
+                operator::Operator::RightShift => ExpOperation::ShiftRight,
+                operator::Operator::Concat => ExpOperation::Concat,
+                operator::Operator::Plus => ExpOperation::Plus,
+                operator::Operator::Minus => ExpOperation::Minus,
+                operator::Operator::Star => ExpOperation::Star,
+                operator::Operator::Slash => ExpOperation::Slash,
+                operator::Operator::DoubleSlash => ExpOperation::FloorDiv,
+                operator::Operator::Percent => ExpOperation::Mod,
+                operator::Operator::Caret => ExpOperation::Exp,
+                operator::Operator::LogicalNot | operator::Operator::Hash => return None,
+            },
+            Lexeme::AngleBrackets(lexer::AngleBrackets::Open) => ExpOperation::LessThan,
+            Lexeme::AngleBrackets(lexer::AngleBrackets::Close) => ExpOperation::GreaterThan,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// The unary operator `lexeme` stands for, if any
+    fn unary(lexeme: Lexeme) -> Option<ExpOperation> {
+        match lexeme {
+            Lexeme::Operator(operator::Operator::LogicalNot) => Some(ExpOperation::Not),
+            Lexeme::Operator(operator::Operator::Minus) => Some(ExpOperation::UnaryMinus),
+            Lexeme::Operator(operator::Operator::Hash) => Some(ExpOperation::Length),
+            Lexeme::Operator(operator::Operator::Tilde) => Some(ExpOperation::BitNot),
+            _ => None,
+        }
     }
 
-    fn rank(&self) -> u8 {
+    /// How tightly a binary operator binds its left and right operands, as in the reference implementation
+    /// Right associative operators bind tighter on the left
+    fn priority(&self) -> (u8, u8) {
         match self {
-            ExpOperation::And | ExpOperation::Or => 0,
+            ExpOperation::Or => (1, 1),
+            ExpOperation::And => (2, 2),
             ExpOperation::LessThan | ExpOperation::GreaterThan 
                 | ExpOperation::LessEqual | ExpOperation::GreaterEqual 
-                | ExpOperation::Equals | ExpOperation::NotEqual => 1,
-            ExpOperation::Concat => 2,
-            ExpOperation::Plus | ExpOperation::Minus => 3,
-            ExpOperation::Star | ExpOperation::Slash => 4,
-            ExpOperation::Not | ExpOperation::UnaryMinus => 5,
-            ExpOperation::Exp => 6,
-            ExpOperation::OpenParen | ExpOperation::CloseParen => 7,
+                | ExpOperation::Equals | ExpOperation::NotEqual => (3, 3),
+            ExpOperation::BitOr => (4, 4),
+            ExpOperation::BitXor => (5, 5),
+            ExpOperation::BitAnd => (6, 6),
+            ExpOperation::ShiftLeft | ExpOperation::ShiftRight => (7, 7),
+            ExpOperation::Concat => (9, 8),
+            ExpOperation::Plus | ExpOperation::Minus => (10, 10),
+            ExpOperation::Star | ExpOperation::Slash | ExpOperation::FloorDiv | ExpOperation::Mod => (11, 11),
+            ExpOperation::Exp => (14, 13),
+            ExpOperation::Not | ExpOperation::UnaryMinus | ExpOperation::Length | ExpOperation::BitNot => {
+                (UNARY_PRIORITY, UNARY_PRIORITY)
+            },
         }
     }
 
     pub fn is_arith_op(&self) -> bool {
         matches!(self, ExpOperation::Plus | ExpOperation::Minus | ExpOperation::Star | ExpOperation::Slash 
-            | ExpOperation::Exp | ExpOperation::UnaryMinus)
+            | ExpOperation::FloorDiv | ExpOperation::Mod | ExpOperation::Exp | ExpOperation::UnaryMinus)
+    }
+
+    pub fn is_bitwise_op(&self) -> bool {
+        matches!(self, ExpOperation::BitOr | ExpOperation::BitXor | ExpOperation::BitAnd 
+            | ExpOperation::ShiftLeft | ExpOperation::ShiftRight | ExpOperation::BitNot)
+    }
+
+    /// The name of the metamethod of an arithmetic or bitwise operator
+    fn event(&self) -> &'static str {
+        match self {
+            ExpOperation::Plus => "__add",
+            ExpOperation::Minus => "__sub",
+            ExpOperation::Star => "__mul",
+            ExpOperation::Slash => "__div",
+            ExpOperation::FloorDiv => "__idiv",
+            ExpOperation::Mod => "__mod",
+            ExpOperation::Exp => "__pow",
+            ExpOperation::UnaryMinus => "__unm",
+            ExpOperation::BitOr => "__bor",
+            ExpOperation::BitXor => "__bxor",
+            ExpOperation::BitAnd => "__band",
+            ExpOperation::ShiftLeft => "__shl",
+            ExpOperation::ShiftRight => "__shr",
+            ExpOperation::BitNot => "__bnot",
+            _ => unreachable!("{self:?} has no metamethod of its own"),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
-pub enum Expression {
-    NumericLiteral(literal::NumericLiteral),
-    StringLiteral(literal::StringLiteral),
+pub enum ExpressionKind {
+    Nil,
+    Boolean(bool),
+    NumericLiteral(Number),
+    StringLiteral(LuaString),
     Identifier(identifier::Identifier),
     FuncCall(FunctionCall),
     MethodCall(MethodCall),
     BinaryExp(BinaryExpression),
     UnaryExp(UnaryExpression),
     TableAccess(TableAccess),
-    TableConstructor(TableConstructor)
+    TableConstructor(TableConstructor),
+    /// A parenthesised expression, which only ever has a single value
+    Paren(Box<Expression>),
+    /// An anonymous function, which becomes a closure when evaluated
+    Function(Rc<LuaFunction>),
+    /// `...`, the extra arguments of the enclosing function
+    Vararg,
+}
+
+#[derive(Clone, Debug)]
+pub struct Expression {
+    kind: ExpressionKind,
+    span: Span,
 }
 
 impl Expression {
-    pub fn eval(&self, ctx: &mut Ctx) -> Value {
-        match self {
-            Expression::NumericLiteral(nlit) => {
-                // FIXME
-                Value::Number(nlit.value()) 
+    pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+        Expression { kind, span }
+    }
+
+    pub fn kind(&self) -> &ExpressionKind {
+        &self.kind
+    }
+
+    pub fn into_kind(self) -> ExpressionKind {
+        self.kind
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    fn binary(op: ExpOperation, lhs: Box<Expression>, rhs: Box<Expression>) -> Expression {
+        let span = lhs.span.to(&rhs.span);
+        Expression::new(ExpressionKind::BinaryExp(BinaryExpression { op, lhs, rhs }), span)
+    }
+
+    /// A unary expression, where `start` is the span of the operator
+    fn unary(op: ExpOperation, arg: Box<Expression>, start: &Span) -> Expression {
+        let span = start.to(&arg.span);
+        Expression::new(ExpressionKind::UnaryExp(UnaryExpression { op, arg }), span)
+    }
+
+    /// Evaluate the expression to a single value
+    /// Errors raised by the expression itself, rather than by something it contains, get its position
+    pub fn eval(&self, ctx: &mut Ctx) -> Result<Value, LuaError> {
+        self.eval_kind(ctx).map_err(|err| err.locate(&self.span))
+    }
+
+    fn eval_kind(&self, ctx: &mut Ctx) -> Result<Value, LuaError> {
+        Ok(match &self.kind {
+            ExpressionKind::Nil => Value::Nil,
+            ExpressionKind::Boolean(b) => Value::Boolean((*b).into()),
+            ExpressionKind::NumericLiteral(n) => {
+                (*n).into()
+            },
+            ExpressionKind::StringLiteral(s) => {
+                Value::String(s.clone())
+            },
+            ExpressionKind::Identifier(ident) => {
+                ctx.get_var(ident)?
             },
-            Expression::StringLiteral(slit) => {
-                Value::String(slit.value().to_string())
+            // a call or `...` gives its first value where only one value is used
+            ExpressionKind::FuncCall(_) | ExpressionKind::MethodCall(_) | ExpressionKind::Vararg => {
+                self.eval_multi(ctx)?.into_iter().next().unwrap_or(Value::Nil)
             },
-            Expression::Identifier(ident) => {
-                ctx.get_var(ident).unwrap_or(Value::Nil)
+            ExpressionKind::Function(func) => {
+                Value::Function(Rc::new(Function::Closure(Closure::new(func.clone(), ctx.env()))))
             },
-            Expression::FuncCall(fcall) => {
-                fcall.call(ctx)
+            ExpressionKind::Paren(exp) => {
+                exp.eval(ctx)?
             },
-            Expression::MethodCall(mcall) => {
-                mcall.call(ctx)
+            ExpressionKind::TableAccess(access) => access.eval(ctx)?,
+            ExpressionKind::TableConstructor(tc) => tc.eval(ctx)?,
+            ExpressionKind::BinaryExp(b) => match b.op {
+                // short circuits, giving whichever operand decided the result
+                ExpOperation::Or => {
+                    let lhs_val = b.lhs.eval(ctx)?;
+                    if lhs_val.as_bool() { lhs_val } else { b.rhs.eval(ctx)? }
+                },
+                ExpOperation::And => {
+                    let lhs_val = b.lhs.eval(ctx)?;
+                    if lhs_val.as_bool() { b.rhs.eval(ctx)? } else { lhs_val }
+                },
+                op => {
+                    let lhs_val = b.lhs.eval(ctx)?;
+                    let rhs_val = b.rhs.eval(ctx)?;
+                    binary_op(op, &lhs_val, &rhs_val, ctx)?
+                },
             },
-            Expression::BinaryExp(b) => {
-                if b.op.is_arith_op() {
-                    let lhs_val = b.lhs.eval(ctx).as_number().expect("BinOp lhs wasnt number");
-                    let rhs_val = b.rhs.eval(ctx).as_number().expect("BinOp rhs wasnt number");
-                    Value::Number(match b.op {
-                        ExpOperation::Plus =>lhs_val + rhs_val, 
-                        ExpOperation::Minus => lhs_val - rhs_val,
-                        ExpOperation::Star => lhs_val * rhs_val,
-                        ExpOperation::Slash => lhs_val / rhs_val,
-                        ExpOperation::Exp => lhs_val.powf(rhs_val),
-                        _ => unreachable!()
-                    })
-                } else {
-                    match b.op {
-                        ExpOperation::Equals => {
-                            let lhs_val = b.lhs.eval(ctx);
-                            let rhs_val = b.rhs.eval(ctx);
-                            Value::Boolean((lhs_val == rhs_val).into())
-                        },
-                        ExpOperation::NotEqual => {
-                            let lhs_val = b.lhs.eval(ctx);
-                            let rhs_val = b.rhs.eval(ctx);
-                            Value::Boolean((lhs_val != rhs_val).into())
-                        },
-                        ExpOperation::LessThan => {
-                            let lhs_val = b.lhs.eval(ctx).as_number().expect("BinOp lhs wasnt number");
-                            let rhs_val = b.rhs.eval(ctx).as_number().expect("BinOp rhs wasnt number");
-                            Value::Boolean((lhs_val < rhs_val).into())
-                        },
-                        ExpOperation::GreaterThan => {
-                            let lhs_val = b.lhs.eval(ctx).as_number().expect("BinOp lhs wasnt number");
-                            let rhs_val = b.rhs.eval(ctx).as_number().expect("BinOp rhs wasnt number");
-                            Value::Boolean((lhs_val > rhs_val).into())
-                        },
-                        ExpOperation::LessEqual => {
-                            let lhs_val = b.lhs.eval(ctx).as_number().expect("BinOp lhs wasnt number");
-                            let rhs_val = b.rhs.eval(ctx).as_number().expect("BinOp rhs wasnt number");
-                            Value::Boolean((lhs_val <= rhs_val).into())
-                        },
-                        ExpOperation::GreaterEqual => {
-                            let lhs_val = b.lhs.eval(ctx).as_number().expect("BinOp lhs wasnt number");
-                            let rhs_val = b.rhs.eval(ctx).as_number().expect("BinOp rhs wasnt number");
-                            Value::Boolean((lhs_val >= rhs_val).into())
-                        },
-                        // short circuits - should probably have a different branch
-                        ExpOperation::Or => {
-                            let lhs_val = b.lhs.eval(ctx).as_bool();
-                            Value::Boolean((lhs_val || b.rhs.eval(ctx).as_bool()).into())
-                        },
-                        ExpOperation::And => {
-                            let lhs_val = b.lhs.eval(ctx).as_bool();
-                            Value::Boolean((lhs_val && b.rhs.eval(ctx).as_bool()).into())
-                        }
-                        _ => panic!("Binop {:?} not yet implemented!", b.op)
-                    }
+            ExpressionKind::UnaryExp(u) => {
+                let val = u.arg.eval(ctx)?;
+                match u.op {
+                    ExpOperation::Not => Value::Boolean((!val.as_bool()).into()),
+                    ExpOperation::Length => length(&val, ctx)?,
+                    // the other unary operators work like binary ones with the operand repeated
+                    op => binary_op(op, &val, &val, ctx)?,
                 }
-            }     
-            _ => panic!("Expression kind {:?} not yet implemented!", self)
+            }
+        })
+    }
+
+    /// The variable the expression names, as error messages describe it, such as `global 'x'` or `field 'y'`
+    pub fn describe(&self, ctx: &Ctx) -> Option<String> {
+        match &self.kind {
+            ExpressionKind::Identifier(ident) => Some(format!("{} '{}'", ctx.var_kind(ident), ident.0)),
+            ExpressionKind::TableAccess(TableAccess::DotAccess(_, field)) => Some(format!("field '{}'", field.0)),
+            ExpressionKind::TableAccess(TableAccess::Index(_, key)) => match key.kind() {
+                ExpressionKind::StringLiteral(s) => Some(format!("field '{s}'")),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Apply a binary operator, or a unary one that gets its operand twice, to evaluated operands
+/// Operands the operator can't handle go to the metamethod of the operator
+fn binary_op(op: ExpOperation, lhs: &Value, rhs: &Value, ctx: &mut Ctx) -> Result<Value, LuaError> {
+    Ok(match op {
+        ExpOperation::Equals => Value::Boolean(equals(lhs, rhs, ctx)?.into()),
+        ExpOperation::NotEqual => Value::Boolean((!equals(lhs, rhs, ctx)?).into()),
+        ExpOperation::LessThan => Value::Boolean(less_than(lhs, rhs, ctx)?.into()),
+        ExpOperation::GreaterThan => Value::Boolean(less_than(rhs, lhs, ctx)?.into()),
+        ExpOperation::LessEqual => Value::Boolean(less_equal(lhs, rhs, ctx)?.into()),
+        ExpOperation::GreaterEqual => Value::Boolean(less_equal(rhs, lhs, ctx)?.into()),
+        ExpOperation::Concat => match (lhs.as_string(), rhs.as_string()) {
+            (Some(l), Some(r)) => Value::String([l.as_bytes(), r.as_bytes()].concat().into()),
+            (l, _) => binary_metamethod("__concat", lhs, rhs, ctx)?.ok_or_else(|| {
+                let culprit = if l.is_none() { lhs } else { rhs };
+                LuaError::new(format!("attempt to concatenate a {} value", culprit.type_name()))
+            })?,
+        },
+        _ if op.is_bitwise_op() => match (lhs.as_number(), rhs.as_number()) {
+            (Some(_), Some(_)) => {
+                let lhs_val = integer_operand(lhs)?;
+                let rhs_val = integer_operand(rhs)?;
+                Value::Integer(match op {
+                    ExpOperation::BitOr => lhs_val | rhs_val,
+                    ExpOperation::BitXor => lhs_val ^ rhs_val,
+                    ExpOperation::BitAnd => lhs_val & rhs_val,
+                    ExpOperation::ShiftLeft => shift_left(lhs_val, rhs_val),
+                    ExpOperation::ShiftRight => shift_left(lhs_val, rhs_val.wrapping_neg()),
+                    ExpOperation::BitNot => !lhs_val,
+                    _ => unreachable!()
+                })
+            },
+            (l, _) => binary_metamethod(op.event(), lhs, rhs, ctx)?.ok_or_else(|| {
+                let culprit = if l.is_none() { lhs } else { rhs };
+                LuaError::new(format!("attempt to perform bitwise operation on a {} value", culprit.type_name()))
+            })?,
+        },
+        _ if op.is_arith_op() => match (lhs.as_number(), rhs.as_number()) {
+            (Some(lhs_val), Some(rhs_val)) => match op {
+                ExpOperation::Plus => lhs_val + rhs_val,
+                ExpOperation::Minus => lhs_val - rhs_val,
+                ExpOperation::Star => lhs_val * rhs_val,
+                ExpOperation::Slash => lhs_val / rhs_val,
+                ExpOperation::FloorDiv => lhs_val.floor_div(rhs_val).ok_or_else(|| LuaError::new("attempt to perform 'n//0'"))?,
+                ExpOperation::Mod => lhs_val.modulo(rhs_val).ok_or_else(|| LuaError::new("attempt to perform 'n%0'"))?,
+                ExpOperation::Exp => lhs_val.pow(rhs_val),
+                ExpOperation::UnaryMinus => -lhs_val,
+                _ => unreachable!()
+            }.into(),
+            (l, _) => binary_metamethod(op.event(), lhs, rhs, ctx)?.ok_or_else(|| {
+                let culprit = if l.is_none() { lhs } else { rhs };
+                LuaError::new(format!("attempt to perform arithmetic on a {} value", culprit.type_name()))
+            })?,
+        },
+        _ => unreachable!("{op:?} is evaluated without its operands"),
+    })
+}
+
+/// `lhs == rhs`, where tables that aren't the same table are compared with their `__eq` metamethod
+fn equals(lhs: &Value, rhs: &Value, ctx: &mut Ctx) -> Result<bool, LuaError> {
+    if lhs == rhs { return Ok(true); }
+    match (lhs, rhs) {
+        (Value::Table(_), Value::Table(_)) => Ok(binary_metamethod("__eq", lhs, rhs, ctx)?.is_some_and(|v| v.as_bool())),
+        _ => Ok(false),
+    }
+}
+
+fn less_than(lhs: &Value, rhs: &Value, ctx: &mut Ctx) -> Result<bool, LuaError> {
+    match compare(lhs, rhs, |a, b| a < b, |a, b| a < b) {
+        Some(result) => Ok(result),
+        None => binary_metamethod("__lt", lhs, rhs, ctx)?.map(|v| v.as_bool()).ok_or_else(|| compare_error(lhs, rhs)),
+    }
+}
+
+fn less_equal(lhs: &Value, rhs: &Value, ctx: &mut Ctx) -> Result<bool, LuaError> {
+    match compare(lhs, rhs, |a, b| a <= b, |a, b| a <= b) {
+        Some(result) => Ok(result),
+        None => binary_metamethod("__le", lhs, rhs, ctx)?.map(|v| v.as_bool()).ok_or_else(|| compare_error(lhs, rhs)),
+    }
+}
+
+/// `#val`, where the `__len` metamethod comes before the length of a table
+fn length(val: &Value, ctx: &mut Ctx) -> Result<Value, LuaError> {
+    if let Value::String(s) = val {
+        return Ok(Value::Integer(s.len() as i64));
+    }
+    match (val, metamethod(val, "__len", ctx)) {
+        (_, Some(handler)) => Ok(call_value(&handler, vec![val.clone(), val.clone()], ctx)?.into_iter().next().unwrap_or(Value::Nil)),
+        (Value::Table(t), None) => Ok(Value::Integer(t.borrow().border())),
+        _ => Err(LuaError::new(format!("attempt to get length of a {} value", val.type_name()))),
+    }
+}
+
+/// The integer a bitwise operand converts to
+fn integer_operand(val: &Value) -> Result<i64, LuaError> {
+    match val.as_number() {
+        Some(n) => n.as_integer().ok_or_else(|| LuaError::new("number has no integer representation")),
+        None => Err(LuaError::new(format!("attempt to perform bitwise operation on a {} value", val.type_name()))),
+    }
+}
+
+/// Compare two values with `<` (or `<=`, depending on `num` and `string`)
+/// Only pairs of numbers and pairs of strings can be compared, strings aren't converted to numbers
+/// Returns `None` for other values, which are compared with metamethods
+fn compare(lhs: &Value, rhs: &Value, num: fn(Number, Number) -> bool, string: fn(&LuaString, &LuaString) -> bool) -> Option<bool> {
+    match (lhs, rhs) {
+        (Value::String(a), Value::String(b)) => Some(string(a, b)),
+        (Value::Integer(_) | Value::Float(_), Value::Integer(_) | Value::Float(_)) => {
+            Some(num(lhs.as_number().expect("Numbers should convert to numbers"), rhs.as_number().expect("Numbers should convert to numbers")))
+        },
+        _ => None,
+    }
+}
+
+fn compare_error(lhs: &Value, rhs: &Value) -> LuaError {
+    if lhs.type_name() == rhs.type_name() {
+        return LuaError::new(format!("attempt to compare two {} values", lhs.type_name()));
+    }
+    LuaError::new(format!("attempt to compare {} with {}", lhs.type_name(), rhs.type_name()))
+}
+
+impl Expression {
+    /// Evaluate the expression where it can give multiple values, as the last expression of a list does
+    /// Only calls and `...` give other than one value, and parentheses around them keep just the first
+    pub fn eval_multi(&self, ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+        match &self.kind {
+            ExpressionKind::Vararg => Ok(ctx.varargs().to_vec()),
+            ExpressionKind::FuncCall(fcall) => fcall.call(ctx).map_err(|err| err.locate(&self.span)),
+            ExpressionKind::MethodCall(mcall) => mcall.call(ctx).map_err(|err| err.locate(&self.span)),
+            _ => Ok(vec![self.eval(ctx)?]),
         }
     }
 }
 
+/// Evaluate a list of expressions, where only the last one can give multiple values
+pub fn eval_explist(exps: &[Expression], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let Some((last, init)) = exps.split_last() else { return Ok(Vec::new()) };
+    let mut vals = init.iter().map(|e| e.eval(ctx)).collect::<Result<Vec<_>, _>>()?;
+    vals.append(&mut last.eval_multi(ctx)?);
+    Ok(vals)
+}
+
 impl Display for Expression {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "{:?}", self.kind)
     }
 }
 
-pub fn parse_expression(lex: &mut Lexer) -> Option<Expression> {
-    // UGHHH I HATE SHUnTING YARD
-    let mut operands = Vec::new();
-    let mut operations = Vec::new();
+/// Parse an expression that has to be present, such as the condition of an if statement
+pub fn expect_expression(lex: &mut Parser) -> Result<Expression, ParseError> {
+    parse_expression(lex)?.ok_or_else(|| ParseError::unexpected(lex))
+}
 
-    let mut last_was_arg = false;
-    let mut opened_parens = 0;
+/// Parse a comma seperated list of one or more expressions
+pub fn parse_explist(lex: &mut Parser) -> Result<Vec<Expression>, ParseError> {
+    let mut exps = vec![expect_expression(lex)?];
+    while lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
+        lex.next();
+        exps.push(expect_expression(lex)?);
+    }
+    Ok(exps)
+}
 
-    while let Some(tok) = lex.clone().peekable().peek() {
-        //eprintln!("tok: {:?}\n op stack: {:?}\n arg_stack: {:?}\n\n", tok, operations, operands);
-        match tok {
-            Lexeme::Operator(op) => {
+/// Parse the next expression, returning `None` if no expression starts here
+pub fn parse_expression(lex: &mut Parser) -> Result<Option<Expression>, ParseError> {
+    let starts_expression = match lex.peek_lexeme() {
+        Some(Lexeme::NumericLiteral(_) | Lexeme::StringLiteral(_) | Lexeme::Identifier(_)) => true,
+        Some(Lexeme::Keyword(Keyword::Nil | Keyword::True | Keyword::False | Keyword::Function)) => true,
+        Some(Lexeme::Seperator(seperator::Seperator::OpenParen | seperator::Seperator::OpenCurly | seperator::Seperator::Dots)) => true,
+        Some(lexeme) => ExpOperation::unary(lexeme).is_some(),
+        None => false,
+    };
+    if starts_expression { parse_subexpression(lex, 0).map(Some) } else { Ok(None) }
+}
+
+/// Parse an expression whose binary operators all bind tighter than `limit`
+/// This is the precedence climbing loop used by the reference implementation
+fn parse_subexpression(lex: &mut Parser, limit: u8) -> Result<Expression, ParseError> {
+    let mut exp = match lex.peek_lexeme().and_then(ExpOperation::unary) {
+        Some(op) => {
+            lex.next();
+            let start = lex.last_span().clone();
+            let arg = parse_subexpression(lex, UNARY_PRIORITY)?;
+            Expression::unary(op, Box::new(arg), &start)
+        },
+        None => parse_simple_expression(lex)?,
+    };
+    while let Some(op) = lex.peek_lexeme().and_then(ExpOperation::binary) {
+        let (left, right) = op.priority();
+        if left <= limit { break; }
+        lex.next();
+        let rhs = parse_subexpression(lex, right)?;
+        exp = Expression::binary(op, Box::new(exp), Box::new(rhs));
+    }
+    Ok(exp)
+}
+
+/// Parse a literal, table constructor or prefix expression
+fn parse_simple_expression(lex: &mut Parser) -> Result<Expression, ParseError> {
+    let kind = match lex.peek_lexeme() {
+        Some(Lexeme::NumericLiteral(n)) => ExpressionKind::NumericLiteral(n.value()),
+        Some(Lexeme::StringLiteral(s)) => ExpressionKind::StringLiteral(s.value().into_owned().into()),
+        Some(Lexeme::Keyword(Keyword::Nil)) => ExpressionKind::Nil,
+        Some(Lexeme::Keyword(Keyword::True)) => ExpressionKind::Boolean(true),
+        Some(Lexeme::Keyword(Keyword::False)) => ExpressionKind::Boolean(false),
+        Some(Lexeme::Seperator(seperator::Seperator::Dots)) => {
+            if !lex.is_vararg() {
+                return Err(ParseError::at_next_lexeme("cannot use '...' outside a vararg function", lex));
+            }
+            ExpressionKind::Vararg
+        },
+        Some(Lexeme::Seperator(seperator::Seperator::OpenCurly)) => {
+            let tc = TableConstructor::parse(lex)?.expect("Table constructor should start with '{'");
+            let span = tc.span().clone();
+            return Ok(Expression::new(ExpressionKind::TableConstructor(tc), span));
+        },
+        Some(Lexeme::Keyword(Keyword::Function)) => {
+            lex.next();
+            let start = lex.last_span().clone();
+            let func = parse_funcbody(lex, Vec::new(), &start)?;
+            return Ok(Expression::new(ExpressionKind::Function(Rc::new(func)), start.to(lex.last_span())));
+        },
+        _ => return parse_prefix_expression(lex)?.ok_or_else(|| ParseError::unexpected(lex)),
+    };
+    lex.next();
+    Ok(Expression::new(kind, lex.last_span().clone()))
+}
+
+/// Parse a prefix expression: a name or parenthesised expression, followed by any number of
+/// field accesses, indexes and calls
+/// Returns `None` if no prefix expression starts here
+pub fn parse_prefix_expression(lex: &mut Parser) -> Result<Option<Expression>, ParseError> {
+    let mut exp = match lex.peek_lexeme() {
+        Some(Lexeme::Identifier(name)) => {
+            lex.next();
+            Expression::new(ExpressionKind::Identifier(Identifier::from(name)), lex.last_span().clone())
+        },
+        Some(Lexeme::Seperator(seperator::Seperator::OpenParen)) => {
+            lex.next();
+            let start = lex.last_span().clone();
+            let inner = expect_expression(lex)?;
+            expect_closing_paren(lex, &start)?;
+            Expression::new(ExpressionKind::Paren(Box::new(inner)), start.to(lex.last_span()))
+        },
+        _ => return Ok(None),
+    };
+    loop {
+        exp = match lex.peek_lexeme() {
+            Some(Lexeme::Seperator(seperator::Seperator::Dot)) => {
                 lex.next();
-                match op {
-                    operator::Operator::LogicalAnd => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::And);
-                    },
-                    operator::Operator::LogicalOr => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Or);
-                    },
-                    operator::Operator::LessEqual => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::LessEqual);
-                    },
-                    operator::Operator::GreaterEqual => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::GreaterEqual);
-                    },
-                    operator::Operator::Equal => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Equals);
-                    }
-                    operator::Operator::NotEqual => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::NotEqual);
-                    } 
-                    operator::Operator::Concat => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Concat);
-                    },
-                    operator::Operator::Plus => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Plus);
-                    },
-                    operator::Operator::Minus => {
-                        operations.push(
-                            if last_was_arg {
-                                ExpOperation::Minus
-                            } else { ExpOperation::UnaryMinus }
-                        );
-                        last_was_arg = false;
-                    },
-                    operator::Operator::Star => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Star);
-                    },
-                    operator::Operator::Slash => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Slash);
-                    },
-                    operator::Operator::LogicalNot => {
-                        assert!(!last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Not);
-                    },
-                    operator::Operator::Caret => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::Exp);
-                    }
-                    _ => { todo!() }
-                }
-            },
-            Lexeme::AngleBrackets(bkt) => {
+                let Some(Lexeme::Identifier(field)) = lex.peek_lexeme() else {
+                    return Err(ParseError::expected("<name>", lex));
+                };
                 lex.next();
-                match bkt {
-                    lexer::AngleBrackets::Open => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::LessThan);
-                    },
-                    lexer::AngleBrackets::Close => {
-                        assert!(last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::GreaterThan);
-                    }
-                }
+                let span = exp.span().to(lex.last_span());
+                Expression::new(ExpressionKind::TableAccess(TableAccess::new_dot(exp, Identifier::from(field))), span)
             },
-            Lexeme::Seperator(sep) => {
-                match sep {
-                    seperator::Seperator::OpenParen => {
-                        lex.next();
-                        opened_parens += 1;
-                        if last_was_arg {
-                            eprintln!("hit error line 274, stacks are:");
-                            eprintln!("operands: {operands:?}");
-                            eprintln!("operations: {operations:?}");
-                        }
-                        assert!(!last_was_arg);
-                        last_was_arg = false;
-                        operations.push(ExpOperation::OpenParen);
-                    },
-                    seperator::Seperator::CloseParen => {
-                        
-                        opened_parens -= 1;
-                        if opened_parens < 0 {
-                            break;
-                        }
-                        lex.next();
-                        last_was_arg = true;
-                        // collapse this paren if we're just wrapping a single operand
-                        // is this sound?
-                        if operations.last() == Some(&ExpOperation::OpenParen) {
-                            operations.pop();
-                        } else {
-                            operations.push(ExpOperation::CloseParen);
-                        }
-                    },
-                    seperator::Seperator::OpenBracket | seperator::Seperator::CloseBracket => {
-                        todo!()
-                    }
-                    seperator::Seperator::OpenCurly => {
-                        if let Some(tc) = TableConstructor::parse(lex) {
-                            operands.push(Expression::TableConstructor(tc));
-                            last_was_arg = true;
-                        } else { todo!() }
-                    }
-                    seperator::Seperator::Colon => {
-                        // kinda hacky
-                        // fixme!
-                        lex.next();
-                        if let Some(op) = operands.last() {
-                            let obj = operands.pop().unwrap();
-                            let func = FunctionCall::parse(lex).expect("functioncall should follow colon");
-                            let mut args = vec![obj.clone()];
-                            args.extend(func.args().iter().map(|e| e.clone()));
-                            let mcall = MethodCall::new(obj, func.name().clone(), args);
-                            operands.push(Expression::MethodCall(mcall));
-                            last_was_arg = true;
-                        } else {
-                            todo!("colon operator didn't have preceding argument!");
-                        }
-                    }
-                    seperator::Seperator::Dot => {
-                        lex.next();
-                        if let Some(op) = operands.last() {
-                            let obj = operands.pop().unwrap();
-                            if let Some(func) = FunctionCall::parse(lex) {
-                                let mcall = MethodCall::new(obj, func.name().clone(), func.args().clone());
-                                operands.push(Expression::MethodCall(mcall));
-                            }
-                            else {
-                                if let Some(Lexeme::Identifier(field)) = lex.next() {
-                                    let taccess = TableAccess::new_dot(obj, field);
-                                    operands.push(Expression::TableAccess(taccess));
-                                } else {
-                                    panic!("Dot operator without function call or field afterwards!");
-                                }
-                            }
-                            
-                            last_was_arg = true;
-                        } else {
-                            todo!("colon operator didn't have preceding argument!");
-                        }
-                    }
-                    _ => break
-                }
-            },
-            Lexeme::Identifier(ident) => {
-                if last_was_arg {
-                    break;
-                }
-                let mut dup_lex = lex.clone();
-                last_was_arg = true;
-                if let Some(funccall) = FunctionCall::parse(&mut dup_lex) {
-                    operands.push(Expression::FuncCall(funccall));
-                    *lex = dup_lex;
-                }
-                /* 
-                else if let Some(mcall) = MethodCall::parse({ dup_lex = lex.clone(); &mut dup_lex }) {
-                    operands.push(Expression::MethodCall(mcall));
-                    *lex = dup_lex;
+            Some(Lexeme::Seperator(seperator::Seperator::OpenBracket)) => {
+                lex.next();
+                let key = expect_expression(lex)?;
+                if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::CloseBracket)) {
+                    return Err(ParseError::expected("']'", lex));
                 }
-                */
-                else {
-                    lex.next();
-                    operands.push(Expression::Identifier(ident.clone()));
-                }        
-            },
-            Lexeme::NumericLiteral(nlit) => {
                 lex.next();
-                assert!(!last_was_arg);
-                last_was_arg = true;
-                operands.push(Expression::NumericLiteral(nlit.clone()));
+                let span = exp.span().to(lex.last_span());
+                Expression::new(ExpressionKind::TableAccess(TableAccess::new_index(exp, key)), span)
             },
-            Lexeme::StringLiteral(slit) => {
+            Some(Lexeme::Seperator(seperator::Seperator::Colon)) => {
                 lex.next();
-                assert!(!last_was_arg);
-                last_was_arg = true;
-                operands.push(Expression::StringLiteral(slit.clone()));
+                let Some(Lexeme::Identifier(method)) = lex.peek_lexeme() else {
+                    return Err(ParseError::expected("<name>", lex));
+                };
+                lex.next();
+                let args = parse_call_args(lex)?;
+                let span = exp.span().to(lex.last_span());
+                let mcall = MethodCall::new(exp, Identifier::from(method), args, span.clone());
+                Expression::new(ExpressionKind::MethodCall(mcall), span)
             },
-            _ => break
-        }
-
-        //eprintln!("matched tok {:?}", tok);
-        while !last_was_arg && operations.len() > 0 {
-            //eprintln!("in shunting yard processing");
-            //eprintln!("top of op stack is {:?}", operations.last());
-            if operations.len() > 1 {
-                let current = operations.pop().unwrap();
-                let previous = operations.pop().unwrap();
-                if previous == ExpOperation::CloseParen {
-                    // find matching open paren
-                    // this code for wrapping a phrase is the same that is called at the end of parsing
-                    // therefore, make that subroutine
-                    //eprintln!("parsing close paren, current token is {:?}, stack is {:?}", current, operations);
-                    
-                    let mut start_idx = operations.len() - 1;
-                    while operations[start_idx] != ExpOperation::OpenParen {
-                        start_idx -= 1;
-                    }
-                    shunting_yard(&operations[start_idx + 1..], &mut operands);
-                    operations.truncate(start_idx);
-                    operations.push(current);
-                }
-                else if ExpOperation::precedence(previous, current) == Ordering::Greater {
-                    // prev arg binds to last two operands
-                    if previous != ExpOperation::OpenParen {
-                        let rhs = Box::new(operands.pop().unwrap());
-                        let lhs = Box::new(operands.pop().unwrap());
-                        let new_operand = Expression::BinaryExp(
-                            BinaryExpression { op: previous, lhs, rhs }  
-                        );
-                        operands.push(new_operand);
-                        operations.push(current);
-                    } else {
-                        operations.push(previous); 
-                        operations.push(current);
-                        break;
-                    }
-                } else if ExpOperation::precedence(previous, current) == Ordering::Equal {
-                    // we've already handled paren cases
-                    // if op isnt exp, we can eval prev
-                    if previous != ExpOperation::Exp && previous != ExpOperation::Concat {
-                        // code duplication
-                        // TODO: MERGE PATHS
-                        let rhs = Box::new(operands.pop().unwrap());
-                        let lhs = Box::new(operands.pop().unwrap());
-                        let new_operand = Expression::BinaryExp(
-                            BinaryExpression { op: previous, lhs, rhs }  
-                        );
-                        operands.push(new_operand);
-                        operations.push(current);
-                    } else { todo!() }
-                }
-                else { operations.push(previous); operations.push(current); break; }
-            } else if *operations.last().unwrap() ==  ExpOperation::UnaryMinus {
-                /* 
-                operations.pop();
-                let arg = Box::new(operands.pop().unwrap());
-                let new_arg = Expression::UnaryExp(
-                    UnaryExpression { op: ExpOperation::UnaryMinus, arg }
-                );
-                operands.push(new_arg);
-                */
-                todo!();
-            }
-            else { //eprintln!("breaking"); 
-                break 
-            }
-        }
-    }
-
-    while let Some(op) = operations.pop() {
-        if op == ExpOperation::UnaryMinus {
-            // duplicate code
-            let arg = Box::new(operands.pop().unwrap());
-            let new_arg = Expression::UnaryExp(
-                UnaryExpression { op: ExpOperation::UnaryMinus, arg }
-            );
-            operands.push(new_arg);
-        }
-        else {
-            // also code dup
-            let rhs = Box::new(operands.pop().unwrap());
-            let lhs = Box::new(operands.pop().unwrap());
-            let new_operand = Expression::BinaryExp(
-                BinaryExpression { op: op, lhs, rhs }  
-            );
-            operands.push(new_operand);
-        }
+            Some(Lexeme::Seperator(seperator::Seperator::OpenParen | seperator::Seperator::OpenCurly) | Lexeme::StringLiteral(_)) => {
+                let args = parse_call_args(lex)?;
+                let span = exp.span().to(lex.last_span());
+                Expression::new(ExpressionKind::FuncCall(FunctionCall::new(exp, args, span.clone())), span)
+            },
+            _ => return Ok(Some(exp)),
+        };
     }
-
-    //println!("Operands: {:?}", operands);
-    //println!("Operations: {:?}", operations);
-
-    operands.pop()
 }
 
-fn shunting_yard(ops: &[ExpOperation], args: &mut Vec<Expression>) {
-    // invariant held by the algorithm is that operations are always sorted lowest associativity to highest
-    // when we get an op and see that the top of the stack has higher precedence we pack that op into an expression
-    // therefore, to finish the rest of these args, just go one by one top to bottom
-    for op in ops.iter().rev() {
-        if *op != ExpOperation::UnaryMinus {
-            if args.len() < 2 { break; }
-            let lhs = Box::new(args.pop().unwrap());
-            let rhs = Box::new(args.pop().unwrap());
-            let e = BinaryExpression { op: *op, lhs, rhs };
-            args.push(Expression::BinaryExp(e));
-        } else { todo!() }
+/// Parse the arguments of a call: a parenthesised list, a string literal or a table constructor
+fn parse_call_args(lex: &mut Parser) -> Result<Vec<Expression>, ParseError> {
+    match lex.peek_lexeme() {
+        Some(Lexeme::Seperator(seperator::Seperator::OpenParen)) => {
+            lex.next();
+            parse_paren_list(lex, expect_expression)
+        },
+        Some(Lexeme::StringLiteral(_) | Lexeme::Seperator(seperator::Seperator::OpenCurly)) => Ok(vec![parse_simple_expression(lex)?]),
+        _ => Err(ParseError::expected("function arguments", lex)),
     }
 }
 
+/// Consume the `)` closing the parenthesis opened at `start`
+fn expect_closing_paren(lex: &mut Parser, start: &Span) -> Result<(), ParseError> {
+    if lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::CloseParen)) {
+        lex.next();
+        Ok(())
+    } else { Err(ParseError::expected_closing("')'", "'('", start, lex)) }
+}
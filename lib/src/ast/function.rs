@@ -1,78 +1,173 @@
-use std::{fmt::{Debug, Display}, io};
+use std::{fmt::{Debug, Display}, rc::Rc};
 
-use crate::{ast::{context::Ctx, expression::{parse_expression, Expression}, parse_comma_list, parse_paren_list, Block}, builtins, lexer::{identifier::Identifier, seperator::Seperator, Lexeme, Lexer}, value::{flatten_values, Value}};
+use crate::{ast::{context::{Ctx, Env, FrameKind}, error::LuaError, expression::{eval_explist, Expression, ExpressionKind}, Block, ControlFlow}, lexer::{identifier::Identifier, span::Span}, value::{meta::metamethod, table::{check_indexable, index}, Value}};
 
 #[derive(Clone)]
 pub struct LuaFunction {
     pub args: Vec<Identifier>,
+    /// Whether the parameter list ends with `...`
+    pub is_vararg: bool,
     pub code: Option<Block>,
+    /// Where the function is defined, from its `function` keyword
+    pub span: Span,
+}
+
+impl Debug for LuaFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaFunction").field("args", &self.args).field("is_vararg", &self.is_vararg).finish_non_exhaustive()
+    }
+}
+
+/// A function implemented in Rust, which takes its arguments and returns its results
+pub type BuiltinFn = fn(&[Value], &mut Ctx) -> Result<Vec<Value>, LuaError>;
+
+/// A Lua function, along with the locals it can see from where it was created
+#[derive(Clone)]
+pub struct Closure {
+    func: Rc<LuaFunction>,
+    env: Env,
+}
+
+impl Closure {
+    pub fn new(func: Rc<LuaFunction>, env: Env) -> Closure {
+        Closure { func, env }
+    }
 }
 
 #[derive(Clone)]
 pub enum Function {
-    LuaFunction(LuaFunction),
-    Builtin(fn(&Vec<Value>) -> Vec<Value>),
+    Closure(Closure),
+    Builtin(BuiltinFn),
 }
 
 impl Function {
-    pub fn call(&self, args: &Vec<Expression>, ctx: &mut Ctx) -> Value {
-        let arg_vals = args.iter().map(|e| e.eval(ctx)).collect::<Vec<_>>();
-        let mut rvs = flatten_values(
+    /// Run the function with already evaluated arguments
+    /// Returns the function's results, or the call it ends with as `return f(...)`, which the caller makes instead
+    fn run(&self, arg_vals: Vec<Value>, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
         match self {
-                Function::LuaFunction(lfunc) => {
-                    // resolve argument expressions
-                    let mut val_iter = arg_vals.into_iter();
-                    // Note: there's some weird behavior with this one
-                    // In order to make lexical scoping work, block entry automically makes the context enter a block
-                    // This means, when we call a function, we actually enter the block twice - once here, once when calling code.walk(ctx)
-                    // FIXME!
-
-                    // this entry should actually add a new function gate
-                    // locals in larger scopes can be accessed, until you cross the function barrier.
-                    ctx.enter_block();
-                    // add new locals
-                    for arg in &lfunc.args {
-                        ctx.new_local(arg.clone(), val_iter.next().unwrap_or(Value::Nil));
+            Function::Closure(closure) => {
+                let lfunc = &closure.func;
+                let mut val_iter = arg_vals.into_iter();
+                let params = lfunc.args.iter().map(|arg| (arg.clone(), val_iter.next().unwrap_or(Value::Nil))).collect::<Vec<_>>();
+                let varargs = if lfunc.is_vararg { val_iter.collect() } else { Vec::new() };
+                ctx.in_function_scope(&closure.env, varargs, |ctx| {
+                    // arguments are locals of the function body
+                    for (arg, val) in params {
+                        ctx.new_local(arg, val);
                     }
-                    if let Some(code) = &lfunc.code {
-                        code.walk(ctx);
+                    let flow = lfunc.code.as_ref().map_or(Ok(ControlFlow::Normal), |code| code.walk_statements(ctx))?;
+                    match flow {
+                        flow @ (ControlFlow::Return(_) | ControlFlow::TailCall(_)) => Ok(flow),
+                        // the parser makes sure breaks and gotos can't leave the function
+                        _ => Ok(ControlFlow::Return(Vec::new())),
                     }
-                    ctx.leave_block()
-                    
-                }
-                Function::Builtin(bfunc) => {
-                    bfunc(&arg_vals)
-                }
-        });   
-        if rvs.is_empty() { Value::Nil }
-        else if rvs.len() == 1 { rvs.pop().unwrap() }
-        else { Value::RetVals(rvs) }
+                })
+            },
+            Function::Builtin(bfunc) => {
+                bfunc(&arg_vals, ctx).map(ControlFlow::Return)
+            }
+        }
+    }
+}
+
+/// Whether `val` can be called: functions can, and so can values with a `__call` metamethod
+pub fn is_callable(val: &Value, ctx: &Ctx) -> bool {
+    matches!(val, Value::Function(_)) || metamethod(val, "__call", ctx).is_some()
+}
+
+/// Call `func` with already evaluated arguments
+/// Values other than functions are called through their `__call` metamethod, which gets the value as its first argument
+pub fn call_value(func: &Value, args: Vec<Value>, ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    call_named(func, args, None, ctx)
+}
+
+/// How much stack has to be left for a call to run on the current stack, and how much more to allocate when it isn't
+/// Every Lua call nests several Rust calls, so deep recursion runs on stack segments allocated as needed
+const STACK_RED_ZONE: usize = 256 * 1024;
+const STACK_SEGMENT: usize = 16 * 1024 * 1024;
+
+/// Like `call_value`, where the call site names the function `name`, such as `global 'f'`, for tracebacks
+/// A function ending with a tail call returns, and the call is made here, so tail calls don't use up the stack
+pub fn call_named(func: &Value, args: Vec<Value>, name: Option<String>, ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let mut call = TailCall { func: func.clone(), args, name };
+    let mut is_tail_call = false;
+    loop {
+        let TailCall { func, mut args, name } = call;
+        let f = match func {
+            Value::Function(f) => f,
+            _ => match metamethod(&func, "__call", ctx) {
+                Some(handler) => {
+                    args.insert(0, func);
+                    call = TailCall { func: handler, args, name };
+                    continue;
+                },
+                None => return Err(LuaError::new(format!("attempt to call a {} value", func.type_name()))),
+            },
+        };
+        let kind = match f.as_ref() {
+            Function::Closure(closure) => FrameKind::Lua(closure.func.span.clone()),
+            Function::Builtin(_) => FrameKind::Builtin,
+        };
+        // a tail call replaces its caller's frame
+        if !is_tail_call && ctx.frame_depth() > ctx.max_depth() {
+            return Err(LuaError::new("stack overflow"));
+        }
+        ctx.push_frame(kind, name, is_tail_call);
+        // the frame of a function raising an error stays until the error is caught
+        let flow = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, || f.run(args, ctx))?;
+        ctx.pop_frame();
+        match flow {
+            ControlFlow::TailCall(tail) => {
+                call = *tail;
+                is_tail_call = true;
+            },
+            ControlFlow::Return(vals) => return Ok(vals),
+            _ => unreachable!("functions only return or make tail calls"),
+        }
     }
 }
 
+/// A call in tail position, as in `return f(...)`, with the function and arguments already evaluated
+#[derive(Clone, Debug, PartialEq)]
+pub struct TailCall {
+    func: Value,
+    args: Vec<Value>,
+    /// What the call site names the function, although the traceback of a tail call doesn't show it
+    name: Option<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct FunctionCall {
-    name: Identifier,
+    func: Box<Expression>,
     args: Vec<Expression>,
+    span: Span,
 }
 
 impl FunctionCall {
-    pub fn new(name: Identifier, args: Vec<Expression>) -> FunctionCall {
-        FunctionCall { name, args }
+    pub fn new(func: Expression, args: Vec<Expression>, span: Span) -> FunctionCall {
+        FunctionCall { func: Box::new(func), args, span }
     }
 
-    pub fn name(&self) -> &Identifier {
-        &self.name
+    /// The name of the called function, if it's called by name
+    pub fn name(&self) -> Option<&str> {
+        match self.func.kind() {
+            ExpressionKind::Identifier(i) => Some(i.0.as_str()),
+            _ => None,
+        }
     }
 
     pub fn args(&self) -> &Vec<Expression> {
         &self.args
     }
 
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
     pub fn print_tree(&self, depth: usize) {
         let tabs = "\t".repeat(depth);
-        print!("{tabs}FunctionCall [ {}(", self.name);
-        if self.args.len() > 0 {
+        print!("{tabs}FunctionCall [ {}(", self.func);
+        if !self.args.is_empty() {
             for arg in &self.args[0..self.args.len() - 1] {
                 print!("{tabs}{arg}, ");
             }
@@ -81,53 +176,29 @@ impl FunctionCall {
         println!("{tabs}) ]");
     }
 
-    pub fn parse(lex: &mut Lexer) -> Option<FunctionCall> {
-        if let Some(Lexeme::Identifier(ident)) = lex.next() 
-            && let Some(Lexeme::Seperator(Seperator::OpenParen)) = lex.next()
-        {
-            //println!("resolving function call");
-            let exps = parse_paren_list(lex, parse_expression);
-            if exps.is_none() {
-                eprintln!("Didn't parse paren functioncall list properly!");
-                for l in lex {
-                    eprintln!("\tNext lex: {l:?}");
-                }
-                todo!();
-             }
-            let exps = exps.unwrap();
-            Some(FunctionCall::new(ident.clone(), exps))
-        } 
-        else { None }
+    pub fn call(&self, ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+        let TailCall { func, args, name } = self.prepare(ctx)?;
+        ctx.set_position(&self.span);
+        call_named(&func, args, name, ctx)
     }
 
-    pub fn call(&self, ctx: &mut Ctx) -> Value {
-        // FIXME: REGISTER AS A GLOBAL FUNCTION
-        if self.name.0 == "print" {
-            for val in self.args.iter().map(|e| e.eval(ctx)) {
-                match val {
-                    Value::String(s) => print!("{s}"),
-                    Value::Number(n) => print!("{n}"),
-                    _ => todo!()
-                }
-                print!("\t")
-            }
-            println!();
-            return Value::Nil;
-        }
-        match ctx.get_var(&self.name) {
-            Some(Value::Function(fcode)) => {
-                fcode.call(&self.args, ctx)
-            },
-            // FIXME
-            _ => { panic!("function not defined, handle this error gracefully!") }
+    /// Evaluate the function and arguments, without making the call
+    pub fn prepare(&self, ctx: &mut Ctx) -> Result<TailCall, LuaError> {
+        let func = self.func.eval(ctx)?;
+        let args = eval_explist(&self.args, ctx)?;
+        let name = self.func.describe(ctx);
+        if !is_callable(&func, ctx) {
+            let var = name.map(|var| format!(" ({var})")).unwrap_or_default();
+            return Err(LuaError::new(format!("attempt to call a {} value{var}", func.type_name())));
         }
+        Ok(TailCall { func, args, name })
     }
 }
 
 impl Display for FunctionCall {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "FunctionCall [ {}(", self.name)?;
-        if self.args.len() > 0 {
+        write!(f, "FunctionCall [ {}(", self.func)?;
+        if !self.args.is_empty() {
             for arg in &self.args[0..self.args.len() - 1] {
                 write!(f, "{arg}, ")?;
             }
@@ -143,84 +214,34 @@ pub struct MethodCall {
     obj: Box<Expression>,
     method: Identifier,
     args: Vec<Expression>,
+    span: Span,
 }
 
 impl MethodCall {
-    /* 
-    pub fn parse(lex: &mut Lexer) -> Option<MethodCall> {
-        let dup_lex = lex.clone();
-        if let Some(obj) = parse_expression(lex)
-            && let Some(Lexeme::Seperator(Seperator::Dot)) = lex.next()
-            && let Some(fcall) = FunctionCall::parse(lex)
-        {
-            return Some(MethodCall { obj: Box::new(obj), method: fcall.name, args: fcall.args });
-        }
-        *lex = dup_lex; 
-        if let Some(obj) = parse_expression(lex)
-            && let Some(Lexeme::Seperator(Seperator::Colon)) = lex.next()
-            && let Some(mut fcall) = FunctionCall::parse(lex)
-        {
-            let mut args = vec![obj.clone()];
-            args.append(&mut fcall.args);
-            return Some(MethodCall { obj: Box::new(obj), method: fcall.name, args });
-        } 
-        else { None }
-    }*/
-
-    pub fn call(&self, ctx: &mut Ctx) -> Value {
-        /*if self.obj.0 == "io" && self.method.0 == "read" {
-            let mut buf = String::new();
-            let stdin = io::stdin();
-            stdin.read_line(&mut buf).unwrap();
-
-            // check args
-            if let Some(a) = self.args.first() 
-            && let Value::String(s) = a.eval(ctx) 
-            && &s.as_str()[0..1] == "n" 
-            {
-                Value::Number(buf.trim().parse().unwrap())
-            } else {
-                Value::String(buf)   
-            }    
-        }
-
-        else if self.obj.0 == "io" && self.method.0 == "write" {
-            for arg in &self.args {
-                let v = arg.eval(ctx);
-                match v {
-                    Value::String(s) => print!("{s}"),
-                    Value::Number(n) => print!("{n}"),
-                    _ => print!("{:?}", v)
-                }
-            }
-            Value::Nil
-        } */
-
-        if self.obj_name() == Some("math") && self.method.0 == "abs" {
-            if let Some(Value::Number(n)) = self.args.first().map(|e| e.eval(ctx)) {
-                Value::Number(n.abs())
-            } else { panic!() }
-        }
-
-        else if self.obj_name() == Some("string") && self.method.0 == "format" {
-            let s = self.args[0].eval(ctx).as_string().expect("format string arg wasnt string");
-            let vals = self.args[1..].iter().map(|e| e.eval(ctx)).collect();
-            Value::String(builtins::string::format(&s, &vals))
-        }
+    pub fn call(&self, ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+        let TailCall { func, args, name } = self.prepare(ctx)?;
+        ctx.set_position(&self.span);
+        call_named(&func, args, name, ctx)
+    }
 
-        else {
-            if let Value::Table(t) = self.obj.eval(ctx) {
-                if let Some(Value::Function(f)) = t.borrow().get(&Value::String(self.method.0.clone())) {
-                    f.call(&self.args, ctx)
-                } else { panic!("method not found in object") }
-            } else { panic!("method object not found") }
+    /// Evaluate the receiver, method and arguments, without making the call
+    pub fn prepare(&self, ctx: &mut Ctx) -> Result<TailCall, LuaError> {
+        // the receiver is evaluated once, and passed as the first argument
+        let obj = self.obj.eval(ctx)?;
+        check_indexable(&obj, &self.obj, ctx)?;
+        let func = index(&obj, &Value::String(self.method.0.as_str().into()), ctx)?;
+        if !is_callable(&func, ctx) {
+            return Err(LuaError::new(format!("attempt to call a {} value (method '{}')", func.type_name(), self.method.0)));
         }
+        let mut args = vec![obj];
+        args.append(&mut eval_explist(&self.args, ctx)?);
+        Ok(TailCall { func, args, name: Some(format!("method '{}'", self.method_name_with_receiver())) })
     }
 
     pub fn print_tree(&self, depth: usize) {
         let tabs = "\t".repeat(depth);
-        print!("{tabs}MethodCall [ {}.{}(", self.obj, self.method);
-        if self.args.len() > 0 {
+        print!("{tabs}MethodCall [ {}:{}(", self.obj, self.method);
+        if !self.args.is_empty() {
             for arg in &self.args[0..self.args.len() - 1] {
                 print!("{tabs}{arg}, ");
             }
@@ -229,26 +250,31 @@ impl MethodCall {
         println!("{tabs}) ]");
     }
 
-    pub fn obj_name(&self) -> Option<&str> {
-        match &*self.obj {
-            Expression::Identifier(i) => Some(i.0.as_str()),
-            _ => None,
+    pub fn method_name(&self) -> &str {
+        self.method.0.as_str()
+    }
+
+    /// The method's name, along with the receiver's if it's a variable, as in `obj:m`
+    fn method_name_with_receiver(&self) -> String {
+        match self.obj.kind() {
+            ExpressionKind::Identifier(obj) => format!("{}:{}", obj.0, self.method.0),
+            _ => self.method.0.clone(),
         }
     }
 
-    pub fn method_name(&self) -> &str {
-        self.method.0.as_str()
+    pub fn new(obj: Expression, method: Identifier, args: Vec<Expression>, span: Span) -> MethodCall {
+        MethodCall { obj: Box::new(obj), method, args, span }
     }
 
-    pub fn new(obj: Expression, method: Identifier, args: Vec<Expression>) -> MethodCall {
-        MethodCall { obj: Box::new(obj), method, args }
+    pub fn span(&self) -> &Span {
+        &self.span
     }
 }
 
 impl Display for MethodCall {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "MethodCall [ {}.{}(", self.obj, self.method)?;
-        if self.args.len() > 0 {
+        write!(f, "MethodCall [ {}:{}(", self.obj, self.method)?;
+        if !self.args.is_empty() {
             for arg in &self.args[0..self.args.len() - 1] {
                 write!(f, "{arg}, ")?;
             }
@@ -1,46 +1,385 @@
-use std::{collections::HashMap, fmt::{Debug, Display}, rc::Rc};
+use std::{fmt::Display, rc::Rc};
 
-use crate::{ast::{context::Ctx, expression::{parse_expression, Expression}, function::{Function, FunctionCall, LuaFunction, MethodCall}, parse_paren_list, Block}, lexer::{self, identifier::Identifier, seperator, Lexeme, Lexer}, value::{flatten_values, table::TableAssign, Boolean, Value}};
+use crate::{ast::{context::Ctx, error::LuaError, expression::{eval_explist, expect_expression, parse_explist, parse_prefix_expression, Expression, ExpressionKind}, function::{call_value, is_callable, Closure, Function, FunctionCall, LuaFunction, MethodCall}, Block, ControlFlow}, lexer::{self, identifier::Identifier, keyword::Keyword, seperator, span::Span, Lexeme}, parser::{self, error::ParseError, Parser}, value::{number::{float_to_integer, Number}, table::{check_indexable_as, index, set_index, TableAccess}, Value}};
 
+/// Something that can be assigned to: a variable or a table field
+#[derive(Clone, Debug)]
+pub enum AssignTarget {
+    Name(Identifier),
+    Field(TableAccess),
+}
+
+impl AssignTarget {
+    /// The target an expression refers to, where `lex` is just after the expression
+    /// Only names and field accesses can be assigned to
+    fn new(exp: Expression, lex: &mut Parser) -> Result<AssignTarget, ParseError> {
+        let span = exp.span().clone();
+        match exp.into_kind() {
+            ExpressionKind::Identifier(ident) if lex.is_read_only(&ident) => Err(const_assign_error(&ident, span)),
+            ExpressionKind::Identifier(ident) => Ok(AssignTarget::Name(ident)),
+            ExpressionKind::TableAccess(access) => Ok(AssignTarget::Field(access)),
+            _ => Err(ParseError::syntax_error(lex)),
+        }
+    }
+}
+
+impl Display for AssignTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssignTarget::Name(ident) => write!(f, "{}", ident.0),
+            AssignTarget::Field(access) => write!(f, "{access:?}"),
+        }
+    }
+}
+
+/// `varlist = explist`
 #[derive(Clone)]
 pub struct Assignment {
-    idents: Vec<Identifier>,
+    targets: Vec<AssignTarget>,
     exps: Vec<Expression>,
-    local: bool
 }
 
 impl Assignment {
     pub fn print_tree(&self, depth: usize) {
         let tabs = "\t".repeat(depth);
-        print!("{tabs}Assignment [ ");
-        for ident in &self.idents {
-            print!("{ident} ");
-        }
-        print!("= ");
-        for exp in &self.exps {
-            print!("{exp} ");
+        println!("{tabs}{self}");
+    }
+
+    fn walk(&self, ctx: &mut Ctx) -> Result<(), LuaError> {
+        // the tables and keys of the targets are evaluated first, then all the values, and only then is anything assigned
+        let fields = self.targets.iter().map(|target| match target {
+            AssignTarget::Name(_) => Ok(None),
+            AssignTarget::Field(access) => access.resolve(ctx).map(Some),
+        }).collect::<Result<Vec<_>, _>>()?;
+        let mut values = eval_explist(&self.exps, ctx)?.into_iter();
+        for (target, field) in self.targets.iter().zip(fields) {
+            let val = values.next().unwrap_or(Value::Nil);
+            match (target, field) {
+                (_, Some((obj, key))) => set_index(&obj, &key, val, ctx)?,
+                (AssignTarget::Name(ident), None) => ctx.set_var(ident.clone(), val)?,
+                (AssignTarget::Field(_), None) => unreachable!("fields are resolved before assigning"),
+            }
         }
-        println!("]");
+        Ok(())
     }
 }
 
 impl Display for Assignment {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Assignment [ ")?;
-        for ident in &self.idents {
-            write!(f, "{ident} ")?;
+        for target in &self.targets {
+            write!(f, "{target} ")?;
         }
         write!(f, "= ")?;
         for exp in &self.exps {
             write!(f, "{exp} ")?;
         }
-        writeln!(f, "]")
+        write!(f, "]")
+    }
+}
+
+/// The attribute of a local variable, as in `local x <const> = 1`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attrib {
+    /// The local can't be assigned to
+    Const,
+    /// The local can't be assigned to, and its value is closed when the local goes out of scope
+    Close,
+}
+
+impl Display for Attrib {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Attrib::Const => write!(f, "<const>"),
+            Attrib::Close => write!(f, "<close>"),
+        }
+    }
+}
+
+/// `local attnamelist [= explist]`
+#[derive(Clone)]
+pub struct LocalAssignment {
+    names: Vec<(Identifier, Option<Attrib>)>,
+    exps: Vec<Expression>,
+}
+
+impl LocalAssignment {
+    pub fn print_tree(&self, depth: usize) {
+        let tabs = "\t".repeat(depth);
+        println!("{tabs}{self}");
+    }
+}
+
+impl Display for LocalAssignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Local [ ")?;
+        for (name, attrib) in &self.names {
+            match attrib {
+                Some(attrib) => write!(f, "{} {attrib} ", name.0)?,
+                None => write!(f, "{} ", name.0)?,
+            }
+        }
+        write!(f, "= ")?;
+        for exp in &self.exps {
+            write!(f, "{exp} ")?;
+        }
+        write!(f, "]")
+    }
+}
+
+
+#[derive(Clone)]
+pub struct WhileStatement {
+    test: Expression,
+    code: Block,
+}
+
+impl WhileStatement {
+    pub fn print_tree(&self, depth: usize) {
+        let tabs = "\t".repeat(depth);
+        println!("{tabs}While: [");
+        println!("{tabs}\tTest: {}", self.test);
+        println!("{tabs}\tCode: ");
+        self.code.print_tree(depth + 2);
+        println!("{tabs}]");
+    }
+
+    pub fn walk(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        while self.test.eval(ctx)?.as_bool() {
+            if let Some(flow) = self.code.walk(ctx)?.loop_exit() { return Ok(flow); }
+        }
+        Ok(ControlFlow::Normal)
+    }
+}
+
+impl Display for WhileStatement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "While: [")?;
+        writeln!(f, "\tTest: {}", self.test)?;
+        writeln!(f, "\tCode: {}", self.code)?;
+        write!(f, "]")
+    }
+}
+
+#[derive(Clone)]
+pub struct RepeatStatement {
+    code: Block,
+    test: Expression,
+}
+
+impl RepeatStatement {
+    pub fn print_tree(&self, depth: usize) {
+        let tabs = "\t".repeat(depth);
+        println!("{tabs}Repeat: [");
+        println!("{tabs}\tCode: ");
+        self.code.print_tree(depth + 2);
+        println!("{tabs}\tUntil: {}", self.test);
+        println!("{tabs}]");
+    }
+
+    pub fn walk(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        // the test is part of the body's scope, so it can see the body's locals
+        loop {
+            if let Some(flow) = self.code.walk_until(&self.test, ctx)? {
+                return Ok(flow.loop_exit().unwrap_or(ControlFlow::Normal));
+            }
+        }
     }
 }
 
+impl Display for RepeatStatement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Repeat: [")?;
+        writeln!(f, "\tCode: {}", self.code)?;
+        writeln!(f, "\tUntil: {}", self.test)?;
+        write!(f, "]")
+    }
+}
+
+/// `for var = start, limit [, step] do ... end`
+#[derive(Clone)]
+pub struct NumericFor {
+    var: Identifier,
+    start: Expression,
+    limit: Expression,
+    step: Option<Expression>,
+    code: Block,
+}
+
+impl NumericFor {
+    pub fn print_tree(&self, depth: usize) {
+        let tabs = "\t".repeat(depth);
+        println!("{tabs}Numeric For: [");
+        println!("{tabs}\tVariable: {}", self.var);
+        println!("{tabs}\tStart: {}", self.start);
+        println!("{tabs}\tLimit: {}", self.limit);
+        if let Some(step) = &self.step {
+            println!("{tabs}\tStep: {step}");
+        }
+        println!("{tabs}\tCode: ");
+        self.code.print_tree(depth + 2);
+        println!("{tabs}]");
+    }
+
+    pub fn walk(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        let start = self.start.eval(ctx)?;
+        let limit = self.limit.eval(ctx)?;
+        let step = self.step.as_ref().map_or(Ok(Value::Integer(1)), |e| e.eval(ctx))?;
+        match (start, step) {
+            (Value::Integer(start), Value::Integer(step)) => self.walk_integer(start, &limit, step, ctx),
+            (start, step) => {
+                let start = for_number(&start, "initial value")?;
+                let limit = for_number(&limit, "limit")?;
+                let step = for_number(&step, "step")?;
+                self.walk_float(start.as_float(), limit.as_float(), step.as_float(), ctx)
+            },
+        }
+    }
+
+    /// Run an integer loop, which precomputes its iteration count so the control variable can't overflow
+    fn walk_integer(&self, start: i64, limit: &Value, step: i64, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        if step == 0 { return Err(LuaError::new("'for' step is zero")); }
+        let Some(limit) = for_limit(start, limit, step)? else { return Ok(ControlFlow::Normal) };
+        let mut count = if step > 0 {
+            (limit as u64).wrapping_sub(start as u64) / step as u64
+        } else {
+            // -(step + 1) + 1 is -step, without overflowing for the minimum integer
+            (start as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+        };
+        let mut i = start;
+        loop {
+            let flow = self.code.walk_with_locals([(self.var.clone(), Value::Integer(i))], ctx)?;
+            if let Some(flow) = flow.loop_exit() { return Ok(flow); }
+            if count == 0 { return Ok(ControlFlow::Normal); }
+            count -= 1;
+            i = i.wrapping_add(step);
+        }
+    }
+
+    fn walk_float(&self, start: f64, limit: f64, step: f64, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        if step == 0.0 { return Err(LuaError::new("'for' step is zero")); }
+        let mut i = start;
+        while if step > 0.0 { i <= limit } else { i >= limit } {
+            let flow = self.code.walk_with_locals([(self.var.clone(), Value::Float(i))], ctx)?;
+            if let Some(flow) = flow.loop_exit() { return Ok(flow); }
+            i += step;
+        }
+        Ok(ControlFlow::Normal)
+    }
+}
+
+/// The number a numeric for loop's `what` converts to
+fn for_number(val: &Value, what: &str) -> Result<Number, LuaError> {
+    val.as_number().ok_or_else(|| LuaError::new(format!("'for' {what} must be a number")))
+}
+
+/// Convert the limit of an integer loop to an integer, clipping floats that are out of range
+/// Returns `None` if the loop shouldn't run at all
+fn for_limit(start: i64, limit: &Value, step: i64) -> Result<Option<i64>, LuaError> {
+    let limit = match for_number(limit, "limit")? {
+        Number::Integer(i) => i,
+        Number::Float(f) => {
+            let f = if step < 0 { f.ceil() } else { f.floor() };
+            match float_to_integer(f) {
+                Some(i) => i,
+                // too large: every integer is below the limit
+                None if f > 0.0 => if step < 0 { return Ok(None) } else { i64::MAX },
+                // too small, or NaN: every integer is above the limit
+                None => if step > 0 { return Ok(None) } else { i64::MIN },
+            }
+        },
+    };
+    let skip = if step > 0 { start > limit } else { start < limit };
+    Ok((!skip).then_some(limit))
+}
+
+impl Display for NumericFor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Numeric For: [")?;
+        writeln!(f, "\tVariable: {}", self.var)?;
+        writeln!(f, "\tStart: {}", self.start)?;
+        writeln!(f, "\tLimit: {}", self.limit)?;
+        if let Some(step) = &self.step {
+            writeln!(f, "\tStep: {step}")?;
+        }
+        writeln!(f, "\tCode: {}", self.code)?;
+        write!(f, "]")
+    }
+}
+
+/// `for names in exps do ... end`
+#[derive(Clone)]
+pub struct GenericFor {
+    names: Vec<Identifier>,
+    exps: Vec<Expression>,
+    code: Block,
+}
+
+impl GenericFor {
+    pub fn print_tree(&self, depth: usize) {
+        let tabs = "\t".repeat(depth);
+        print!("{tabs}Generic For: [ ");
+        for name in &self.names {
+            print!("{name} ");
+        }
+        print!("in ");
+        for exp in &self.exps {
+            print!("{exp} ");
+        }
+        println!();
+        println!("{tabs}\tCode: ");
+        self.code.print_tree(depth + 2);
+        println!("{tabs}]");
+    }
+
+    pub fn walk(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        // the explist gives the iterator function, the state, the initial control value and the closing value
+        let mut values = eval_explist(&self.exps, ctx)?.into_iter();
+        let iter = values.next().unwrap_or(Value::Nil);
+        let state = values.next().unwrap_or(Value::Nil);
+        let control = values.next().unwrap_or(Value::Nil);
+        let closing = values.next().unwrap_or(Value::Nil);
+
+        // the closing value is a hidden `<close>` local, which is closed however the loop ends
+        ctx.enter_block();
+        let flow = ctx.new_close_local(Identifier::from("(for state)"), closing)
+            .and_then(|()| self.iterate(&iter, &state, control, ctx));
+        ctx.leave_block(flow)
+    }
+
+    fn iterate(&self, iter: &Value, state: &Value, mut control: Value, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        if !is_callable(iter, ctx) {
+            return Err(LuaError::new(format!("attempt to call a {} value (for iterator 'for iterator')", iter.type_name())));
+        }
+        loop {
+            let mut results = call_value(iter, vec![state.clone(), control.clone()], ctx)?.into_iter();
+            control = results.next().unwrap_or(Value::Nil);
+            if control == Value::Nil { return Ok(ControlFlow::Normal); }
+
+            let mut locals = vec![(self.names[0].clone(), control.clone())];
+            for name in &self.names[1..] {
+                locals.push((name.clone(), results.next().unwrap_or(Value::Nil)));
+            }
+            if let Some(flow) = self.code.walk_with_locals(locals, ctx)?.loop_exit() { return Ok(flow); }
+        }
+    }
+}
 
-pub struct WhileStatement {}
-pub struct RepeatStatement {}
+impl Display for GenericFor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Generic For: [ ")?;
+        for name in &self.names {
+            write!(f, "{name} ")?;
+        }
+        write!(f, "in ")?;
+        for exp in &self.exps {
+            write!(f, "{exp} ")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "\tCode: {}", self.code)?;
+        write!(f, "]")
+    }
+}
 
 #[derive(Clone)]
 pub struct Conditional {
@@ -85,15 +424,78 @@ impl Display for Conditional {
     }
 }
 
-pub struct Goto {}
-pub struct Label {}
-pub struct ForStatement {}
 
 
+/// The name in a function statement: a variable, followed by any number of fields
+/// `function a.b.c()` has the name `a` and the fields `b` and `c`
 #[derive(Clone)]
-pub struct FunctionDef {
+pub struct FuncName {
     name: Identifier,
-    func: LuaFunction,
+    fields: Vec<Identifier>,
+}
+
+impl FuncName {
+    fn parse(lex: &mut Parser) -> Result<FuncName, ParseError> {
+        let name = expect_name(lex)?;
+        let mut fields = Vec::new();
+        while lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Dot)) {
+            lex.next();
+            fields.push(expect_name(lex)?);
+        }
+        Ok(FuncName { name, fields })
+    }
+
+    /// The table holding the last field, and the key of that field
+    /// Returns `None` if the name is just a variable
+    fn resolve_field(&self, ctx: &mut Ctx) -> Result<Option<(Value, Value)>, LuaError> {
+        let Some((last, path)) = self.fields.split_last() else { return Ok(None) };
+        let mut obj = ctx.get_var(&self.name)?;
+        check_indexable_as(&obj, || Some(format!("{} '{}'", ctx.var_kind(&self.name), self.name.0)), ctx)?;
+        for field in path {
+            obj = index(&obj, &field_key(field), ctx)?;
+            check_indexable_as(&obj, || Some(format!("field '{}'", field.0)), ctx)?;
+        }
+        Ok(Some((obj, field_key(last))))
+    }
+
+    /// The value the name refers to
+    fn eval(&self, ctx: &mut Ctx) -> Result<Value, LuaError> {
+        match self.resolve_field(ctx)? {
+            Some((obj, key)) => index(&obj, &key, ctx),
+            None => ctx.get_var(&self.name),
+        }
+    }
+
+    /// Assign `val` to the variable or field the name refers to
+    fn assign(&self, val: Value, ctx: &mut Ctx) -> Result<(), LuaError> {
+        match self.resolve_field(ctx)? {
+            Some((obj, key)) => set_index(&obj, &key, val, ctx),
+            None => ctx.set_var(self.name.clone(), val),
+        }
+    }
+}
+
+/// The key of the field `obj.field`
+fn field_key(field: &Identifier) -> Value {
+    Value::String(field.0.as_str().into())
+}
+
+impl Display for FuncName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name.0)?;
+        for field in &self.fields {
+            write!(f, ".{}", field.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// `function name() ... end`, or `local function name() ... end`
+#[derive(Clone)]
+pub struct FunctionDef {
+    name: FuncName,
+    func: Rc<LuaFunction>,
+    local: bool,
 }
 
 impl FunctionDef {
@@ -102,7 +504,7 @@ impl FunctionDef {
         println!("{tabs}FunctionDef: [");
         println!("{tabs}\tName: {}", self.name);
         print!("{tabs}\tArgs: ");
-        if self.func.args.len() > 0 {
+        if !self.func.args.is_empty() {
             for arg in &self.func.args[0..(self.func.args.len() - 1)] {
                 print!("{tabs}{arg}, ");
             }
@@ -125,7 +527,7 @@ impl Display for FunctionDef {
         writeln!(f, "FunctionDef: [")?;
         writeln!(f, "\tName: {}", self.name)?;
         write!(f, "\tArgs: ")?;
-        if self.func.args.len() > 0 {
+        if !self.func.args.is_empty() {
             for arg in &self.func.args[0..(self.func.args.len() - 1)] {
                 write!(f, "{arg}, ")?;
             }
@@ -143,11 +545,12 @@ impl Display for FunctionDef {
     }
 }
 
+/// `function obj:method() ... end`, which defines a function with an extra `self` argument
 #[derive(Clone)]
 pub struct MethodDef {
-    obj: Identifier,
+    obj: FuncName,
     method: Identifier,
-    func: LuaFunction,
+    func: Rc<LuaFunction>,
 }
 
 impl MethodDef {
@@ -157,7 +560,7 @@ impl MethodDef {
         println!("{tabs}\tParent: {}", self.obj);
         println!("{tabs}\tMethod: {}", self.method);
         print!("{tabs}\tArgs: ");
-        if self.func.args.len() > 0 {
+        if !self.func.args.is_empty() {
             for arg in &self.func.args[0..(self.func.args.len() - 1)] {
                 print!("{tabs}{arg}, ");
             }
@@ -181,7 +584,7 @@ impl Display for MethodDef {
         writeln!(f, "\tParent: {}", self.obj)?;
         writeln!(f, "\tMethod: {}", self.method)?;
         write!(f, "\tArgs: ")?;
-        if self.func.args.len() > 0 {
+        if !self.func.args.is_empty() {
             for arg in &self.func.args[0..(self.func.args.len() - 1)] {
                 write!(f, "{arg}, ")?;
             }
@@ -200,8 +603,6 @@ impl Display for MethodDef {
 }
 
 
-pub struct Break {} // ?
-
 #[derive(Clone)]
 pub struct Return {
     vals: Vec<Expression>,
@@ -229,8 +630,9 @@ impl Display for Return {
 }
 
 #[derive(Clone)]
-pub enum Statement {
+pub enum StatementKind {
     Assignment(Assignment),
+    Local(LocalAssignment),
     Conditional(Conditional),
     FunctionDef(FunctionDef),
     FunctionCall(FunctionCall),
@@ -238,266 +640,618 @@ pub enum Statement {
     MethodCall(MethodCall),
     Return(Return),
     Do(Block),
-    TableAssign(TableAssign),
+    While(WhileStatement),
+    Repeat(RepeatStatement),
+    NumericFor(Box<NumericFor>),
+    GenericFor(GenericFor),
+    Break,
+    Goto(Identifier),
+    Label(Identifier),
+}
+
+#[derive(Clone)]
+pub struct Statement {
+    kind: StatementKind,
+    span: Span,
 }
 
 impl Display for Statement {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Statement::Assignment(a) => { write!(f, "{}", a) },
-            Statement::Conditional(cond) => { write!(f, "{}", cond) },
-            Statement::FunctionDef(fdef) => { write!(f, "{}", fdef) },
-            Statement::FunctionCall(fcall) => { write!(f, "{}", fcall) },
-            Statement::MethodDef(mdef) => { write!(f, "{}", mdef) },
-            Statement::MethodCall(mcall) => { write!(f, "{}", mcall) },
-            Statement::Return(r) => { write!(f, "{}", r) },
-            Statement::Do(d) => { write!(f, "Do [ {} ]", d) },
-            Statement::TableAssign(tassign) => {write!(f, "{}", tassign) }
+        match &self.kind {
+            StatementKind::Assignment(a) => { write!(f, "{}", a) },
+            StatementKind::Local(l) => { write!(f, "{}", l) },
+            StatementKind::Conditional(cond) => { write!(f, "{}", cond) },
+            StatementKind::FunctionDef(fdef) => { write!(f, "{}", fdef) },
+            StatementKind::FunctionCall(fcall) => { write!(f, "{}", fcall) },
+            StatementKind::MethodDef(mdef) => { write!(f, "{}", mdef) },
+            StatementKind::MethodCall(mcall) => { write!(f, "{}", mcall) },
+            StatementKind::Return(r) => { write!(f, "{}", r) },
+            StatementKind::Do(d) => { write!(f, "Do [ {} ]", d) },
+            StatementKind::While(w) => { write!(f, "{}", w) },
+            StatementKind::Repeat(r) => { write!(f, "{}", r) },
+            StatementKind::NumericFor(nf) => { write!(f, "{}", nf) },
+            StatementKind::GenericFor(gf) => { write!(f, "{}", gf) },
+            StatementKind::Break => { write!(f, "Break") },
+            StatementKind::Goto(label) => { write!(f, "Goto [ {label} ]") },
+            StatementKind::Label(label) => { write!(f, "Label [ {label} ]") },
         }
     }
 }
 
 impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Statement {
+        Statement { kind, span }
+    }
+
+    pub fn kind(&self) -> &StatementKind {
+        &self.kind
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
     pub fn print_tree(&self, depth: usize) {
         //let tabs = "\t".repeat(depth);
-        match self {
-            Statement::Assignment(a) => { a.print_tree(depth) },
-            Statement::Conditional(cond) => { cond.print_tree(depth) },
-            Statement::FunctionDef(fdef) => { fdef.print_tree(depth) },
-            Statement::FunctionCall(fcall) => { fcall.print_tree(depth) },
-            Statement::MethodDef(mdef) => { mdef.print_tree(depth); }
-            Statement::MethodCall(mcall) => { mcall.print_tree(depth) },
-            Statement::Return(r) => { r.print_tree(depth) },
-            Statement::Do(d) => {
+        match &self.kind {
+            StatementKind::Assignment(a) => { a.print_tree(depth) },
+            StatementKind::Local(l) => { l.print_tree(depth) },
+            StatementKind::Conditional(cond) => { cond.print_tree(depth) },
+            StatementKind::FunctionDef(fdef) => { fdef.print_tree(depth) },
+            StatementKind::FunctionCall(fcall) => { fcall.print_tree(depth) },
+            StatementKind::MethodDef(mdef) => { mdef.print_tree(depth); }
+            StatementKind::MethodCall(mcall) => { mcall.print_tree(depth) },
+            StatementKind::Return(r) => { r.print_tree(depth) },
+            StatementKind::Do(d) => {
                 let tabs = "\t".repeat(depth);
                 println!("{tabs}Do [");
                 d.print_tree(depth + 1);
                 println!("{tabs}]")
             },
-            Statement::TableAssign(t) => { t.print_tree(depth); }
+            StatementKind::While(w) => { w.print_tree(depth) },
+            StatementKind::Repeat(r) => { r.print_tree(depth) },
+            StatementKind::NumericFor(nf) => { nf.print_tree(depth) },
+            StatementKind::GenericFor(gf) => { gf.print_tree(depth) },
+            StatementKind::Break | StatementKind::Goto(_) | StatementKind::Label(_) => {
+                let tabs = "\t".repeat(depth);
+                println!("{tabs}{self}");
+            },
         }
     }
 
-    pub fn walk(&self, ctx: &mut Ctx) {
-        match self {
-            Statement::Assignment(a) => {
-                // FIXME: WE ARENT HANDLING LOCALS!
+    /// Run the statement, returning how execution continues after it
+    /// Errors raised by the statement itself, rather than by a statement it contains, get its position
+    pub fn walk(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        self.walk_kind(ctx).map_err(|err| err.locate(&self.span))
+    }
 
+    fn walk_kind(&self, ctx: &mut Ctx) -> Result<ControlFlow, LuaError> {
+        match &self.kind {
+            StatementKind::Assignment(a) => a.walk(ctx)?,
+            StatementKind::Local(l) => {
                 // evaulate expressions even if unused
-                let mut values = flatten_values(a.exps.iter().map(|e| e.eval(ctx)).collect::<>()).into_iter();
-                for ident in &a.idents {
-                    ctx.new_global(ident.clone(), values.next().unwrap_or(Value::Nil));
+                let mut values = eval_explist(&l.exps, ctx)?.into_iter();
+                for (name, attrib) in &l.names {
+                    let val = values.next().unwrap_or(Value::Nil);
+                    if *attrib == Some(Attrib::Close) {
+                        ctx.new_close_local(name.clone(), val)?;
+                    } else {
+                        ctx.new_local(name.clone(), val);
+                    }
                 }
             },
-            Statement::Conditional(c) => {
+            StatementKind::Conditional(c) => {
                 for (exp, block) in &c.cases {
-                    let res = exp.eval(ctx).as_bool();
+                    let res = exp.eval(ctx)?.as_bool();
                     if res {
-                        if let Some(block) = block {
-                            block.walk(ctx);
-                        }
-                        return;
+                        return match block {
+                            Some(block) => block.walk(ctx),
+                            None => Ok(ControlFlow::Normal),
+                        };
                     }
                 }
                 if let Some(block) = &c.fallback {
-                    block.walk(ctx);
+                    return block.walk(ctx);
                 }
             },
-            Statement::FunctionDef(fdef) => {
-                // FIXME: THIS TREATS ALL FUNCTIONS AS GLOBALS
-                ctx.new_global(fdef.name.clone(), Value::Function(Rc::new(Function::LuaFunction(fdef.func.clone()))));
-            },
-            Statement::FunctionCall(fcall) => {
-                fcall.call(ctx);
+            StatementKind::FunctionDef(fdef) => {
+                // a local function is in scope in its own body, so it can call itself
+                if fdef.local {
+                    ctx.new_local(fdef.name.name.clone(), Value::Nil);
+                }
+                let closure = Closure::new(fdef.func.clone(), ctx.env());
+                fdef.name.assign(Value::Function(Rc::new(Function::Closure(closure))), ctx)?;
             },
-            Statement::Return(r) => {
-                // FIXME
-                let rv = r.vals.iter().map(|exp| exp.eval(ctx)).collect();
-                ctx.ret(rv);
-            }
-            Statement::MethodDef(mdef) => {
-                todo!()
-            }
-            Statement::MethodCall(mcall) => {
-                mcall.call(ctx);
+            StatementKind::FunctionCall(fcall) => {
+                fcall.call(ctx)?;
             },
-            Statement::Do(block) => {
-                block.walk(ctx);
+            StatementKind::Return(r) => {
+                // `return f(...)` leaves the function before the call, unless `<close>` locals have to be closed after it
+                if let [exp] = r.vals.as_slice() && ctx.can_tail_call() {
+                    let call = match exp.kind() {
+                        ExpressionKind::FuncCall(fcall) => Some(fcall.prepare(ctx)),
+                        ExpressionKind::MethodCall(mcall) => Some(mcall.prepare(ctx)),
+                        _ => None,
+                    };
+                    if let Some(call) = call {
+                        ctx.set_position(exp.span());
+                        return Ok(ControlFlow::TailCall(Box::new(call.map_err(|err| err.locate(exp.span()))?)));
+                    }
+                }
+                return Ok(ControlFlow::Return(eval_explist(&r.vals, ctx)?));
             },
-            Statement::TableAssign(tassign) => {
-                tassign.walk(ctx);
+            StatementKind::MethodDef(mdef) => {
+                let obj = mdef.obj.eval(ctx)?;
+                let closure = Closure::new(mdef.func.clone(), ctx.env());
+                set_index(&obj, &field_key(&mdef.method), Value::Function(Rc::new(Function::Closure(closure))), ctx)?;
             }
+            StatementKind::MethodCall(mcall) => {
+                mcall.call(ctx)?;
+            },
+            StatementKind::Do(block) => return block.walk(ctx),
+            StatementKind::While(w) => return w.walk(ctx),
+            StatementKind::Repeat(r) => return r.walk(ctx),
+            StatementKind::NumericFor(nf) => return nf.walk(ctx),
+            StatementKind::GenericFor(gf) => return gf.walk(ctx),
+            StatementKind::Break => return Ok(ControlFlow::Break),
+            StatementKind::Goto(label) => return Ok(ControlFlow::Goto(label.clone())),
+            // labels are found by the block containing them
+            StatementKind::Label(_) => {},
         }
+        Ok(ControlFlow::Normal)
     }
 }
 
-pub fn parse_statement(lex: &mut Lexer) -> Option<Statement> {
-    //println!("Parse statement");
-    // parse assignment
-    let dup_lex = lex.clone();
+/// Parse the next statement, returning `None` if no statement starts here
+pub fn parse_statement(lex: &mut Parser) -> Result<Option<Statement>, ParseError> {
+    // skip empty statements
+    while lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Semicolon)) {
+        lex.next();
+    }
+    let start = lex.peek_span();
+    Ok(parse_statement_kind(lex)?.map(|kind| Statement::new(kind, start.to(lex.last_span()))))
+}
 
-    if let Some(tassign) = TableAssign::parse(lex) {
-        return Some(Statement::TableAssign(tassign));
+/// Parse a function's parameter list and body, up to and including the closing `end`
+pub fn parse_funcbody(lex: &mut Parser, mut args: Vec<Identifier>, start: &Span) -> Result<LuaFunction, ParseError> {
+    if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::OpenParen)) {
+        return Err(ParseError::expected("'('", lex));
     }
-    *lex = dup_lex;
+    lex.next();
+    let (mut params, is_vararg) = parse_params(lex)?;
+    args.append(&mut params);
+    let outer_vararg = lex.set_vararg(is_vararg);
+    let code = parse_block_with_locals(lex, &args);
+    lex.set_vararg(outer_vararg);
+    let code = code?;
+    expect_end(lex, "'function'", start)?;
+    check_jumps(&code)?;
+    Ok(LuaFunction { args, is_vararg, code: Some(code), span: start.clone() })
+}
 
-    if let Some(Lexeme::Keyword(lexer::keyword::Keyword::Local)) = lex.next() {
-        if let Some(Statement::Assignment(mut assign)) = parse_statement(lex) {
-            assign.local = true;
-            return Some(Statement::Assignment(assign));
-        } else { 
-            while let Some(l) = lex.next() {
-                eprintln!("Remaining lexeme: {l:?}");
+/// Parse a parameter list up to and including the closing `)`
+/// Returns the parameter names, and whether the list ends with `...`
+fn parse_params(lex: &mut Parser) -> Result<(Vec<Identifier>, bool), ParseError> {
+    let mut params = Vec::new();
+    let mut is_vararg = false;
+    if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::CloseParen)) {
+        loop {
+            if lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Dots)) {
+                lex.next();
+                is_vararg = true;
+                break;
             }
-            panic!("Local without following assignment") 
+            params.push(expect_name(lex)?);
+            if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::Comma)) { break; }
+            lex.next();
         }
     }
+    if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::CloseParen)) {
+        return Err(ParseError::expected("')'", lex));
+    }
+    lex.next();
+    Ok((params, is_vararg))
+}
 
-    *lex = dup_lex;
-    if let Some(Lexeme::Identifier(i)) = lex.next() 
-    {
-        // try to parse more idents
-        let mut idents = vec![i];
-        let mut failed = false;
-        while let Some(lx) = lex.next() && !matches!(lx, Lexeme::Assignment(_)) {
-            if !matches!(lx, Lexeme::Seperator(seperator::Seperator::Comma)) {
-                failed = true;
-                break;
+/// Consume the `end` closing the construct `opener` that started at `start`
+fn expect_end(lex: &mut Parser, opener: &str, start: &Span) -> Result<(), ParseError> {
+    if lex.peek_lexeme() == Some(Lexeme::Keyword(Keyword::End)) {
+        lex.next();
+        Ok(())
+    } else { Err(ParseError::expected_closing("'end'", opener, start, lex)) }
+}
+
+fn parse_statement_kind(lex: &mut Parser) -> Result<Option<StatementKind>, ParseError> {
+    let start = lex.peek_span();
+    let kind = match lex.peek_lexeme() {
+        Some(Lexeme::Keyword(Keyword::Local)) if lex.peek_nth_lexeme(1) == Some(Lexeme::Keyword(Keyword::Function)) => {
+            lex.next();
+            lex.next();
+            let name = FuncName { name: expect_name(lex)?, fields: Vec::new() };
+            // the function can refer to itself
+            lex.declare_local(name.name.clone(), false);
+            let func = parse_funcbody(lex, Vec::new(), &start)?;
+            StatementKind::FunctionDef(FunctionDef { name, func: Rc::new(func), local: true })
+        },
+        Some(Lexeme::Keyword(Keyword::Local)) => {
+            lex.next();
+            let names = parse_attnamelist(lex)?;
+            let exps = if lex.peek_lexeme() == Some(Lexeme::Assignment(lexer::assignment::Assignment {})) {
+                lex.next();
+                parse_explist(lex)?
+            } else { Vec::new() };
+            // the locals are only in scope after the declaration
+            for (name, attrib) in &names {
+                lex.declare_local(name.clone(), attrib.is_some());
             }
-            if let Some(Lexeme::Identifier(ident)) = lex.next() {
-                idents.push(ident);
-            } else { failed = true; break; }
-        }
-        if !failed { 
-            let mut exps = Vec::new();
-            if let Some(first_e) = parse_expression(lex) {
-                // assignments have to have at least one expression
-                exps.push(first_e);
-                while lex.clone().next() == Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
-                    lex.next();
-                    exps.push(parse_expression(lex).unwrap());
+            StatementKind::Local(LocalAssignment { names, exps })
+        },
+        Some(Lexeme::Keyword(Keyword::If)) => {
+            lex.next();
+            let mut cases = Vec::new();
+            let mut fallback = None;
+            let test = expect_expression(lex)?;
+            expect_then(lex)?;
+            let code = Block::parse(lex)?;
+            cases.push((test, Some(code)));
+            loop {
+                match lex.peek_lexeme() {
+                    Some(Lexeme::Keyword(Keyword::Elseif)) => {
+                        lex.next();
+                        let new_test = expect_expression(lex)?;
+                        expect_then(lex)?;
+                        let new_code = Block::parse(lex)?;
+                        cases.push((new_test, Some(new_code)));
+                    },
+                    Some(Lexeme::Keyword(Keyword::Else)) => {
+                        lex.next();
+                        fallback = Some(Block::parse(lex)?);
+                        expect_end(lex, "'if'", &start)?;
+                        break
+                    },
+                    _ => {
+                        expect_end(lex, "'if'", &start)?;
+                        break
+                    }
                 }
-                //println!("parsed assignment!");
-                return Some(Statement::Assignment(Assignment {idents, exps, local: false}));
             }
-        }
-    }
-    *lex = dup_lex;
-    // parse if
-    if let Some(Lexeme::Keyword(lexer::keyword::Keyword::If)) = lex.next() {
-        let mut cases = Vec::new();
-        let mut fallback = None;
-        let test = parse_expression(lex).unwrap();
-        //println!("parsed if test");
-        let then_kw = lex.next();
-        let code = Block::parse(lex);
-        cases.push((test, code));
-        // FIXME: shitty stupid hack
-        let mut hit_end = false;
-        loop {
-            match lex.next() {
-                Some(Lexeme::Keyword(lexer::keyword::Keyword::Elseif)) => {
-                    let new_test = parse_expression(lex).unwrap();
-                    let then_kw = lex.next();
-                    let new_code = Block::parse(lex);
-                    cases.push((new_test, new_code));
+            StatementKind::Conditional(Conditional { cases, fallback })
+        },
+        Some(Lexeme::Keyword(Keyword::Function)) => {
+            lex.next();
+            let name = FuncName::parse(lex)?;
+
+            // parse colondef
+            if lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Colon)) {
+                lex.next();
+                let method = expect_name(lex)?;
+                let func = parse_funcbody(lex, vec![Identifier::from("self")], &start)?;
+                return Ok(Some(StatementKind::MethodDef(MethodDef { obj: name, method, func: Rc::new(func) })));
+            }
+
+            if name.fields.is_empty() && lex.is_read_only(&name.name) {
+                return Err(const_assign_error(&name.name, lex.last_span().clone()));
+            }
+            let func = parse_funcbody(lex, Vec::new(), &start)?;
+            StatementKind::FunctionDef(FunctionDef { name, func: Rc::new(func), local: false })
+        },
+        Some(Lexeme::Keyword(Keyword::Return)) => {
+            lex.next();
+            let vals = if parser::is_block_end(lex.peek_lexeme()) 
+                || lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Semicolon)) 
+            {
+                Vec::new()
+            } else { parse_explist(lex)? };
+            if lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Semicolon)) {
+                lex.next();
+            }
+            StatementKind::Return(Return { vals })
+        },
+        Some(Lexeme::Keyword(Keyword::Do)) => {
+            lex.next();
+            let b = Block::parse(lex)?;
+            expect_end(lex, "'do'", &start)?;
+            StatementKind::Do(b)
+        },
+        Some(Lexeme::Keyword(Keyword::While)) => {
+            lex.next();
+            let test = expect_expression(lex)?;
+            expect_do(lex)?;
+            let code = Block::parse(lex)?;
+            expect_end(lex, "'while'", &start)?;
+            StatementKind::While(WhileStatement { test, code })
+        },
+        Some(Lexeme::Keyword(Keyword::For)) => {
+            lex.next();
+            let name = expect_name(lex)?;
+            match lex.peek_lexeme() {
+                Some(Lexeme::Assignment(_)) => {
+                    lex.next();
+                    let start_exp = expect_expression(lex)?;
+                    if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
+                        return Err(ParseError::expected("','", lex));
+                    }
+                    lex.next();
+                    let limit = expect_expression(lex)?;
+                    let step = if lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
+                        lex.next();
+                        Some(expect_expression(lex)?)
+                    } else { None };
+                    expect_do(lex)?;
+                    let code = parse_block_with_locals(lex, std::slice::from_ref(&name))?;
+                    expect_end(lex, "'for'", &start)?;
+                    StatementKind::NumericFor(Box::new(NumericFor { var: name, start: start_exp, limit, step, code }))
                 },
-                Some(Lexeme::Keyword(lexer::keyword::Keyword::Else)) => {
-                    //eprintln!("parsing else");
-                    let new_code = Block::parse(lex);
-                    fallback = new_code;
-                    //eprintln!("parsed else");
-                    break
+                Some(Lexeme::Seperator(seperator::Seperator::Comma) | Lexeme::Keyword(Keyword::In)) => {
+                    let mut names = vec![name];
+                    if lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
+                        lex.next();
+                        names.append(&mut parse_namelist(lex)?);
+                    }
+                    if lex.peek_lexeme() != Some(Lexeme::Keyword(Keyword::In)) {
+                        return Err(ParseError::expected("'in'", lex));
+                    }
+                    lex.next();
+                    let exps = parse_explist(lex)?;
+                    expect_do(lex)?;
+                    let code = parse_block_with_locals(lex, &names)?;
+                    expect_end(lex, "'for'", &start)?;
+                    StatementKind::GenericFor(GenericFor { names, exps, code })
                 },
+                _ => return Err(ParseError::expected("'=' or 'in'", lex)),
+            }
+        },
+        Some(Lexeme::Keyword(Keyword::Repeat)) => {
+            lex.next();
+            let code = Block::parse(lex)?;
+            if lex.peek_lexeme() != Some(Lexeme::Keyword(Keyword::Until)) {
+                return Err(ParseError::expected_closing("'until'", "'repeat'", &start, lex));
+            }
+            lex.next();
+            let test = expect_expression(lex)?;
+            StatementKind::Repeat(RepeatStatement { code, test })
+        },
+        Some(Lexeme::Keyword(Keyword::Break)) => {
+            lex.next();
+            StatementKind::Break
+        },
+        Some(Lexeme::Keyword(Keyword::Goto)) => {
+            lex.next();
+            StatementKind::Goto(expect_name(lex)?)
+        },
+        Some(Lexeme::Seperator(seperator::Seperator::DoubleColon)) => {
+            lex.next();
+            let label = expect_name(lex)?;
+            if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::DoubleColon)) {
+                return Err(ParseError::expected("'::'", lex));
+            }
+            lex.next();
+            StatementKind::Label(label)
+        },
+        Some(Lexeme::Identifier(_) | Lexeme::Seperator(seperator::Seperator::OpenParen)) => parse_prefix_statement(lex)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(kind))
+}
 
-                Some(Lexeme::Keyword(lexer::keyword::Keyword::End)) => {
-                    hit_end = true;
-                    break
-                },
-                other => {
-                    panic!("syntax error parsing if statement, then kw was {:?}, next was {:?}", then_kw, other);
-                }
+/// Parse a statement starting with a prefix expression: an assignment, or a function or method call
+fn parse_prefix_statement(lex: &mut Parser) -> Result<StatementKind, ParseError> {
+    let Some(exp) = parse_prefix_expression(lex)? else {
+        return Err(ParseError::syntax_error(lex));
+    };
+    let is_assign = matches!(lex.peek_lexeme(), Some(Lexeme::Seperator(seperator::Seperator::Comma) | Lexeme::Assignment(_)));
+    if !is_assign {
+        return match exp.into_kind() {
+            ExpressionKind::FuncCall(fcall) => Ok(StatementKind::FunctionCall(fcall)),
+            ExpressionKind::MethodCall(mcall) => Ok(StatementKind::MethodCall(mcall)),
+            // any other expression can't be used as a statement
+            _ => Err(ParseError::syntax_error(lex)),
+        };
+    }
+
+    let mut targets = vec![AssignTarget::new(exp, lex)?];
+    while lex.peek_lexeme() == Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
+        lex.next();
+        let exp = parse_prefix_expression(lex)?.ok_or_else(|| ParseError::syntax_error(lex))?;
+        targets.push(AssignTarget::new(exp, lex)?);
+    }
+    if lex.peek_lexeme() != Some(Lexeme::Assignment(lexer::assignment::Assignment {})) {
+        return Err(ParseError::expected("'='", lex));
+    }
+    lex.next();
+    // assignments have to have at least one expression
+    let exps = parse_explist(lex)?;
+    Ok(StatementKind::Assignment(Assignment { targets, exps }))
+}
+
+/// Parse a block in which `locals` are in scope, as loop variables and parameters are
+fn parse_block_with_locals(lex: &mut Parser, locals: &[Identifier]) -> Result<Block, ParseError> {
+    let scope = lex.scope_len();
+    for local in locals {
+        lex.declare_local(local.clone(), false);
+    }
+    let block = Block::parse(lex);
+    lex.end_scope(scope);
+    block
+}
+
+/// The error for assigning to a `<const>` or `<close>` local
+fn const_assign_error(name: &Identifier, span: Span) -> ParseError {
+    ParseError::semantic(format!("attempt to assign to const variable '{}'", name.0), span)
+}
+
+/// Parse the names of a local declaration, each of which can have an attribute
+fn parse_attnamelist(lex: &mut Parser) -> Result<Vec<(Identifier, Option<Attrib>)>, ParseError> {
+    let mut names = Vec::new();
+    loop {
+        let name = expect_name(lex)?;
+        let attrib = if lex.peek_lexeme() == Some(Lexeme::AngleBrackets(lexer::AngleBrackets::Open)) {
+            lex.next();
+            let attrib = expect_name(lex)?;
+            if lex.peek_lexeme() != Some(Lexeme::AngleBrackets(lexer::AngleBrackets::Close)) {
+                return Err(ParseError::expected("'>'", lex));
             }
-        }
-        if !hit_end { lex.next();  }
-        return Some(Statement::Conditional(Conditional { cases, fallback }))
-    }                                                                                                                                                                                                                               
-    *lex = dup_lex;
-    // parse functioncall
-    if let Some(fcall) = FunctionCall::parse(lex)
-    {
-        return Some(Statement::FunctionCall(fcall));
-    }
-    *lex = dup_lex;
-    // parse method call
-    if let Some(Expression::MethodCall(mcall)) = parse_expression(lex) 
-    {
-        return Some(Statement::MethodCall(mcall));
-    }
-    *lex = dup_lex;
-    // parse functiondef                                                                                                        
-    if let Some(Lexeme::Keyword(lexer::keyword::Keyword::Function)) = lex.next() 
-        && let Some(Lexeme::Identifier(name)) = lex.next()
-        && lex.next() == Some(Lexeme::Seperator(seperator::Seperator::OpenParen))
-    {
-        let args = parse_paren_list(lex, |l| 
-            if let Some(Lexeme::Identifier(ident)) = l.next() {
-                Some(ident)
-            } else { None }
-        ).unwrap();                                                                                                                                                                                                                                               
-        let code = Block::parse(lex);
-        let end_kw = lex.next();
-        let fdef = Statement::FunctionDef(FunctionDef { name, func: LuaFunction { args, code } });
-        assert_eq!(end_kw, Some(Lexeme::Keyword(lexer::keyword::Keyword::End)));
-
-        //println!("parsed function def!");
-        return Some(fdef);
-    }
-    *lex = dup_lex;
-
-    // parse colondef
-    if let Some(Lexeme::Keyword(lexer::keyword::Keyword::Function)) = lex.next() 
-        && let Some(Lexeme::Identifier(obj)) = lex.next()
-        && lex.next() == Some(Lexeme::Seperator(seperator::Seperator::Colon))
-        && let Some(Lexeme::Identifier(method)) = lex.next()
-    {
-        let _oparen = lex.next();
-
-        let mut args = vec![Identifier("self".to_string())];
-        args.append(&mut parse_paren_list(lex, |l| 
-            if let Some(Lexeme::Identifier(ident)) = l.next() {
-                Some(ident)
-            } else { None }
-        ).unwrap());
-        let code = Block::parse(lex);
-        let end_kw = lex.next();
-        let fdef = Statement::MethodDef(MethodDef { obj, method, func: LuaFunction { args, code } });
-        assert_eq!(end_kw, Some(Lexeme::Keyword(lexer::keyword::Keyword::End)));
-        return Some(fdef);
-    }
-    *lex = dup_lex;
-
-    // parse return
-    if let Some(Lexeme::Keyword(lexer::keyword::Keyword::Return)) = lex.next() {
-
-        let mut vals = Vec::new();
-        if let Some(exp) = parse_expression(lex) {
-            vals.push(exp);
-            while lex.clone().next() == Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
-                lex.next();
-                vals.push(parse_expression(lex).unwrap());
+            lex.next();
+            match attrib.0.as_str() {
+                "const" => Some(Attrib::Const),
+                "close" => Some(Attrib::Close),
+                _ => return Err(ParseError::semantic(format!("unknown attribute '{}'", attrib.0), lex.last_span().clone())),
             }
+        } else { None };
+        if attrib == Some(Attrib::Close) && names.iter().any(|(_, a)| *a == Some(Attrib::Close)) {
+            return Err(ParseError::semantic("multiple to-be-closed variables in local list", lex.last_span().clone()));
         }
-        return Some(Statement::Return(Return { vals }));
+        names.push((name, attrib));
+        if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
+            return Ok(names);
+        }
+        lex.next();
     }
-    *lex = dup_lex;
-    
-    if let Some(Lexeme::Keyword(lexer::keyword::Keyword::Do)) = lex.next() {
-        let b = Block::parse(lex).expect("valid block should follow do statement");
-        let _ = lex.next(); // get rid of 'end'
-        return Some(Statement::Do(b));
+}
+
+/// Consume a name, which has to be the next lexeme
+fn expect_name(lex: &mut Parser) -> Result<Identifier, ParseError> {
+    match lex.peek_lexeme() {
+        Some(Lexeme::Identifier(name)) => {
+            lex.next();
+            Ok(Identifier::from(name))
+        },
+        _ => Err(ParseError::expected("<name>", lex)),
     }
+}
 
-    *lex = dup_lex;
-    None
-}
\ No newline at end of file
+fn expect_then(lex: &mut Parser) -> Result<(), ParseError> {
+    if lex.peek_lexeme() == Some(Lexeme::Keyword(Keyword::Then)) {
+        lex.next();
+        Ok(())
+    } else { Err(ParseError::expected("'then'", lex)) }
+}
+
+fn expect_do(lex: &mut Parser) -> Result<(), ParseError> {
+    if lex.peek_lexeme() == Some(Lexeme::Keyword(Keyword::Do)) {
+        lex.next();
+        Ok(())
+    } else { Err(ParseError::expected("'do'", lex)) }
+}
+
+fn parse_namelist(lex: &mut Parser) -> Result<Vec<Identifier>, ParseError> {
+    let mut idents = Vec::new();
+    loop {
+        idents.push(expect_name(lex)?);
+        if lex.peek_lexeme() != Some(Lexeme::Seperator(seperator::Seperator::Comma)) {
+            return Ok(idents);
+        }
+        lex.next();
+    }
+}
+
+/// A `goto` or `break` that hasn't found its target yet
+struct PendingJump {
+    /// The label jumped to, or `None` for a `break`
+    label: Option<Identifier>,
+    span: Span,
+}
+
+/// How a nested block is entered, for checking the jumps inside it
+#[derive(Clone, Copy, PartialEq)]
+enum NestedBlock {
+    Plain,
+    Loop,
+    /// A `repeat` body, whose locals stay in scope in the `until` condition
+    Repeat,
+}
+
+impl Statement {
+    /// The blocks nested in this statement, along with what kind of block they are
+    /// Function bodies aren't included, since jumps can't leave a function
+    fn nested_blocks(&self) -> Vec<(&Block, NestedBlock)> {
+        match &self.kind {
+            StatementKind::Do(b) => vec![(b, NestedBlock::Plain)],
+            StatementKind::Conditional(c) => c.cases.iter()
+                .filter_map(|(_, b)| b.as_ref())
+                .chain(&c.fallback)
+                .map(|b| (b, NestedBlock::Plain))
+                .collect(),
+            StatementKind::While(w) => vec![(&w.code, NestedBlock::Loop)],
+            StatementKind::Repeat(r) => vec![(&r.code, NestedBlock::Repeat)],
+            StatementKind::NumericFor(nf) => vec![(&nf.code, NestedBlock::Loop)],
+            StatementKind::GenericFor(gf) => vec![(&gf.code, NestedBlock::Loop)],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The local variable this statement declares, if it declares any
+    fn declared_local(&self) -> Option<&Identifier> {
+        match &self.kind {
+            StatementKind::Local(l) => l.names.first().map(|(name, _)| name),
+            StatementKind::FunctionDef(fdef) if fdef.local => Some(&fdef.name.name),
+            _ => None,
+        }
+    }
+}
+
+/// Check that every `goto` and `break` in a function body has a target it's allowed to jump to
+pub fn check_jumps(body: &Block) -> Result<(), ParseError> {
+    match check_block_jumps(body, NestedBlock::Plain, &mut Vec::new())?.into_iter().next() {
+        None => Ok(()),
+        Some(PendingJump { label: None, span }) => {
+            Err(ParseError::semantic(format!("break outside a loop at line {}", span.line()), span))
+        },
+        Some(PendingJump { label: Some(label), span }) => {
+            Err(ParseError::semantic(format!("no visible label '{}' for <goto> at line {}", label.0, span.line()), span))
+        },
+    }
+}
+
+/// Resolve the jumps in `block` to its labels, returning the jumps that leave the block
+/// `visible` holds the labels defined so far in the enclosing blocks
+fn check_block_jumps(block: &Block, kind: NestedBlock, visible: &mut Vec<(Identifier, Span)>) -> Result<Vec<PendingJump>, ParseError> {
+    let outer_labels = visible.len();
+    let mut labels = Vec::new();
+    // jumps, with the index of the statement they leave this block from
+    let mut pending = Vec::new();
+    for (i, st) in block.statements.iter().enumerate() {
+        match st.kind() {
+            StatementKind::Label(label) => {
+                if let Some((_, defined)) = visible.iter().find(|(l, _)| l == label) {
+                    let message = format!("label '{}' already defined on line {}", label.0, defined.line());
+                    return Err(ParseError::semantic(message, st.span.clone()));
+                }
+                visible.push((label.clone(), st.span.clone()));
+                labels.push((label, i));
+            },
+            StatementKind::Goto(label) => pending.push((PendingJump { label: Some(label.clone()), span: st.span.clone() }, i)),
+            StatementKind::Break => pending.push((PendingJump { label: None, span: st.span.clone() }, i)),
+            _ => for (inner, inner_kind) in st.nested_blocks() {
+                for jump in check_block_jumps(inner, inner_kind, visible)? {
+                    // loops are the target of the breaks inside them
+                    if !(inner_kind != NestedBlock::Plain && jump.label.is_none()) {
+                        pending.push((jump, i));
+                    }
+                }
+            },
+        }
+    }
+    visible.truncate(outer_labels);
+
+    let mut unresolved = Vec::new();
+    for (jump, from) in pending {
+        let target = jump.label.as_ref().and_then(|name| labels.iter().find(|(l, _)| *l == name));
+        match target {
+            Some(&(label, to)) if to > from => {
+                // a label at the end of the block is outside the scope of the block's locals,
+                // unless the block is a repeat body, where they're still visible to the condition
+                let at_end = kind != NestedBlock::Repeat && block.statements[to + 1..].iter().all(|st| matches!(st.kind(), StatementKind::Label(_)));
+                let skipped_local = block.statements[from + 1..to].iter().find_map(Statement::declared_local);
+                if let Some(local) = skipped_local && !at_end {
+                    let message = format!("<goto {}> at line {} jumps into the scope of local '{}'", label.0, jump.span.line(), local.0);
+                    return Err(ParseError::semantic(message, jump.span));
+                }
+            },
+            // jumping backwards never enters a local's scope
+            Some(_) => {},
+            None => unresolved.push(jump),
+        }
+    }
+    Ok(unresolved)
+}
@@ -0,0 +1,579 @@
+// test evaluation of parsed code
+
+use crate::{ast::context::Ctx, builtins::prelude, parser::parse, value::{table::raw_get, Value}};
+
+/// Run the chunk `src`, and return the value of the global `name` afterwards
+fn run(src: &str, name: &str) -> Value {
+    let block = parse(src, "test.lua").unwrap_or_else(|errs| panic!("{src} failed to parse: {errs:?}"));
+    let mut ctx = Ctx::new();
+    prelude(&mut ctx);
+    if let Err(err) = block.walk(&mut ctx) {
+        panic!("{src} raised an error: {err}");
+    }
+    raw_get(&ctx.globals(), &string(name))
+}
+
+/// Run the chunk `src`, which has to raise an error, and return the error message
+fn error(src: &str) -> String {
+    let block = parse(src, "test.lua").unwrap_or_else(|errs| panic!("{src} failed to parse: {errs:?}"));
+    let mut ctx = Ctx::new();
+    prelude(&mut ctx);
+    match block.walk(&mut ctx) {
+        Ok(_) => panic!("{src} didn't raise an error"),
+        Err(err) => err.to_string(),
+    }
+}
+
+/// Evaluate the expression `exp` by assigning it to a global
+fn eval(exp: &str) -> Value {
+    run(&format!("result = {exp}"), "result")
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.into())
+}
+
+#[test]
+fn arithmetic_precedence() {
+    assert_eq!(eval("2 - 3 - 4"), Value::Integer(-5));
+    assert_eq!(eval("1 + 2 * 3"), Value::Integer(7));
+    assert_eq!(eval("(1 + 2) * 3"), Value::Integer(9));
+    assert_eq!(eval("7 // 2 % 2 * 5"), Value::Integer(5));
+    assert_eq!(eval("2 ^ 3 ^ 2"), Value::Float(512.0));
+    assert_eq!(eval("-2 ^ 2"), Value::Float(-4.0));
+    assert_eq!(eval("- -3"), Value::Integer(3));
+    assert_eq!(eval("2 * -3"), Value::Integer(-6));
+}
+
+#[test]
+fn concat_is_right_associative() {
+    assert_eq!(eval("1 .. 2 .. 3"), string("123"));
+    assert_eq!(eval("'a' .. 1 + 2"), string("a3"));
+}
+
+#[test]
+fn bitwise_operators() {
+    assert_eq!(eval("1 | 2 ~ 3 & 6"), Value::Integer(1));
+    assert_eq!(eval("1 << 2 + 1"), Value::Integer(8));
+    assert_eq!(eval("-1 >> 63"), Value::Integer(1));
+    assert_eq!(eval("~0"), Value::Integer(-1));
+    assert_eq!(eval("3.0 | '4'"), Value::Integer(7));
+}
+
+#[test]
+fn logical_operators() {
+    assert_eq!(eval("nil or 5"), Value::Integer(5));
+    assert_eq!(eval("false and undefined()"), Value::Boolean(false.into()));
+    assert_eq!(eval("1 or 2 and 3"), Value::Integer(1));
+    assert_eq!(eval("not nil == true"), Value::Boolean(true.into()));
+    assert_eq!(eval("1 < 2 == true"), Value::Boolean(true.into()));
+    assert_eq!(eval("#'abc' + 1"), Value::Integer(4));
+}
+
+#[test]
+fn while_loop() {
+    assert_eq!(run("i, sum = 0, 0\nwhile i < 10 do i = i + 1 sum = sum + i end", "sum"), Value::Integer(55));
+    assert_eq!(run("n = 0 while false do n = 1 end", "n"), Value::Integer(0));
+}
+
+#[test]
+fn repeat_loop() {
+    // the condition can see locals from the body
+    let src = "n = 0\nrepeat local done = n >= 3 n = n + 1 until done";
+    assert_eq!(run(src, "n"), Value::Integer(4));
+    // the body always runs at least once
+    assert_eq!(run("n = 0 repeat n = n + 1 until true", "n"), Value::Integer(1));
+}
+
+#[test]
+fn locals() {
+    let src = "x = 1\ndo local x = 2 x = x + 1 y = x end";
+    assert_eq!(run(src, "x"), Value::Integer(1));
+    assert_eq!(run(src, "y"), Value::Integer(3));
+}
+
+#[test]
+fn numeric_for() {
+    assert_eq!(run("sum = 0 for i = 1, 10 do sum = sum + i end", "sum"), Value::Integer(55));
+    assert_eq!(run("n = 0 for i = 10, 1, -3 do n = n * 10 + i end", "n"), Value::Integer(10741));
+    assert_eq!(run("n = 0 for i = 1, 0 do n = n + 1 end", "n"), Value::Integer(0));
+    // the loop variable is a fresh local, which the body can't use to change the loop
+    assert_eq!(run("n = 0 for i = 1, 3 do i = i * 10 n = n + 1 end", "n"), Value::Integer(3));
+    assert_eq!(run("for i = 1, 3 do x = i end", "i"), Value::Nil);
+}
+
+#[test]
+fn numeric_for_limits() {
+    // integer loops near the edges don't overflow
+    let src = "max = 9223372036854775807\nn = 0 for i = max - 2, max do n = n + 1 end";
+    assert_eq!(run(src, "n"), Value::Integer(3));
+    let src = "min = -9223372036854775807 - 1\nn = 0 for i = min, min + 2, 2 do n = n + 1 end";
+    assert_eq!(run(src, "n"), Value::Integer(2));
+    // float limits are floored for integer loops
+    assert_eq!(run("for i = 1, 3.9 do last = i end", "last"), Value::Integer(3));
+    assert_eq!(run("n = 0 for i = 1, 1 / 0 do n = n + 1 if n == 5 then return n end end", "n"), Value::Integer(5));
+    // any float makes a float loop
+    assert_eq!(run("for i = 1, 2, 0.5 do last = i end", "last"), Value::Float(2.0));
+}
+
+#[test]
+fn numeric_for_zero_step() {
+    assert_eq!(error("for i = 1, 10, 0 do end"), "test.lua:1: 'for' step is zero");
+}
+
+#[test]
+fn generic_for() {
+    // the iterator is called with the state and the control value until it returns nil
+    let src = "function upto(n, i) if i < n then return i + 1, i * i end end\n\
+        sum = 0 for i, sq in upto, 4, 0 do sum = sum + i * 10 + sq end";
+    assert_eq!(run(src, "sum"), Value::Integer(114));
+    let src = "n = 0 for name, f in pairs(io) do n = n + 1 end";
+    assert_eq!(run(src, "n"), Value::Integer(2));
+    assert_eq!(error("for x in nil do end"), "test.lua:1: attempt to call a nil value (for iterator 'for iterator')");
+}
+
+#[test]
+fn clear_fields_during_pairs() {
+    let src = "local t = {1, 2, 3, a = 1, b = 2, c = 3}\nn = 0\n\
+        for k in pairs(t) do t[k] = nil n = n + 1 end\nleft = next(t)";
+    assert_eq!(run(src, "n"), Value::Integer(6));
+    assert_eq!(run(src, "left"), Value::Nil);
+    // cleared fields can be set again
+    assert_eq!(run("local t = {x = 1}\nt.x = nil\nt.x = 2\nfor k, v in pairs(t) do result = v end", "result"), Value::Integer(2));
+}
+
+#[test]
+fn break_exits_innermost_loop() {
+    let src = "n = 0\nfor i = 1, 3 do\n  while true do n = n + 1 break end\n  if i == 2 then break end\nend";
+    assert_eq!(run(src, "n"), Value::Integer(2));
+    assert_eq!(run("n = 0 repeat n = n + 1 if n == 4 then break end until false", "n"), Value::Integer(4));
+}
+
+#[test]
+fn goto_continue() {
+    let src = "sum = 0\nfor i = 1, 5 do\n  local odd = i % 2 == 1\n  if odd then goto continue end\n  sum = sum + i\n  ::continue::\nend";
+    assert_eq!(run(src, "sum"), Value::Integer(6));
+}
+
+#[test]
+fn goto_backwards() {
+    let src = "n = 0\n::top::\nn = n + 1\nif n < 3 then goto top end";
+    assert_eq!(run(src, "n"), Value::Integer(3));
+    // jumping out of nested blocks and loops
+    let src = "n = 0\nwhile true do\n  do n = n + 1 if n == 5 then goto done end end\nend\n::done::";
+    assert_eq!(run(src, "n"), Value::Integer(5));
+    // each jump back declares new locals, and closures keep the ones they captured
+    let src = "local fs = {}\ndo\n  local i = 0\n  ::top::\n  local x = i\n  fs[#fs + 1] = function() return x end\n\
+        i = i + 1\n  if i < 3 then goto top end\nend\nr = fs[1]() .. fs[2]() .. fs[3]()";
+    assert_eq!(run(src, "r"), string("012"));
+}
+
+#[test]
+fn return_unwinds() {
+    // a return without values still stops the function
+    assert_eq!(run("function f() if true then return end y = 1 end\nf()", "y"), Value::Nil);
+    let src = "function f()\n  while true do\n    for i = 1, 10 do if i == 3 then return i * 2 end end\n  end\nend\nr = f()";
+    assert_eq!(run(src, "r"), Value::Integer(6));
+    // a chunk can return too
+    assert_eq!(run("x = 1 do return end x = 2", "x"), Value::Integer(1));
+}
+
+#[test]
+fn closures_capture_locals() {
+    // locals outlive the call that declared them
+    let src = "function counter()\n  local n = 0\n  function inc() n = n + 1 return n end\nend\ncounter()\ninc() inc()\nr = inc()";
+    assert_eq!(run(src, "r"), Value::Integer(3));
+    // closures from the same call share their locals, and each call has its own
+    let src = "function make()\n  local x = 0\n  function get() return x end\n  function set(v) x = v end\nend\n\
+        make() set(5) old_get = get\nmake() r = old_get() + get()";
+    assert_eq!(run(src, "r"), Value::Integer(5));
+    // each loop iteration has a fresh local
+    let src = "for i = 1, 3 do\n  function f() return i end\n  if i == 2 then g = f end\nend\nr = g()";
+    assert_eq!(run(src, "r"), Value::Integer(2));
+}
+
+#[test]
+fn lexical_scoping() {
+    // a function can't see its caller's locals
+    let src = "function f() return secret end\nfunction g() local secret = 1 return f() end\nr = g()";
+    assert_eq!(run(src, "r"), Value::Nil);
+    // or locals declared after it
+    let src = "local x = 1\nfunction f() return x end\nlocal x = 2\nr = f()";
+    assert_eq!(run(src, "r"), Value::Integer(1));
+    // function statements assign to locals in scope
+    let src = "local f = 1\nfunction f() return 2 end\nr = f()";
+    assert_eq!(run(src, "r"), Value::Integer(2));
+}
+
+#[test]
+fn anonymous_functions() {
+    assert_eq!(run("f = function(x) return x + 1 end\nr = f(2)", "r"), Value::Integer(3));
+    let src = "function counter()\n  local n = 0\n  return function() n = n + 1 return n end\nend\n\
+        c = counter() c() c()\nr = c() + counter()()";
+    assert_eq!(run(src, "r"), Value::Integer(4));
+}
+
+#[test]
+fn local_functions() {
+    // a local function can call itself
+    let src = "local function fact(n) if n <= 1 then return 1 end return n * fact(n - 1) end\nr = fact(5)";
+    assert_eq!(run(src, "r"), Value::Integer(120));
+    assert_eq!(run("do local function f() end end\nr = f", "r"), Value::Nil);
+}
+
+#[test]
+fn function_fields_and_methods() {
+    assert_eq!(run("local t = {}\nfunction t.double(x) return x * 2 end\nr = t.double(4)", "r"), Value::Integer(8));
+    let src = "local t = {}\nfunction t:me(x) return self, x end\nme, x = t:me(3)\nr = me == t and x";
+    assert_eq!(run(src, "r"), Value::Integer(3));
+}
+
+#[test]
+fn varargs() {
+    let src = "function f(a, ...) local x, y = ... return a, y end
+r1, r2 = f(1, 2, 3)";
+    assert_eq!(run(src, "r1"), Value::Integer(1));
+    assert_eq!(run(src, "r2"), Value::Integer(3));
+    // only the last expression of a list gives multiple values
+    let src = "function f(...) return ... end
+function count(...) return select('#', ...) end
+\
+        r = count(f(1, 2), f(3, 4)) .. count(f()) .. count((f(1, 2))) .. count(..., nil)";
+    assert_eq!(run(src, "r"), string("3012"));
+}
+
+#[test]
+fn multiple_values() {
+    let src = "function f() return 1, 2 end\nfunction none() end\na, b, c = f(), f()";
+    assert_eq!(run(src, "b"), Value::Integer(1));
+    assert_eq!(run(src, "c"), Value::Integer(2));
+    // a call is truncated to one value in an expression, and gives nil if it has none
+    assert_eq!(run("function f() return 1, 2 end\nr = f() + f()", "r"), Value::Integer(2));
+    assert_eq!(run("function none() end\nr = none() == nil", "r"), Value::Boolean(true.into()));
+    assert_eq!(run("function f() return 1, 2 end\na, b = (f())", "b"), Value::Nil);
+    let src = "function f() return 1, 2 end\nfor i, j in function(_, c) if not c then return f() end end do a, b = i, j end";
+    assert_eq!(run(src, "b"), Value::Integer(2));
+}
+
+#[test]
+fn select() {
+    assert_eq!(eval("select('#')"), Value::Integer(0));
+    assert_eq!(eval("select(2, 'a', 'b', 'c')"), string("b"));
+    assert_eq!(run("a, b = select(-2, 1, 2, 3)", "b"), Value::Integer(3));
+    assert_eq!(eval("select(5, 1, 2)"), Value::Nil);
+}
+
+#[test]
+fn select_out_of_range() {
+    assert_eq!(error("select(-3, 1, 2)"), "test.lua:1: bad argument #1 to 'select' (index out of range)");
+}
+
+#[test]
+fn field_assignment() {
+    assert_eq!(run("local t = {}\nt.x = 1\nt['y'] = t.x + 1\nr = t.y", "r"), Value::Integer(2));
+    // chains of field accesses and indexes
+    let src = "local t, u = {}, {}\nt.sub = u\nt.sub[1] = 'a'\nt['sub'].b = t.sub[1]\nr = u.b";
+    assert_eq!(run(src, "r"), string("a"));
+    assert_eq!(eval("({}).missing"), Value::Nil);
+    assert_eq!(eval("({})[nil]"), Value::Nil);
+    // `t[1.0]` is the same field as `t[1]`
+    assert_eq!(run("local t = {}\nt[1.0] = 'one'\nr = t[1]", "r"), string("one"));
+}
+
+#[test]
+fn multiple_assignment() {
+    // mixed locals, fields and globals, with missing values assigned nil
+    let src = "local a, b, t = 1, 2, {c = 3}\ng = 0\na, t.c, b, g = t.c, a, b .. ''\nr = a .. t.c .. b .. (g == nil and 'n' or 'x')";
+    assert_eq!(run(src, "r"), string("312n"));
+    // all values are evaluated before anything is assigned
+    assert_eq!(run("local t = {}\nt.a, t.b = 1, 2\nt.a, t.b = t.b, t.a\nr = t.a * 10 + t.b", "r"), Value::Integer(21));
+    // and the keys of the targets before the values
+    assert_eq!(run("local t = {}\ni = 1\ni, t[i] = 2, 'x'\nr = t[1]", "r"), string("x"));
+}
+
+#[test]
+fn assign_nil_key() {
+    assert_eq!(error("local t = {}\nt[nil] = 1"), "test.lua:2: index is nil");
+}
+
+#[test]
+fn call_forms() {
+    assert_eq!(run("function id(x) return x end\nr = id 'a' .. id [[b]]", "r"), string("ab"));
+    assert_eq!(run("function f() return function(x) return x * 2 end end\nr = f()(3)", "r"), Value::Integer(6));
+    assert_eq!(run("local t = {function(x) return x + 1 end}\nr = t[1](1) + (t[1])(2)", "r"), Value::Integer(5));
+    assert_eq!(eval("string.format('%d-%s', 1, 'x')"), string("1-x"));
+    // the receiver of a method call is evaluated once
+    let src = "local t = {}\nn = 0\nfunction get() n = n + 1 return t end\nfunction t:m(x) return self == t and x end\nr = get():m 'y' .. n";
+    assert_eq!(run(src, "r"), string("y1"));
+}
+
+#[test]
+fn local_attributes() {
+    assert_eq!(run("local x <const> = 2\nr = x * 3", "r"), Value::Integer(6));
+    // nil and false don't need to be closable
+    assert_eq!(run("do local a <close>, b <const> = nil end\nlocal c <close> = false\nr = 1", "r"), Value::Integer(1));
+}
+
+#[test]
+fn close_non_closable() {
+    assert_eq!(error("local x <close> = 1"), "test.lua:1: variable 'x' got a non-closable value");
+}
+
+#[test]
+fn table_constructors() {
+    let src = "t = {1, 'two'; x = 3, ['y' .. 1] = 4, 5,}\nr = t[1] .. t[2] .. t.x .. t.y1 .. t[3] .. #t";
+    assert_eq!(run(src, "r"), string("1two3453"));
+    // only the last field expands to all the values of a call
+    let src = "function f() return 1, 2, 3 end\nt = {f(), f()}\nu = {f(), f(), n = 0}\nr = #t .. #u";
+    assert_eq!(run(src, "r"), string("42"));
+    // positional fields are stored last
+    assert_eq!(run("t = {[1] = 'a', 'b', [2] = 'c', [3] = 'd'}\nr = t[1] .. t[2] .. t[3]", "r"), string("bcd"));
+    assert_eq!(eval("#{n = 1}"), Value::Integer(0));
+    assert_eq!(run("local n = 0\nt = {n, n = 5, [n + 1] = 2}\nr = t[1] + t.n", "r"), Value::Integer(5));
+}
+
+#[test]
+fn table_length() {
+    let src = "local t = {}\nfor i = 1, 100 do t[#t + 1] = i end\nr = #t .. ' '\n\
+        t[100] = nil r = r .. #t .. ' '\nt[50] = nil r = r .. #t .. ' '\nt[50] = 0 r = r .. #t\n\
+        local u = {[2] = 2, [3] = 3}\nu[1] = 1\nr = r .. ' ' .. #u";
+    assert_eq!(run(src, "r"), string("100 99 49 99 3"));
+}
+
+#[test]
+fn table_constructor_nil_key() {
+    assert_eq!(error("t = {[nil] = 1}"), "test.lua:1: index is nil");
+}
+
+#[test]
+fn metatables() {
+    let src = "local mt = {}\nt = setmetatable({}, mt)\nr = getmetatable(t) == mt and getmetatable({}) == nil";
+    assert_eq!(run(src, "r"), Value::Boolean(true.into()));
+    assert_eq!(run("t = setmetatable({}, {__metatable = 'locked'})\nr = getmetatable(t)", "r"), string("locked"));
+    assert_eq!(eval("getmetatable('').__index == string"), Value::Boolean(true.into()));
+    assert_eq!(eval("('%d'):format(7)"), string("7"));
+}
+
+#[test]
+fn protected_metatable() {
+    assert_eq!(error("t = setmetatable({}, {__metatable = false})\nsetmetatable(t, nil)"), "test.lua:2: cannot change a protected metatable");
+}
+
+#[test]
+fn index_metamethods() {
+    // __index tables are chained, and functions get the table and key
+    let src = "local base = {greet = 'hi'}\nlocal mid = setmetatable({}, {__index = base})\n\
+        t = setmetatable({}, {__index = mid})\nu = setmetatable({}, {__index = function(t, k) return k .. '!' end})\n\
+        r = t.greet .. u.x .. tostring(rawget(t, 'greet'))";
+    assert_eq!(run(src, "r"), string("hix!nil"));
+    // __newindex only applies to missing fields
+    let src = "log = ''\nt = setmetatable({a = 1}, {__newindex = function(t, k, v) log = log .. k rawset(t, k, v * 10) end})\n\
+        t.a = 2\nt.b = 3\nt.b = 4\nr = log .. t.a .. t.b";
+    assert_eq!(run(src, "r"), string("b24"));
+    let src = "local store = {}\nt = setmetatable({}, {__newindex = store})\nt.x = 1\nr = tostring(rawget(t, 'x')) .. store.x";
+    assert_eq!(run(src, "r"), string("nil1"));
+}
+
+#[test]
+fn operator_metamethods() {
+    let src = "local V = {}\nV.__index = V\n\
+        function V.new(x) return setmetatable({x = x}, V) end\n\
+        V.__add = function(a, b) return V.new(a.x + (getmetatable(b) == V and b.x or b)) end\n\
+        V.__unm = function(a) return V.new(-a.x) end\n\
+        V.__eq = function(a, b) return a.x == b.x end\n\
+        V.__lt = function(a, b) return a.x < b.x end\n\
+        V.__le = function(a, b) return a.x <= b.x end\n\
+        V.__len = function(a) return a.x end\n\
+        V.__concat = function(a, b) return 'V' .. (getmetatable(a) == V and a.x or a) .. (getmetatable(b) == V and b.x or b) end\n\
+        V.__call = function(self, y) return self.x * y end\n\
+        V.__band = function(a, b) return 'band' end\n\
+        V.__tostring = function(a) return 'V(' .. a.x .. ')' end\n\
+        local a, b = V.new(1), V.new(2)\n\
+        r = tostring(-(a + b)) .. (a + 1).x .. #b .. a(5) .. (a & 1) .. (a .. 'z') .. ('y' .. b)\n\
+        cmp = (a == V.new(1)) and (a ~= b) and (a < b) and (b > a) and (a <= a) and not (b <= a)";
+    assert_eq!(run(src, "r"), string("V(-3)225bandV1zVy2"));
+    assert_eq!(run(src, "cmp"), Value::Boolean(true.into()));
+}
+
+#[test]
+fn tostring_and_name() {
+    assert_eq!(eval("tostring(nil) .. tostring(true) .. tostring(1.5)"), string("niltrue1.5"));
+    let src = "t = setmetatable({}, {__name = 'Point'})\nr = tostring(t)";
+    let Value::String(s) = run(src, "r") else { panic!("tostring didn't give a string") };
+    assert!(s.to_str().is_some_and(|s| s.starts_with("Point: 0x")), "{s}");
+}
+
+#[test]
+fn close_metamethod() {
+    let src = "log = ''\nlocal mt = {__close = function(v, err) log = log .. v.name end}\n\
+        local function closable(name) return setmetatable({name = name}, mt) end\n\
+        function f()\n  local a <close> = closable('a')\n  local b <close> = closable('b')\n  return 'r'\nend\n\
+        local r = f()\nlog = log .. r\n\
+        for i = 1, 3 do local c <close> = closable(i) if i == 2 then break end end\n\
+        do local d <close> = closable('d') end";
+    assert_eq!(run(src, "log"), string("bar12d"));
+    // the closing value of a generic for is closed when the loop ends
+    let src = "log = ''\nlocal c = setmetatable({}, {__close = function() log = log .. 'closed' end})\n\
+        for k in next, {1}, nil, c do log = log .. k end";
+    assert_eq!(run(src, "log"), string("1closed"));
+}
+
+#[test]
+fn runtime_error_messages() {
+    assert_eq!(error("foo()"), "test.lua:1: attempt to call a nil value (global 'foo')");
+    assert_eq!(error("local t = {}\nt.x.y = 1"), "test.lua:2: attempt to index a nil value (field 'x')");
+    assert_eq!(error("local n = 1\nr = n.x"), "test.lua:2: attempt to index a number value (local 'n')");
+    assert_eq!(error("local t\nfunction f() return t[1] end\nf()"), "test.lua:2: attempt to index a nil value (upvalue 't')");
+    assert_eq!(error("r = {} + 1"), "test.lua:1: attempt to perform arithmetic on a table value");
+    assert_eq!(error("r = 1 // 0"), "test.lua:1: attempt to perform 'n//0'");
+    assert_eq!(error("r = 1 % 0"), "test.lua:1: attempt to perform 'n%0'");
+    assert_eq!(error("r = 1 < 'x'"), "test.lua:1: attempt to compare number with string");
+    assert_eq!(error("function t.f() end"), "test.lua:1: attempt to index a nil value (global 't')");
+    assert_eq!(error("local t = {}\nfunction t.a.b:m() end"), "test.lua:2: attempt to index a nil value (field 'a')");
+    assert_eq!(error("string.nope()"), "test.lua:1: attempt to call a nil value (field 'nope')");
+    assert_eq!(error("error({})"), "(error object is a table value)");
+}
+
+#[test]
+fn error_and_pcall() {
+    assert_eq!(eval("select(2, pcall(function(a, b) return a + b end, 1, 2))"), Value::Integer(3));
+    assert_eq!(run("ok, r = pcall(function() error('x') end)", "r"), string("test.lua:1: x"));
+    assert_eq!(run("ok, r = pcall(error, 'x', 0)", "ok"), Value::Boolean(false.into()));
+    assert_eq!(run("ok, r = pcall(error, 'x', 0)", "r"), string("x"));
+    // level 2 blames the caller of the function raising the error
+    let src = "local function check(n) if n < 0 then error('negative', 2) end end\nok, r = pcall(function()\n  check(-1)\nend)";
+    assert_eq!(run(src, "r"), string("test.lua:3: negative"));
+    // other error objects are raised as they are
+    assert_eq!(run("ok, e = pcall(error, {code = 42})\nr = e.code", "r"), Value::Integer(42));
+    assert_eq!(run("ok, r = pcall(nil)", "r"), string("attempt to call a nil value"));
+    // locals are still in scope after an error is caught
+    assert_eq!(run("local a = 1\npcall(function() local b <const> = 2 error('x') end)\nr = a", "r"), Value::Integer(1));
+}
+
+#[test]
+fn xpcall_and_assert() {
+    let src = "ok, r = xpcall(function(x) error(x, 0) end, function(m) return 'handled ' .. m end, 'x')";
+    assert_eq!(run(src, "r"), string("handled x"));
+    assert_eq!(eval("select('#', assert(1, 2, 3))"), Value::Integer(3));
+    assert_eq!(run("ok, r = pcall(assert, false, 'nope')", "r"), string("nope"));
+    assert_eq!(error("assert(nil)"), "test.lua:1: assertion failed!");
+}
+
+#[test]
+fn close_on_error() {
+    let src = "log = ''\nlocal mt = {__close = function(v, err) log = log .. tostring(err) end}\n\
+        pcall(function()\n  local x <close> = setmetatable({}, mt)\n  error('boom', 0)\nend)\n\
+        do local y <close> = setmetatable({}, mt) end";
+    assert_eq!(run(src, "log"), string("boomnil"));
+}
+
+#[test]
+fn close_on_goto() {
+    // jumping back over a `<close>` declaration closes the value at the jump
+    let src = "log = ''\ndo\n  local i = 0\n  ::top::\n\
+        local c <close> = setmetatable({n = i + 1}, {__close = function(v) log = log .. v.n end})\n\
+        i = i + 1\n  if i < 3 then goto top end\n  log = log .. 'end'\nend";
+    assert_eq!(run(src, "log"), string("12end3"));
+}
+
+#[test]
+fn traceback_names_frames() {
+    // the parentheses keep the calls from being tail calls
+    let src = "local t = {}\nfunction t:m() return (debug.traceback('here')) end\n\
+        local function g() return (t:m()) end\nfunction f() return (g()) end\ntb = f()";
+    let expected = "here\nstack traceback:\n\ttest.lua:2: in method 't:m'\n\ttest.lua:3: in upvalue 'g'\n\
+        \ttest.lua:4: in function 'f'\n\ttest.lua:5: in main chunk\n\t[C]: in ?";
+    assert_eq!(run(src, "tb"), string(expected));
+    let src = "local function g() return (debug.traceback()) end\nlocal function f() return g() end\ntb = f()";
+    let expected = "stack traceback:\n\ttest.lua:1: in function <test.lua:1>\n\t(...tail calls...)\n\
+        \ttest.lua:3: in main chunk\n\t[C]: in ?";
+    assert_eq!(run(src, "tb"), string(expected));
+}
+
+#[test]
+fn traceback_of_error() {
+    let src = "ok, tb = xpcall(function()\n  error('boom')\nend, debug.traceback)\nafter = debug.traceback(nil, 1)";
+    let expected = "test.lua:2: boom\nstack traceback:\n\t[C]: in function 'error'\n\ttest.lua:2: in function <test.lua:1>\n\
+        \t[C]: in function 'xpcall'\n\ttest.lua:1: in main chunk\n\t[C]: in ?";
+    assert_eq!(run(src, "tb"), string(expected));
+    // the frames of the caught error are gone
+    assert_eq!(run(src, "after"), string("stack traceback:\n\ttest.lua:4: in main chunk\n\t[C]: in ?"));
+    assert_eq!(run("t = debug.traceback({})", "t").type_name(), "table");
+}
+
+#[test]
+fn tail_calls() {
+    // far deeper than the maximum call depth
+    let src = "local function count(n, acc) if n == 0 then return acc end return count(n - 1, acc + 1) end\n\
+        result = count(100000, 0)";
+    assert_eq!(run(src, "result"), Value::Integer(100000));
+    let src = "local even, odd\nfunction even(n) if n == 0 then return true end return odd(n - 1) end\n\
+        function odd(n) if n == 0 then return false end return even(n - 1) end\nresult = even(5001)";
+    assert_eq!(run(src, "result"), Value::Boolean(false.into()));
+    // a `<close>` local is closed after the returned call
+    let src = "log = ''\nlocal function f() log = log .. 'f' end\n\
+        local function g() local x <close> = setmetatable({}, {__close = function() log = log .. 'c' end}) return f() end\ng()";
+    assert_eq!(run(src, "log"), string("fc"));
+    let src = "local function f() error('x') end\nlocal function g() return f() end\ng()";
+    assert_eq!(error(src), "test.lua:1: x");
+}
+
+#[test]
+fn stack_overflow() {
+    let src = "local function f() return 1 + f() end\nok, err = pcall(f)";
+    let block = parse(src, "test.lua").unwrap();
+    let mut ctx = Ctx::new();
+    ctx.set_max_depth(20);
+    prelude(&mut ctx);
+    block.walk(&mut ctx).unwrap();
+    assert_eq!(raw_get(&ctx.globals(), &string("err")), string("test.lua:1: stack overflow"));
+
+    // a `<close>` value is closed once the overflowing calls are unwound
+    let src = "local function rec(n) return 1 + rec(n + 1) end\nclosed = false\n\
+        ok = pcall(function()\n  local x <close> = setmetatable({}, {__close = function() closed = true end})\n  rec(1)\nend)";
+    let block = parse(src, "test.lua").unwrap();
+    let mut ctx = Ctx::new();
+    ctx.set_max_depth(20);
+    prelude(&mut ctx);
+    block.walk(&mut ctx).unwrap();
+    assert_eq!(raw_get(&ctx.globals(), &string("ok")), Value::Boolean(false.into()));
+    assert_eq!(raw_get(&ctx.globals(), &string("closed")), Value::Boolean(true.into()));
+}
+
+#[test]
+fn global_table() {
+    assert_eq!(run("x = 1\nresult = _G.x + _G._G.x", "result"), Value::Integer(2));
+    assert_eq!(run("_G.y = 'set'\nresult = y", "result"), string("set"));
+    let src = "n = 0\nfor k, v in pairs(_G) do if k == 'print' then n = n + 1 end end";
+    assert_eq!(run(src, "n"), Value::Integer(1));
+    // a strict mode, where undeclared globals are errors
+    let src = "setmetatable(_G, {__index = function(_, k) error('undeclared ' .. k, 2) end,\n\
+        __newindex = function(_, k) error('assigning ' .. k, 2) end})\n";
+    assert_eq!(error(&format!("{src}local x = y")), "test.lua:3: undeclared y");
+    assert_eq!(error(&format!("{src}z = 1")), "test.lua:3: assigning z");
+}
+
+#[test]
+fn env() {
+    assert_eq!(run("local g = _G\nlocal _ENV = {x = 1}\nx = x + 1\ng.result = x", "result"), Value::Integer(2));
+    let src = "x = 'outer'\nlocal g = _G\ndo local _ENV = {g = g} g.inner = x end\nresult = tostring(inner) .. x";
+    assert_eq!(run(src, "result"), string("nilouter"));
+    // functions see the `_ENV` they were defined in
+    let src = "local function sandbox(env) local _ENV = env return function() y = 2 return x end end\n\
+        local env = {x = 'inner'}\nresult = sandbox(env)() .. env.y";
+    assert_eq!(run(src, "result"), string("inner2"));
+    let src = "local g = _G\n_ENV = {}\ng.result = x == nil and g.x == 1\n";
+    assert_eq!(run(&format!("x = 1\n{src}"), "result"), Value::Boolean(true.into()));
+    assert_eq!(error("local _ENV = nil\nreturn x"), "test.lua:2: attempt to index a nil value (local '_ENV')");
+    assert_eq!(error("_ENV = nil\nreturn x"), "test.lua:2: attempt to index a nil value (upvalue '_ENV')");
+}
+
+#[test]
+fn load_chunks() {
+    assert_eq!(run("result = load('return 1 + ...')(2)", "result"), Value::Integer(3));
+    let src = "env = {x = 'sandboxed'}\nf = load('y = x return x', 'plugin', 't', env)\nresult = f() .. env.y .. tostring(y)";
+    assert_eq!(run(src, "result"), string("sandboxedsandboxednil"));
+    assert_eq!(run("f, result = load('return +', 'bad')", "result").type_name(), "string");
+    assert_eq!(run("f, err = load('x = 1', 'c', 'b') result = f", "result"), Value::Nil);
+    assert_eq!(run("ok, result = pcall(load('error(\"boom\")', 'chunk'))", "result"), string("chunk:1: boom"));
+}
@@ -1,10 +1,27 @@
-use crate::{ast::context::Ctx, lexer::identifier::Identifier, value::Value};
+use std::rc::Rc;
 
+use crate::{ast::{context::Ctx, function::Function}, lexer::identifier::Identifier, value::{table::Table, Value}};
+
+pub mod base;
+pub mod debug;
 pub mod io;
 pub mod math;
 pub mod string;
 
 pub fn prelude(ctx: &mut Ctx) {
+    for (name, func) in base::functions() {
+        ctx.new_global(Identifier::from(name), Value::Function(Rc::new(Function::Builtin(func))));
+    }
     let io_table = io::create_io_table();
     ctx.new_global(Identifier("io".to_string()), Value::Table(io_table));
+    let math_table = math::create_math_table();
+    ctx.new_global(Identifier("math".to_string()), Value::Table(math_table));
+    let debug_table = debug::create_debug_table();
+    ctx.new_global(Identifier("debug".to_string()), Value::Table(debug_table));
+    let string_table = string::create_string_table();
+    // strings index the string library, so `s:format(...)` calls `string.format(s, ...)`
+    let string_metatable = Table::new();
+    string_metatable.borrow_mut().insert(&Value::String("__index".into()), Value::Table(string_table.clone()));
+    ctx.set_string_metatable(string_metatable);
+    ctx.new_global(Identifier("string".to_string()), Value::Table(string_table));
 }
\ No newline at end of file
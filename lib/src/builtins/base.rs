@@ -0,0 +1,280 @@
+use std::{cell::RefCell, io::{self, Write}, rc::Rc};
+
+use crate::{ast::{context::{Ctx, Env}, error::LuaError, function::{call_value, BuiltinFn, Closure, Function, LuaFunction}}, lexer::span::{Position, Span}, parser::parse, value::{meta::{get_metatable, metamethod}, string::LuaString, table::{raw_get, raw_set, Table}, Boolean, Value}};
+
+fn table_arg(args: &[Value], idx: usize, fname: &str) -> Result<Rc<RefCell<Table>>, LuaError> {
+    match args.get(idx) {
+        Some(Value::Table(t)) => Ok(t.clone()),
+        other => Err(LuaError::type_error(idx + 1, fname, "table", other)),
+    }
+}
+
+/// The argument `idx` of `fname` as an integer, where numbers and strings are converted
+fn integer_arg(args: &[Value], idx: usize, fname: &str) -> Result<i64, LuaError> {
+    match args.get(idx).map(|arg| (arg, arg.as_number())) {
+        Some((_, Some(n))) => n.as_integer()
+            .ok_or_else(|| LuaError::bad_argument(idx + 1, fname, "number has no integer representation")),
+        other => Err(LuaError::type_error(idx + 1, fname, "number", other.map(|(arg, _)| arg))),
+    }
+}
+
+fn next(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let t = table_arg(args, 0, "next")?;
+    let key = args.get(1).cloned().unwrap_or(Value::Nil);
+    match t.borrow().next(&key)? {
+        Some((k, v)) => Ok(vec![k, v]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+fn pairs(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let t = table_arg(args, 0, "pairs")?;
+    Ok(vec![Value::Function(Rc::new(Function::Builtin(next))), Value::Table(t), Value::Nil])
+}
+
+/// The iterator returned by `ipairs`, which stops at the first nil value
+fn ipairs_next(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let t = table_arg(args, 0, "ipairs")?;
+    let i = match args.get(1) {
+        Some(Value::Integer(i)) => i.wrapping_add(1),
+        _ => 1,
+    };
+    match t.borrow().get(&Value::Integer(i)) {
+        Some(v) => Ok(vec![Value::Integer(i), v]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+fn ipairs(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let t = table_arg(args, 0, "ipairs")?;
+    Ok(vec![Value::Function(Rc::new(Function::Builtin(ipairs_next))), Value::Table(t), Value::Integer(0)])
+}
+
+/// `select('#', ...)` counts the extra arguments, `select(n, ...)` returns them from the nth on
+/// A negative `n` counts from the end
+fn select(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let top = args.len() as i64;
+    if let Some(Value::String(s)) = args.first() && s.to_str() == Some("#") {
+        return Ok(vec![Value::Integer(top - 1)]);
+    }
+    let n = integer_arg(args, 0, "select")?;
+    let n = if n < 0 { top + n } else { n.min(top) };
+    if n < 1 {
+        return Err(LuaError::bad_argument(1, "select", "index out of range"));
+    }
+    Ok(args[n as usize..].to_vec())
+}
+
+fn setmetatable(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let t = table_arg(args, 0, "setmetatable")?;
+    let metatable = match args.get(1) {
+        Some(Value::Table(mt)) => Some(mt.clone()),
+        Some(Value::Nil) => None,
+        other => return Err(LuaError::type_error(2, "setmetatable", "nil or table", other)),
+    };
+    let t = Value::Table(t);
+    if metamethod(&t, "__metatable", ctx).is_some() {
+        return Err(LuaError::new("cannot change a protected metatable"));
+    }
+    let Value::Table(table) = &t else { unreachable!() };
+    table.borrow_mut().set_metatable(metatable);
+    Ok(vec![t])
+}
+
+/// `getmetatable(v)`, which gives the `__metatable` field of the metatable instead if it has one
+fn getmetatable(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let val = args.first().ok_or_else(|| LuaError::bad_argument(1, "getmetatable", "value expected"))?;
+    match get_metatable(val, ctx) {
+        Some(mt) => Ok(vec![metamethod(val, "__metatable", ctx).unwrap_or(Value::Table(mt))]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+fn rawget(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let t = table_arg(args, 0, "rawget")?;
+    Ok(vec![raw_get(&t, args.get(1).unwrap_or(&Value::Nil))])
+}
+
+fn rawset(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let t = table_arg(args, 0, "rawset")?;
+    raw_set(&t, args.get(1).unwrap_or(&Value::Nil), args.get(2).cloned().unwrap_or(Value::Nil))?;
+    Ok(vec![Value::Table(t)])
+}
+
+fn rawequal(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let lhs = args.first().unwrap_or(&Value::Nil);
+    let rhs = args.get(1).unwrap_or(&Value::Nil);
+    Ok(vec![Value::Boolean((lhs == rhs).into())])
+}
+
+fn rawlen(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    match args.first() {
+        Some(Value::Table(t)) => Ok(vec![Value::Integer(t.borrow().border())]),
+        Some(Value::String(s)) => Ok(vec![Value::Integer(s.len() as i64)]),
+        _ => Err(LuaError::bad_argument(1, "rawlen", "table or string expected")),
+    }
+}
+
+/// Convert any value to a string, using the `__tostring` metamethod, or the `__name` field of the metatable
+pub fn tostring(val: &Value, ctx: &mut Ctx) -> Result<LuaString, LuaError> {
+    if let Some(handler) = metamethod(val, "__tostring", ctx) {
+        let result = call_value(&handler, vec![val.clone()], ctx)?.into_iter().next().unwrap_or(Value::Nil);
+        return result.as_string().ok_or_else(|| LuaError::new("'__tostring' must return a string"));
+    }
+    Ok(match val {
+        Value::Nil => "nil".into(),
+        Value::Boolean(b) => if *b == Boolean::True { "true".into() } else { "false".into() },
+        Value::Integer(_) | Value::Float(_) | Value::String(_) => val.as_string().expect("Numbers should convert to strings"),
+        Value::Table(t) => {
+            let name = match metamethod(val, "__name", ctx) {
+                Some(Value::String(name)) => name.to_str_lossy().into_owned(),
+                _ => "table".to_string(),
+            };
+            format!("{name}: {:p}", Rc::as_ptr(t)).into()
+        },
+        Value::Function(f) => format!("function: {:p}", Rc::as_ptr(f)).into(),
+        Value::Userdata | Value::Thread => val.type_name().into(),
+    })
+}
+
+fn tostring_(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let val = args.first().ok_or_else(|| LuaError::bad_argument(1, "tostring", "value expected"))?;
+    Ok(vec![Value::String(tostring(val, ctx)?)])
+}
+
+/// Write the arguments to stdout, converted with `tostring` and seperated by tabs
+fn print(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let strings = args.iter().map(|arg| tostring(arg, ctx)).collect::<Result<Vec<_>, _>>()?;
+    let mut line = Vec::new();
+    for (i, s) in strings.iter().enumerate() {
+        if i > 0 { line.push(b'\t'); }
+        line.extend_from_slice(s.as_bytes());
+    }
+    line.push(b'\n');
+    io::stdout().lock().write_all(&line).map_err(|err| LuaError::new(err.to_string()))?;
+    Ok(Vec::new())
+}
+
+/// `error(v [, level])` raises `v`, where a message gets the position of the call `level` levels up
+/// Level 1, the default, is where `error` was called, and level 0 adds no position
+fn error(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let val = args.first().cloned().unwrap_or(Value::Nil);
+    let level = match args.get(1) {
+        None | Some(Value::Nil) => 1,
+        Some(_) => integer_arg(args, 1, "error")?,
+    };
+    // level 0 is `error` itself, which has no position
+    let span = usize::try_from(level).ok().and_then(|level| ctx.position(level));
+    Err(LuaError::with_value(val, span))
+}
+
+/// `pcall(f, ...)` calls `f` in protected mode
+/// Returns `true` and the results of `f`, or `false` and the error object if it raised an error
+fn pcall(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let (func, args) = args.split_first().ok_or_else(|| LuaError::bad_argument(1, "pcall", "value expected"))?;
+    let depth = ctx.frame_depth();
+    match call_value(func, args.to_vec(), ctx) {
+        Ok(mut results) => {
+            results.insert(0, Value::Boolean(Boolean::True));
+            Ok(results)
+        },
+        Err(err) => {
+            ctx.unwind_frames(depth);
+            Ok(vec![Value::Boolean(Boolean::False), err.into_value()])
+        },
+    }
+}
+
+/// `xpcall(f, msgh, ...)` is like `pcall`, except that the error object is replaced by what `msgh` returns for it
+fn xpcall(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let func = args.first().cloned().unwrap_or(Value::Nil);
+    let handler = args.get(1).cloned().unwrap_or(Value::Nil);
+    let depth = ctx.frame_depth();
+    match call_value(&func, args.get(2..).unwrap_or_default().to_vec(), ctx) {
+        Ok(mut results) => {
+            results.insert(0, Value::Boolean(Boolean::True));
+            Ok(results)
+        },
+        Err(err) => {
+            // the handler runs before the error's frames are dropped, so it can get a traceback of where the error was
+            // raised, and an error in the handler replaces the original error
+            let handled = match call_value(&handler, vec![err.into_value()], ctx) {
+                Ok(results) => results.into_iter().next().unwrap_or(Value::Nil),
+                Err(err) => err.into_value(),
+            };
+            ctx.unwind_frames(depth);
+            Ok(vec![Value::Boolean(Boolean::False), handled])
+        },
+    }
+}
+
+/// `assert(v [, message, ...])` returns its arguments if `v` is true, and raises `message` otherwise
+fn assert(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    match args {
+        [] => Err(LuaError::bad_argument(1, "assert", "value expected")),
+        [v, ..] if v.as_bool() => Ok(args.to_vec()),
+        // the message is raised as it is, without a position
+        [_, message, ..] => Err(LuaError::with_value(message.clone(), None)),
+        [_] => Err(LuaError::new("assertion failed!")),
+    }
+}
+
+/// `load(chunk [, chunkname [, mode [, env]]])` compiles the string `chunk` into a function, which runs it
+/// The function's `_ENV` is `env` if it's given, even as nil, and the globals otherwise
+/// Returns nil and the error message if the chunk doesn't compile
+fn load(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    // FIXME: chunks can also be given by a function returning their pieces
+    let source = match args.first() {
+        Some(Value::String(s)) => s.to_str_lossy().into_owned(),
+        other => return Err(LuaError::type_error(1, "load", "string", other)),
+    };
+    let name = match args.get(1) {
+        Some(Value::String(name)) => name.to_str_lossy().into_owned(),
+        _ => {
+            let first = source.lines().next().unwrap_or_default();
+            let more = if first.len() < source.trim_end().len() { "..." } else { "" };
+            format!("[string \"{first}{more}\"]")
+        },
+    };
+    // there are no binary chunks
+    if let Some(Value::String(mode)) = args.get(2) && !mode.as_bytes().contains(&b't') {
+        let message = format!("attempt to load a text chunk (mode is '{}')", mode.to_str_lossy());
+        return Ok(vec![Value::Nil, Value::String(message.into())]);
+    }
+    let env = if args.len() > 3 { args[3].clone() } else { Value::Table(ctx.globals()) };
+    match parse(&source, &name) {
+        Ok(code) => {
+            let span = Span::new(name.into(), Position::start(), Position::start());
+            let func = LuaFunction { args: Vec::new(), is_vararg: true, code: Some(code), span };
+            let closure = Closure::new(Rc::new(func), Env::chunk(env));
+            Ok(vec![Value::Function(Rc::new(Function::Closure(closure)))])
+        },
+        Err(errors) => {
+            let message = errors.first().map(ToString::to_string).unwrap_or_default();
+            Ok(vec![Value::Nil, Value::String(message.into())])
+        },
+    }
+}
+
+/// The functions of the basic library, which are globals
+pub fn functions() -> [(&'static str, BuiltinFn); 17] {
+    [
+        ("next", next),
+        ("pairs", pairs),
+        ("ipairs", ipairs),
+        ("select", select),
+        ("setmetatable", setmetatable),
+        ("getmetatable", getmetatable),
+        ("rawget", rawget),
+        ("rawset", rawset),
+        ("rawequal", rawequal),
+        ("rawlen", rawlen),
+        ("tostring", tostring_),
+        ("print", print),
+        ("error", error),
+        ("pcall", pcall),
+        ("xpcall", xpcall),
+        ("assert", assert),
+        ("load", load),
+    ]
+}
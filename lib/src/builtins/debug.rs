@@ -0,0 +1,29 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{ast::{context::Ctx, error::LuaError, function::Function}, value::{table::Table, Value}};
+
+/// `debug.traceback([message [, level]])` gives `message` followed by a traceback of the stack, from `level` up
+/// Level 1, the default, is the function calling `traceback`, and messages other than strings are returned as they are
+fn traceback(args: &[Value], ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let msg = match args.first() {
+        None | Some(Value::Nil) => None,
+        Some(val) => match val.as_string() {
+            Some(msg) => Some(msg),
+            None => return Ok(vec![val.clone()]),
+        },
+    };
+    let level = match args.get(1) {
+        None | Some(Value::Nil) => 1,
+        Some(arg) => arg.as_number().and_then(|n| n.as_integer())
+            .ok_or_else(|| LuaError::type_error(2, "traceback", "number", Some(arg)))?,
+    };
+    let mut text = msg.map(|msg| format!("{}\n", msg.to_str_lossy())).unwrap_or_default();
+    text.push_str(&ctx.traceback(usize::try_from(level).unwrap_or(0)));
+    Ok(vec![Value::String(text.into())])
+}
+
+pub fn create_debug_table() -> Rc<RefCell<Table>> {
+    let t = Table::new();
+    t.borrow_mut().insert(&Value::String("traceback".into()), Value::Function(Rc::new(Function::Builtin(traceback))));
+    t
+}
@@ -1,42 +1,40 @@
-use std::{cell::RefCell, io, rc::Rc};
+use std::{cell::RefCell, io::{self, Write}, rc::Rc};
 
-use crate::{ast::function::Function, value::{table::Table, Value}};
+use crate::{ast::{context::Ctx, error::LuaError, function::Function}, value::{number::Number, table::Table, Value}};
 
-fn write(args: &Vec<Value>) -> Vec<Value> {
-    for arg in args {
-        match arg {
-            Value::String(s) => print!("{s}"),
-            Value::Number(n) => print!("{n}"),
-            _ => print!("{:?}", arg)
-        }
+fn write(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let mut out = io::stdout().lock();
+    for (idx, arg) in args.iter().enumerate() {
+        let s = arg.as_string().ok_or_else(|| LuaError::type_error(idx + 1, "write", "string", Some(arg)))?;
+        out.write_all(s.as_bytes()).map_err(|err| LuaError::new(err.to_string()))?;
     }
-    Vec::new()
+    Ok(Vec::new())
 }
 
-fn read(args: &Vec<Value>) -> Vec<Value> {
+fn read(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
     let mut buf = String::new();
     let stdin = io::stdin();
-    stdin.read_line(&mut buf).unwrap();
+    stdin.read_line(&mut buf).map_err(|err| LuaError::new(err.to_string()))?;
 
     // check args
     if let Some(Value::String(s)) = args.first() 
-    && &s.as_str()[0..1] == "n" 
+    && s.as_bytes().first() == Some(&b'n') 
     {
-        vec![Value::Number(buf.trim().parse().unwrap())]
+        Ok(vec![buf.parse::<Number>().map_or(Value::Nil, Value::from)])
     } else {
-        vec![Value::String(buf)]
+        Ok(vec![Value::String(buf.into())])
     }    
 }
 
 pub fn create_io_table() -> Rc<RefCell<Table>> {
     let t = Table::new();
     t.borrow_mut().insert(
-        &Value::String(String::from("write")),
+        &Value::String("write".into()),
         Value::Function(Rc::new(Function::Builtin(write))) 
     );
 
     t.borrow_mut().insert(
-        &Value::String(String::from("read")),
+        &Value::String("read".into()),
         Value::Function(Rc::new(Function::Builtin(read))) 
     );
 
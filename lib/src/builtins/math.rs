@@ -0,0 +1,102 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{ast::{context::Ctx, error::LuaError, function::{BuiltinFn, Function}}, value::{number::{float_to_integer, Number}, table::Table, Value}};
+
+fn number_arg(args: &[Value], idx: usize, fname: &str) -> Result<Number, LuaError> {
+    let arg = args.get(idx);
+    arg.and_then(|v| v.as_number()).ok_or_else(|| LuaError::type_error(idx + 1, fname, "number", arg))
+}
+
+/// Rounds a float with `round`, giving an integer if the result fits in one
+fn round_with(args: &[Value], fname: &str, round: fn(f64) -> f64) -> Result<Vec<Value>, LuaError> {
+    let n = match number_arg(args, 0, fname)? {
+        Number::Float(f) => {
+            let f = round(f);
+            float_to_integer(f).map_or(Number::Float(f), Number::Integer)
+        },
+        int => int,
+    };
+    Ok(vec![n.into()])
+}
+
+fn abs(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let n = match number_arg(args, 0, "abs")? {
+        Number::Integer(i) => Number::Integer(i.wrapping_abs()),
+        Number::Float(f) => Number::Float(f.abs()),
+    };
+    Ok(vec![n.into()])
+}
+
+fn ceil(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    round_with(args, "ceil", f64::ceil)
+}
+
+fn floor(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    round_with(args, "floor", f64::floor)
+}
+
+fn sqrt(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    Ok(vec![Value::Float(number_arg(args, 0, "sqrt")?.as_float().sqrt())])
+}
+
+fn max(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let mut max = number_arg(args, 0, "max")?;
+    for idx in 1..args.len() {
+        let n = number_arg(args, idx, "max")?;
+        if max < n { max = n; }
+    }
+    Ok(vec![max.into()])
+}
+
+fn min(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let mut min = number_arg(args, 0, "min")?;
+    for idx in 1..args.len() {
+        let n = number_arg(args, idx, "min")?;
+        if n < min { min = n; }
+    }
+    Ok(vec![min.into()])
+}
+
+/// `"integer"` or `"float"` for numbers, and `nil` for anything else
+fn type_(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let name = match args.first().ok_or_else(|| LuaError::bad_argument(1, "type", "value expected"))? {
+        Value::Integer(_) => Value::String("integer".into()),
+        Value::Float(_) => Value::String("float".into()),
+        _ => Value::Nil,
+    };
+    Ok(vec![name])
+}
+
+/// Converts floats with an integer value to integers, anything else gives `nil`
+fn tointeger(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let int = match args.first() {
+        Some(Value::Integer(i)) => Value::Integer(*i),
+        Some(Value::Float(f)) => float_to_integer(*f).map_or(Value::Nil, Value::Integer),
+        _ => Value::Nil,
+    };
+    Ok(vec![int])
+}
+
+pub fn create_math_table() -> Rc<RefCell<Table>> {
+    let t = Table::new();
+    let functions: [(&str, BuiltinFn); 8] = [
+        ("abs", abs),
+        ("ceil", ceil),
+        ("floor", floor),
+        ("sqrt", sqrt),
+        ("max", max),
+        ("min", min),
+        ("type", type_),
+        ("tointeger", tointeger),
+    ];
+    for (name, func) in functions {
+        t.borrow_mut().insert(&Value::String(name.into()), Value::Function(Rc::new(Function::Builtin(func))));
+    }
+
+    t.borrow_mut().insert(&Value::String("huge".into()), Value::Float(f64::INFINITY));
+    t.borrow_mut().insert(&Value::String("pi".into()), Value::Float(std::f64::consts::PI));
+    t.borrow_mut().insert(&Value::String("maxinteger".into()), Value::Integer(i64::MAX));
+    t.borrow_mut().insert(&Value::String("mininteger".into()), Value::Integer(i64::MIN));
+
+    t
+}
@@ -1,79 +1,106 @@
-use std::ffi::c_char;
+use std::{cell::RefCell, rc::Rc};
 
-use crate::{ast::expression::Expression, value::Value};
+use crate::{ast::{context::Ctx, error::LuaError, function::{BuiltinFn, Function}}, value::{number::format_g, table::Table, Value}};
 
-pub fn format(mut fmt: &str, args: &Vec<Value>) -> String {
+/// The arguments of `string.format` after the format string, which counts them for error messages
+struct FormatArgs<'a> {
+    args: std::slice::Iter<'a, Value>,
+    /// The argument number of the next argument, where the format string is argument 1
+    idx: usize,
+}
+
+impl FormatArgs<'_> {
+    fn next(&mut self) -> (usize, Option<&Value>) {
+        self.idx += 1;
+        (self.idx, self.args.next())
+    }
+
+    /// An argument for an integer format, which has to be a number with an integer representation
+    fn integer(&mut self) -> Result<i64, LuaError> {
+        let (idx, arg) = self.next();
+        match arg.and_then(|v| v.as_number()) {
+            Some(n) => n.as_integer().ok_or_else(|| LuaError::bad_argument(idx, "format", "number has no integer representation")),
+            None => Err(LuaError::type_error(idx, "format", "number", arg)),
+        }
+    }
+
+    fn float(&mut self) -> Result<f64, LuaError> {
+        let (idx, arg) = self.next();
+        arg.and_then(|v| v.as_number()).map(|n| n.as_float()).ok_or_else(|| LuaError::type_error(idx, "format", "number", arg))
+    }
+
+    fn string(&mut self) -> Result<String, LuaError> {
+        let (idx, arg) = self.next();
+        arg.and_then(|v| v.as_string()).map(|s| s.to_string()).ok_or_else(|| LuaError::type_error(idx, "format", "string", arg))
+    }
+}
+
+/// Format `f` like C's `%.{precision}e`, with at least two exponent digits
+fn format_e(f: f64, precision: usize, upper: bool) -> String {
+    let formatted = format!("{:.*e}", precision, f);
+    let formatted = match formatted.split_once('e') {
+        Some((mantissa, exp)) => {
+            let (sign, digits) = exp.strip_prefix('-').map_or(('+', exp), |digits| ('-', digits));
+            format!("{mantissa}e{sign}{digits:0>2}")
+        },
+        // infinite and nan values have no exponent
+        None => formatted,
+    };
+    if upper { formatted.to_uppercase() } else { formatted }
+}
+
+pub fn format(mut fmt: &str, args: &[Value]) -> Result<String, LuaError> {
     let mut buf = String::new();
-    let mut iter = args.iter();
+    let mut args = FormatArgs { args: args.iter(), idx: 1 };
     while let Some(idx) = fmt.find('%') {
         buf.push_str(&fmt[..idx]);
         fmt = &fmt[idx + 1..];
-        match &fmt[0..1] {
-            "d" | "i" => {
-                buf = format!("{buf}{}", iter.next().and_then(|v| v.as_number().map(|n| n as i64)).expect("failed to parse as number"))
-            },
-            "u" => {
-                buf = format!("{buf}{}", iter.next().and_then(|v| v.as_number().map(|n| n as u64)).expect("failed to parse as number"))
-            },
-            "o" => {
-                buf = format!("{buf}{:o}", iter.next().and_then(|v| v.as_number().map(|n| n as i64)).expect("failed to parse as number"))
+        // an optional precision, as in `%.3f`
+        let precision = match fmt.strip_prefix('.') {
+            Some(rest) => {
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                fmt = &rest[digits..];
+                Some(rest[..digits].parse::<usize>().unwrap_or(0))
             },
-            "x" => {
-                buf = format!("{buf}{:x}", iter.next().and_then(|v| v.as_number().map(|n| n as i64)).expect("failed to parse as number"))
-            },
-            "X" => {
-                buf = format!("{buf}{:X}", iter.next().and_then(|v| v.as_number().map(|n| n as i64)).expect("failed to parse as number"))
-            },
-            "f" => {
-                buf = format!("{buf}{}", iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"))
-            },
-            "e" => {
-                buf = format!("{buf}{:e}", iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"))
-            },
-            "E" => {
-                buf = format!("{buf}{:E}", iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"))
-            },
-            "g" => {
-                buf = format!("{buf}{:?}", iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"))
-            },
-            "G" => {
-                // FIXME
-                buf = format!("{buf}{:?}", iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"))
-            },
-            "c" => {
-                buf = format!("{buf}{}", iter.next()
-                    .and_then(|v| v.as_number().map(|c| ((c as u64) % 256) as u8 as char)).expect("failed to parse as number"))
-            },
-            "s" => {
-                buf = format!("{buf}{}", iter.next().and_then(|v| v.as_string()).expect("failed to parse as string"))
-            },
-            "." => { 
-                fmt = &fmt[1..];
-                let mut idx = 0;
-                while fmt[idx..idx + 1].chars().next().unwrap().is_numeric() {
-                    idx += 1;
-                }
-                let num = fmt[0..idx].parse::<usize>().expect("number wasn't found after dot");
-                fmt = &fmt[idx..];
-                match &fmt[0..1] {
-                    "e" => {
-                        buf = format!("{buf}{:.*e}", num, iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"))
-                    },
-                    "E" => {
-                        buf = format!("{buf}{:.*E}", num, iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"))
-                    },
-                    "g" => {
-                        // FIXME
-                        let formatted = format!("{:?}", iter.next().and_then(|v| v.as_number()).expect("failed to parse as number"));
-                        buf = format!("{buf}{:.*}", num + 1, formatted);
-                    },
-                    _ => todo!(),
-                }
-            },
-            _ => todo!()
+            None => None,
+        };
+        let Some(conversion) = fmt.chars().next() else {
+            return Err(LuaError::new("invalid conversion '%' to 'format'"));
+        };
+        match (conversion, precision) {
+            ('d' | 'i', None) => buf.push_str(&args.integer()?.to_string()),
+            ('u', None) => buf.push_str(&(args.integer()? as u64).to_string()),
+            ('o', None) => buf.push_str(&format!("{:o}", args.integer()?)),
+            ('x', None) => buf.push_str(&format!("{:x}", args.integer()?)),
+            ('X', None) => buf.push_str(&format!("{:X}", args.integer()?)),
+            ('c', None) => buf.push(((args.integer()? as u64) % 256) as u8 as char),
+            ('%', None) => buf.push('%'),
+            ('s', None) => buf.push_str(&args.string()?),
+            ('f', _) => buf.push_str(&format!("{:.*}", precision.unwrap_or(6), args.float()?)),
+            ('e', _) => buf.push_str(&format_e(args.float()?, precision.unwrap_or(6), false)),
+            ('E', _) => buf.push_str(&format_e(args.float()?, precision.unwrap_or(6), true)),
+            ('g', _) => buf.push_str(&format_g(args.float()?, precision.unwrap_or(6))),
+            ('G', _) => buf.push_str(&format_g(args.float()?, precision.unwrap_or(6)).to_uppercase()),
+            _ => return Err(LuaError::new(format!("invalid conversion '%{conversion}' to 'format'"))),
         }
-        fmt = &fmt[1..];
+        fmt = &fmt[conversion.len_utf8()..];
     }
     buf.push_str(fmt);
-    buf
-}
\ No newline at end of file
+    Ok(buf)
+}
+
+fn format_(args: &[Value], _ctx: &mut Ctx) -> Result<Vec<Value>, LuaError> {
+    let fmt = args.first().and_then(Value::as_string).ok_or_else(|| LuaError::type_error(1, "format", "string", args.first()))?;
+    Ok(vec![Value::String(format(&fmt.to_str_lossy(), &args[1..])?.into())])
+}
+
+pub fn create_string_table() -> Rc<RefCell<Table>> {
+    let t = Table::new();
+    let functions: [(&str, BuiltinFn); 1] = [
+        ("format", format_),
+    ];
+    for (name, func) in functions {
+        t.borrow_mut().insert(&Value::String(name.into()), Value::Function(Rc::new(Function::Builtin(func))));
+    }
+    t
+}
@@ -1,5 +1,7 @@
 //! This module implements lexer functionality
 //! It takes in a string representing a lua program and outputs a sequence of lexemes, or tokens
+//! Lexing is done in a single pass over the bytes of the source, and lexemes borrow their text from it
+//! Comments and whitespace are skipped, unless the lexer is asked to keep them as trivia
 
 pub mod assignment; // ?
 pub mod comment;
@@ -8,22 +10,24 @@ pub mod keyword;
 pub mod literal;
 pub mod operator;
 pub mod seperator;
+pub mod span;
 pub mod whitespace;
 
+use std::{collections::VecDeque, rc::Rc};
+
+use assignment::Assignment;
+use comment::Comment;
+use keyword::Keyword;
+use literal::{NumericLiteral, StringLiteral};
+use operator::Operator;
+use seperator::Seperator;
+use span::{Position, Span};
+
+use crate::parser::error::ParseError;
+
 #[cfg(test)]
 mod tests;
 
-/// This trait isn't used as trait in the code, and will be removed in the future
-/// It exists to specify what functions each token type (as represented in the submodules) must provide
-trait Token : Sized {
-    /// Attempt to parse this token from the input stream
-    /// If successful, returns the parsed token and the number of characters consumed
-    // Maybe use a different type?
-    fn parse(text: &str) -> Option<(Self, usize)>;
-    /// Returns the string matched to this token
-    fn raw(&self) -> &str; 
-} 
-
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum AngleBrackets {
     Open,
@@ -31,96 +35,351 @@ pub enum AngleBrackets {
 }
 
 // Make sure this contains all options!
-#[derive(Clone, PartialEq, Debug)]
-pub enum Lexeme {
-    Comment(comment::Comment),
-    Keyword(keyword::Keyword),
-    StringLiteral(literal::StringLiteral),
-    Operator(operator::Operator),
-    Assignment(assignment::Assignment),
-    NumericLiteral(literal::NumericLiteral),
-    Seperator(seperator::Seperator),
-    Identifier(identifier::Identifier),
-    Whitespace(whitespace::Whitespace),
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Lexeme<'a> {
+    Keyword(Keyword),
+    StringLiteral(StringLiteral<'a>),
+    Operator(Operator),
+    Assignment(Assignment),
+    NumericLiteral(NumericLiteral<'a>),
+    Seperator(Seperator),
+    Identifier(&'a str),
     // Kind of a hack, since < and > can lex as both seperators and operators, and only parsing can tell them apart
     AngleBrackets(AngleBrackets),
+    // Trivia, only emitted by lexers created with `Lexer::with_trivia`
+    Comment(Comment<'a>),
+    Whitespace(&'a str),
 }
 
-#[derive(Clone, Copy)]
-pub struct Lexer<'a> {
+impl<'a> Lexeme<'a> {
+    /// The text this lexeme was read from
+    pub fn raw(&self) -> &'a str {
+        match self {
+            Lexeme::Keyword(kw) => kw.raw(),
+            Lexeme::StringLiteral(s) => s.raw(),
+            Lexeme::Operator(op) => op.raw(),
+            Lexeme::Assignment(a) => a.raw(),
+            Lexeme::NumericLiteral(n) => n.raw(),
+            Lexeme::Seperator(sep) => sep.raw(),
+            Lexeme::Identifier(ident) => ident,
+            Lexeme::AngleBrackets(AngleBrackets::Open) => "<",
+            Lexeme::AngleBrackets(AngleBrackets::Close) => ">",
+            Lexeme::Comment(c) => c.raw(),
+            Lexeme::Whitespace(wsp) => wsp,
+        }
+    }
+
+    /// Whether this is a comment or whitespace, which the parser never sees
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Lexeme::Comment(_) | Lexeme::Whitespace(_))
+    }
+}
+
+/// A lexeme together with the source range it was read from
+#[derive(Clone, PartialEq, Debug)]
+pub struct SpannedLexeme<'a> {
+    pub lexeme: Lexeme<'a>,
+    pub span: Span,
+}
+
+/// Why the lexer couldn't read a lexeme
+#[derive(Debug)]
+struct LexFailure {
+    message: &'static str,
+    /// How much of the input to quote in the error, or `None` to report the end of the input
+    len: Option<usize>,
+}
+
+/// Reads lexemes one at a time from the source text
+struct Scanner<'a> {
     text: &'a str,
-    index: usize, // Change to some form of span?
+    file: Rc<str>,
+    pos: Position,
+    error: Option<ParseError>,
+    /// Whether comments and whitespace are emitted
+    keep_trivia: bool,
+}
+
+impl<'a> Scanner<'a> {
+    /// Read the next lexeme, skipping whitespace and comments unless `keep_trivia` is set
+    /// Returns `None` at the end of the input, or if the input can't be lexed, in which case `error` is set
+    fn scan(&mut self) -> Option<SpannedLexeme<'a>> {
+        if self.error.is_some() { return None; }
+        loop {
+            let text = &self.text[self.pos.offset..];
+            if text.is_empty() { return None; }
+
+            let (lexeme, len) = match lex_one(text) {
+                Ok(lexed) => lexed,
+                Err(failure) => {
+                    let found = failure.len.map(|len| &text[..len]);
+                    let end = self.pos.advance(found.unwrap_or(text));
+                    let message = match found {
+                        Some(_) => failure.message.to_string(),
+                        None => format!("{} (starting at line {})", failure.message, self.pos.line),
+                    };
+                    let span = Span::new(self.file.clone(), self.pos, end);
+                    self.error = Some(ParseError::new(message, span, found.map(str::to_string)));
+                    return None;
+                }
+            };
+            let start = self.pos;
+            self.pos = start.advance(&text[..len]);
+            if self.keep_trivia || !lexeme.is_trivia() {
+                let span = Span::new(self.file.clone(), start, self.pos);
+                return Some(SpannedLexeme { lexeme, span });
+            }
+        }
+    }
+
+    /// Skip over the character that caused a lexing error, so that lexing can resume after it
+    fn skip_invalid(&mut self) {
+        if self.error.take().is_some() {
+            // skip past any whitespace and comments before the invalid character
+            let text = &self.text[self.pos.offset..];
+            let mut skipped = 0;
+            while let Ok((lexeme, len)) = lex_one(&text[skipped..]) && lexeme.is_trivia() {
+                skipped += len;
+            }
+            let len = text[skipped..].chars().next().map_or(0, char::len_utf8);
+            self.pos = self.pos.advance(&text[..skipped + len]);
+        }
+    }
+}
+
+/// Returns the lexeme at the start of `text` and its length in bytes
+fn lex_one(text: &str) -> Result<(Lexeme<'_>, usize), LexFailure> {
+    let bytes = text.as_bytes();
+    let next = bytes.get(1).copied();
+    let op = |op, len| Ok((Lexeme::Operator(op), len));
+    let sep = |sep| Ok((Lexeme::Seperator(sep), 1));
+    match bytes[0] {
+        b if whitespace::is_whitespace(b) => {
+            let len = whitespace::len(bytes);
+            Ok((Lexeme::Whitespace(&text[..len]), len))
+        },
+        b'-' if next == Some(b'-') => match Comment::lex(text) {
+            Some((c, len)) => Ok((Lexeme::Comment(c), len)),
+            None => Err(LexFailure { message: "unfinished long comment", len: None }),
+        },
+        b if identifier::is_name_start(b) => {
+            let len = identifier::name_len(bytes);
+            let name = &text[..len];
+            let lexeme = match name {
+                "and" => Lexeme::Operator(Operator::LogicalAnd),
+                "or" => Lexeme::Operator(Operator::LogicalOr),
+                "not" => Lexeme::Operator(Operator::LogicalNot),
+                _ => Keyword::from_name(name).map_or(Lexeme::Identifier(name), Lexeme::Keyword),
+            };
+            Ok((lexeme, len))
+        },
+        b'0'..=b'9' => lex_number(text),
+        b'.' => match next {
+            Some(b'0'..=b'9') => lex_number(text),
+            Some(b'.') if bytes.get(2) == Some(&b'.') => Ok((Lexeme::Seperator(Seperator::Dots), 3)),
+            Some(b'.') => op(Operator::Concat, 2),
+            _ => sep(Seperator::Dot),
+        },
+        b'"' | b'\'' => {
+            StringLiteral::lex_short(text).map(|(s, len)| (Lexeme::StringLiteral(s), len))
+        },
+        b'[' => match literal::long_bracket_level(bytes) {
+            Some(level) => match StringLiteral::lex_long(text, level) {
+                Some((s, len)) => Ok((Lexeme::StringLiteral(s), len)),
+                None => Err(LexFailure { message: "unfinished long string", len: None }),
+            },
+            None if next == Some(b'=') => {
+                let len = 1 + bytes[1..].iter().take_while(|&&b| b == b'=').count();
+                Err(LexFailure { message: "invalid long string delimiter", len: Some(len) })
+            },
+            None => sep(Seperator::OpenBracket),
+        },
+        b'=' if next == Some(b'=') => op(Operator::Equal, 2),
+        b'=' => Ok((Lexeme::Assignment(Assignment {}), 1)),
+        b'<' => match next {
+            Some(b'=') => op(Operator::LessEqual, 2),
+            Some(b'<') => op(Operator::LeftShift, 2),
+            _ => Ok((Lexeme::AngleBrackets(AngleBrackets::Open), 1)),
+        },
+        b'>' => match next {
+            Some(b'=') => op(Operator::GreaterEqual, 2),
+            Some(b'>') => op(Operator::RightShift, 2),
+            _ => Ok((Lexeme::AngleBrackets(AngleBrackets::Close), 1)),
+        },
+        b'~' if next == Some(b'=') => op(Operator::NotEqual, 2),
+        b'~' => op(Operator::Tilde, 1),
+        b'/' if next == Some(b'/') => op(Operator::DoubleSlash, 2),
+        b'/' => op(Operator::Slash, 1),
+        b':' if next == Some(b':') => Ok((Lexeme::Seperator(Seperator::DoubleColon), 2)),
+        b':' => sep(Seperator::Colon),
+        b'+' => op(Operator::Plus, 1),
+        b'-' => op(Operator::Minus, 1),
+        b'*' => op(Operator::Star, 1),
+        b'%' => op(Operator::Percent, 1),
+        b'^' => op(Operator::Caret, 1),
+        b'#' => op(Operator::Hash, 1),
+        b'&' => op(Operator::BitAnd, 1),
+        b'|' => op(Operator::BitOr, 1),
+        b'(' => sep(Seperator::OpenParen),
+        b')' => sep(Seperator::CloseParen),
+        b'{' => sep(Seperator::OpenCurly),
+        b'}' => sep(Seperator::CloseCurly),
+        b']' => sep(Seperator::CloseBracket),
+        b';' => sep(Seperator::Semicolon),
+        b',' => sep(Seperator::Comma),
+        _ => {
+            let len = text.chars().next().map_or(0, char::len_utf8);
+            Err(LexFailure { message: "unexpected symbol", len: Some(len) })
+        },
+    }
+}
+
+fn lex_number(text: &str) -> Result<(Lexeme<'_>, usize), LexFailure> {
+    NumericLiteral::lex(text)
+        .map(|(n, len)| (Lexeme::NumericLiteral(n), len))
+        .map_err(|len| LexFailure { message: "malformed number", len: Some(len) })
+}
+
+/// The lexer as seen by the parser
+/// Lexemes are read ahead into a buffer as the parser peeks at them, so the parser can look as far ahead as it needs
+pub struct Lexer<'a> {
+    scanner: Scanner<'a>,
+    buffer: VecDeque<SpannedLexeme<'a>>,
+    last_span: Span,
+    /// How many blocks the lexemes returned so far have opened and not closed
+    depth: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(text: &'a str) -> Lexer<'a> {
-        Lexer { text, index: 0 }
+        Lexer::with_file(text, "?")
     }
 
-}
+    /// Create a lexer whose spans report `file` as their source
+    pub fn with_file(text: &'a str, file: &str) -> Lexer<'a> {
+        let file: Rc<str> = Rc::from(file);
+        let last_span = Span::new(file.clone(), Position::start(), Position::start());
+        let scanner = Scanner { text, file, pos: Position::start(), error: None, keep_trivia: false };
+        Lexer { scanner, buffer: VecDeque::new(), last_span, depth: 0 }
+    }
 
-impl<'a> Iterator for Lexer<'a> {
-    type Item = Lexeme;
-    
-    // Make sure to check for proper ordering!
-    fn next(&mut self) -> Option<Lexeme> {
-        if self.index >= self.text.len() { return None; }
-
-        let text = &self.text[self.index..];
-
-        if let Some((comment, len)) = comment::Comment::parse(text) {
-            self.index += len;
-            //Some(Lexeme::Comment(comment))
-            self.next()
-        }
-        // parse op before numbers in order to not consume +/-
-        else if let Some((op, len)) = operator::Operator::parse(text) {
-            self.index += len;
-            Some(Lexeme::Operator(op))
-        }
-        else if let Some((kw, len)) = keyword::Keyword::parse(text) {
-            self.index += len;
-            Some(Lexeme::Keyword(kw))
-        }
-        // lex immediatly after keywords to prevent other captures
-        else if let Some((ident, len)) = identifier::Identifier::parse(text) {
-            self.index += len;
-            Some(Lexeme::Identifier(ident))
-        }
-        else if let Some((s, len)) = literal::StringLiteral::parse(text) {
-            self.index += len;
-            Some(Lexeme::StringLiteral(s))
-        } 
-        else if let Some((a, len)) = assignment::Assignment::parse(text) {
-            self.index += len;
-            Some(Lexeme::Assignment(a))
-        } 
-        else if let Some((n, len)) = literal::NumericLiteral::parse(text) {
-            self.index += len;
-            Some(Lexeme::NumericLiteral(n))
-        }
-        else if let Some((sep, len)) = seperator::Seperator::parse(text) {
-            self.index += len;
-            Some(Lexeme::Seperator(sep))
+    /// Create a lexer that also emits comments and whitespace, so that every byte of `text` is part of some lexeme
+    /// Concatenating the raw text of the lexemes gives back the original source
+    pub fn with_trivia(text: &'a str, file: &str) -> Lexer<'a> {
+        let mut lex = Lexer::with_file(text, file);
+        lex.scanner.keep_trivia = true;
+        lex
+    }
+
+    /// Skip a first line starting with '#', as used by unix shebangs
+    /// When trivia is kept, the line is emitted as a comment instead
+    pub fn skip_shebang(&mut self) {
+        let scanner = &mut self.scanner;
+        if scanner.pos.offset == 0 && self.buffer.is_empty() && scanner.text.starts_with('#') {
+            let (comment, len) = Comment::lex_shebang(scanner.text);
+            let start = scanner.pos;
+            scanner.pos = start.advance(&scanner.text[..len]);
+            if scanner.keep_trivia {
+                let span = Span::new(scanner.file.clone(), start, scanner.pos);
+                self.buffer.push_back(SpannedLexeme { lexeme: Lexeme::Comment(comment), span });
+            }
         }
-        else if let Some((wsp, len)) = whitespace::Whitespace::parse(text) {
-            self.index += len;
-            //eprintln!("Whitespace: {wsp:?}, len: {len}");
-            //Some(Lexeme::Whitespace(wsp))
-            //eprintln!("Not emitting whitespace token");
-            self.next()
+    }
+
+    /// How many blocks the lexemes returned so far leave open
+    /// Every `if`, `do`, `function` and `repeat` is closed by exactly one `end` or `until`, so this doesn't need the parser
+    pub fn block_depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn next_lexeme(&mut self) -> Option<Lexeme<'a>> {
+        self.next().map(|t| t.lexeme)
+    }
+
+    /// The lexeme `n` lexemes ahead, where 0 is the next one
+    /// Returns `None` if the input ends, or can't be lexed, before then
+    pub fn peek_nth(&mut self, n: usize) -> Option<&SpannedLexeme<'a>> {
+        while self.buffer.len() <= n {
+            let tok = self.scanner.scan()?;
+            self.buffer.push_back(tok);
         }
-        else if &text[..1] == "<" {
-            self.index += 1;
-            Some(Lexeme::AngleBrackets(AngleBrackets::Open))
+        self.buffer.get(n)
+    }
+
+    pub fn peek(&mut self) -> Option<&SpannedLexeme<'a>> {
+        self.peek_nth(0)
+    }
+
+    pub fn peek_lexeme(&mut self) -> Option<Lexeme<'a>> {
+        self.peek().map(|t| t.lexeme)
+    }
+
+    pub fn peek_nth_lexeme(&mut self, n: usize) -> Option<Lexeme<'a>> {
+        self.peek_nth(n).map(|t| t.lexeme)
+    }
+
+    /// The span of the next lexeme, or an empty span at the end of the input
+    pub fn peek_span(&mut self) -> Span {
+        self.near().0
+    }
+
+    /// The span of the lexeme most recently returned by `next`
+    pub fn last_span(&self) -> &Span {
+        &self.last_span
+    }
+
+    pub fn file(&self) -> &Rc<str> {
+        &self.scanner.file
+    }
+
+    /// The span and source text of the next lexeme, with no text at the end of the input
+    pub fn near(&mut self) -> (Span, Option<&'a str>) {
+        let text = self.scanner.text;
+        match self.peek() {
+            Some(tok) => (tok.span.clone(), Some(tok.span.slice(text))),
+            None => {
+                let pos = self.scanner.pos;
+                (Span::new(self.scanner.file.clone(), pos, pos), None)
+            }
         }
-        else if &text[..1] == ">" {
-            self.index += 1;
-            Some(Lexeme::AngleBrackets(AngleBrackets::Close))
+    }
+
+    /// The error that stopped the lexer, if it couldn't make sense of the input
+    pub fn error(&self) -> Option<&ParseError> {
+        if self.buffer.is_empty() { self.scanner.error.as_ref() } else { None }
+    }
+
+    /// The error that reading the next lexeme would produce
+    pub fn peek_error(&mut self) -> Option<ParseError> {
+        match self.peek() {
+            Some(_) => None,
+            None => self.scanner.error.clone(),
         }
-        else {    
-            None
+    }
+
+    /// Skip over the character that caused a lexing error, so that lexing can resume after it
+    pub fn skip_invalid(&mut self) {
+        if self.buffer.is_empty() {
+            self.scanner.skip_invalid();
         }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = SpannedLexeme<'a>;
 
+    fn next(&mut self) -> Option<SpannedLexeme<'a>> {
+        let tok = match self.buffer.pop_front() {
+            Some(tok) => tok,
+            None => self.scanner.scan()?,
+        };
+        self.last_span = tok.span.clone();
+        match tok.lexeme {
+            Lexeme::Keyword(Keyword::If | Keyword::Do | Keyword::Function | Keyword::Repeat) => self.depth += 1,
+            // a stray `end` doesn't close anything
+            Lexeme::Keyword(Keyword::End | Keyword::Until) => self.depth = self.depth.saturating_sub(1),
+            _ => {},
+        }
+        Some(tok)
     }
 }
@@ -1,19 +1,11 @@
-use std::fmt::{write, Debug, Display};
-
-use super::Token;
+use std::fmt::{Debug, Display};
 
 /// Should this really be a different thing?
 #[derive(Clone, Copy, PartialEq)]
 pub struct Assignment {}
 
-impl Token for Assignment {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        // no regex needed!
-        if &text[0..1] == "=" {
-            Some((Assignment {}, 1))
-        } else { None }
-    }
-    fn raw(&self) -> &str {
+impl Assignment {
+    pub fn raw(&self) -> &'static str {
         "="
     }
 }
@@ -28,4 +20,4 @@ impl Debug for Assignment {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self)
     }
-}
\ No newline at end of file
+}
@@ -1,35 +1,46 @@
-use lazy_static::lazy_static;
-use regex::{self, Regex};
-use super::*;
+//! Single line (`-- ...`) and long (`--[==[ ... ]==]`) comments
 
-const RE_STR: &str = r"(?m)\A--(\[\[(?<multi>(.|\s)*?)\]\]|(?<single>.*$))";
-const MULTILINE_GROUP: &str = "multi";
-const SINGLELINE_GROUP: &str = "single";
+use super::literal::{find_long_bracket_close, long_bracket_level};
 
-lazy_static! {
-    static ref RE: Regex = Regex::new(RE_STR).expect("Error parsing comment regex");
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Comment<'a> {
+    raw: &'a str,
+    /// The text of the comment, without the dashes and brackets
+    body: &'a str,
+    long: bool,
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub struct Comment {
-    comment: String,
-}
-
-impl Token for Comment {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        // try to match comment regex
-        if let Some(captures) = RE.captures(text) {
-            let len = captures[0].len(); // how many characters do we consume in total?
-            let comment = {if let Some(multi_line) = captures.name(MULTILINE_GROUP) {
-                multi_line.as_str()
-            } else {
-                &captures[SINGLELINE_GROUP]
-            }}.to_string();
-            Some((Comment { comment }, len))
+impl<'a> Comment<'a> {
+    /// Read the comment at the start of `text`, which has to start with `--`
+    /// Returns `None` if a long comment is never closed
+    pub fn lex(text: &'a str) -> Option<(Comment<'a>, usize)> {
+        let bytes = text.as_bytes();
+        if bytes.get(2) == Some(&b'[')
+            && let Some(level) = long_bracket_level(&bytes[2..])
+        {
+            let (close, len) = find_long_bracket_close(&bytes[2..], level)?;
+            let comment = Comment { raw: &text[..len + 2], body: &text[level + 4..close + 2], long: true };
+            return Some((comment, len + 2));
         }
-        else { None }
+        let len = bytes.iter().position(|&b| b == b'\n' || b == b'\r').unwrap_or(bytes.len());
+        Some((Comment { raw: &text[..len], body: &text[2..len], long: false }, len))
+    }
+
+    /// Read the `#` line that can start a chunk, as used by unix shebangs
+    pub fn lex_shebang(text: &'a str) -> (Comment<'a>, usize) {
+        let len = text.find('\n').unwrap_or(text.len());
+        (Comment { raw: &text[..len], body: &text[1..len], long: false }, len)
+    }
+
+    pub fn raw(&self) -> &'a str {
+        self.raw
     }
-    fn raw(&self) -> &str {
-        self.comment.as_str()
+
+    pub fn body(&self) -> &'a str {
+        self.body
     }
-}
\ No newline at end of file
+
+    pub fn is_long(&self) -> bool {
+        self.long
+    }
+}
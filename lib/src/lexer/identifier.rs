@@ -1,16 +1,5 @@
 use std::fmt::Display;
 
-use lazy_static::lazy_static;
-use regex::Regex;
-
-use super::Token;
-
-const IDENTIFIER_RE_STR: &str = r#"(?m)\A[a-zA-Z_][a-zA-Z0-9_]*"#;
-
-lazy_static! {
-    static ref IDENTIFIER_RE: Regex = Regex::new(IDENTIFIER_RE_STR).expect("Identifier regex failed to parse");
-}
-
 #[derive(Clone, PartialEq, Debug, Eq, Hash)]
 pub struct Identifier(pub String);
 
@@ -20,13 +9,18 @@ impl Display for Identifier {
     }
 }
 
-impl Token for Identifier {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        if let Some(captures) = IDENTIFIER_RE.captures(text) {
-            Some((Identifier(captures[0].to_string()), captures[0].len()))
-        } else { None }
-    }
-    fn raw(&self) -> &str {
-        self.0.as_str()
+impl From<&str> for Identifier {
+    fn from(name: &str) -> Self {
+        Identifier(name.to_string())
     }
-}
\ No newline at end of file
+}
+
+/// Whether `b` can start a name
+pub fn is_name_start(b: u8) -> bool {
+    b.is_ascii_alphabetic() || b == b'_'
+}
+
+/// The length of the name at the start of `bytes`
+pub fn name_len(bytes: &[u8]) -> usize {
+    bytes.iter().position(|&b| !b.is_ascii_alphanumeric() && b != b'_').unwrap_or(bytes.len())
+}
@@ -1,18 +1,7 @@
 //! This module lexes all reserved keywords in Lua
+//! `and`, `or` and `not` are lexed as operators instead
 
 use macros::VariantsToStr;
-use static_assertions::const_assert;
-use super::*;
-
-// check that the list is sorted
-const fn is_sorted(a: &[&str]) -> bool {
-    let mut i = 1;
-    while i < a.len() {
-        if a[i-1].len() < a[i].len() {return false;}
-        i += 1;
-    }
-    true
-}
 
 #[derive(VariantsToStr, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Keyword {
@@ -21,6 +10,7 @@ pub enum Keyword {
     Repeat,
     Return,
     Break,
+    Goto,
     False,
     Local,
     Until,
@@ -28,28 +18,16 @@ pub enum Keyword {
     Else,
     Then,
     True,
-    And,
     End,
     For,
     Nil,
-    Not,
     Do,
     If,
     In,
-    Or,
 }
 
-const_assert!(is_sorted(&Keyword::all_variants_str()));
-
-impl Token for Keyword {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        Keyword::str_to_variant(text)
-            .filter(|&(_kw, size)| 
-                text.chars().skip(size).next()
-                .is_none_or(|c| !c.is_alphanumeric() && c != '_') 
-            )
-    }
-    fn raw(&self) -> &str {
-        todo!()
+impl Keyword {
+    pub fn raw(&self) -> &'static str {
+        self.as_str()
     }
-}
\ No newline at end of file
+}
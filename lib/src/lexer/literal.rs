@@ -1,187 +1,409 @@
-use std::collections::HashMap;
-use lazy_static::lazy_static;
-use strfmt::strfmt;
-use regex::Regex;
+//! String and numeric literals
+//! Literals borrow their text from the source, and string values are only decoded when they're asked for
 
-use super::Token;
+use std::borrow::Cow;
 
-const SHORT_LITERAL_STR_RE_STR: &str = r#"\A(('(?<single_str>([^'\n\\]|\\.)*)')|("(?<double_str>([^"\n\\]|\\.)*)"))"#;
-const LONG_STR_BEGIN_RE_STR: &str = r#"\A\[(?<equals>=*)\["#;
-const LONG_STR_FORMAT_RE_STR: &str = r#"\A\[{0}\[\n?(?<str>(.|\s)*?)\]{0}\]"#; // using lazy capture isn't very efficient
+use crate::value::number::Number;
 
-/// These regexes have the issue that they need to match the general construction A|B|AB
-/// But, we also need to seperate the parts into capture groups so that we can parse them together
-/// So, we need to check the vacuosly true string
-const DECIMAL_RE_STR: &str = r#"\A(?<base>[0-9]*)(?<fraction>\.[0-9]*)?([eE][-+]?[0-9]*)?"#;
-const HEX_RE_STR: &str = r#"\A(?<hex>0[xX][0-9a-fA-F]*(?<fraction>\.[0-9a-fA-F]*)?(?<exp>[pP][-+]?[0-9]+)?)"#;
+use super::{span::line_break_len, LexFailure};
 
-lazy_static! {
-    static ref SHORT_LITERAL_STR_RE: Regex = Regex::new(SHORT_LITERAL_STR_RE_STR).expect("Error parsing short literal str regex");
-    static ref LONG_STR_BEGIN_RE: Regex = Regex::new(LONG_STR_BEGIN_RE_STR).expect("Error parsing long literal str begining regex");
-    static ref DECIMAL_RE: Regex = Regex::new(DECIMAL_RE_STR).expect("Error parsing decimal literal regex");
-    static ref HEX_RE: Regex = Regex::new(HEX_RE_STR).expect("Error parsing hex literal regex");
-}
-
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum StringLiteralKind {
     Short,
     Long,
 }
 
-/// TODO: Figure out escape characters
-#[derive(Clone, PartialEq, Eq, Debug)]
-pub struct StringLiteral {
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StringLiteral<'a> {
     kind: StringLiteralKind,
-    s: String,
-    raw: String,
-    // do we need the seperators as their own values
-}
-
-impl StringLiteral {
-    pub fn match_long_str(s: &str) -> Option<StringLiteral> {
-        // parse begining of string
-        LONG_STR_BEGIN_RE.captures(s)
-            .map(|captures| {
-                let eq = &captures["equals"];
-                // create format map
-                let format_map = HashMap::from([(0.to_string(), eq)]);
-                // create matching regex
-                let format_re = Regex::new(
-                    strfmt(LONG_STR_FORMAT_RE_STR, &format_map).expect("formatting string failed").as_str()
-                ).expect("Error parsing long str format regex");
-                format_re.captures(s)
-            })
-            .flatten()
-            .map(|captures| {
-                StringLiteral {
-                    kind: StringLiteralKind::Long,
-                    s: captures["str"].to_string(),
-                    raw: captures[0].to_string(),
+    raw: &'a str,
+    /// The text between the delimiters, without the line break that can start a long string
+    body: &'a str,
+    /// Whether the body has escapes or line breaks that have to be converted to get the value
+    needs_decoding: bool,
+}
+
+impl<'a> StringLiteral<'a> {
+    /// Read a quoted string literal from the start of `text`, which has to start with the quote
+    /// Escape sequences are checked here, so that decoding them later can't fail
+    pub(super) fn lex_short(text: &'a str) -> Result<(StringLiteral<'a>, usize), LexFailure> {
+        let bytes = text.as_bytes();
+        let quote = bytes[0];
+        let mut idx = 1;
+        let mut needs_decoding = false;
+        loop {
+            match bytes.get(idx) {
+                None => return Err(LexFailure { message: "unfinished string", len: Some(bytes.len()) }),
+                Some(b'\n' | b'\r') => return Err(LexFailure { message: "unfinished string", len: Some(idx) }),
+                Some(b'\\') => {
+                    needs_decoding = true;
+                    idx = read_escape(bytes, idx + 1, &mut Discard)?;
+                },
+                Some(&c) if c == quote => break,
+                Some(_) => idx += 1,
+            }
+        }
+        let len = idx + 1;
+        Ok((StringLiteral { kind: StringLiteralKind::Short, raw: &text[..len], body: &text[1..idx], needs_decoding }, len))
+    }
+
+    /// Read a long bracket string of `level` from the start of `text`
+    /// Returns `None` if the string is never closed
+    pub fn lex_long(text: &'a str, level: usize) -> Option<(StringLiteral<'a>, usize)> {
+        let (close, len) = find_long_bracket_close(text.as_bytes(), level)?;
+        // a line break directly after the opening bracket isn't part of the string
+        let mut start = level + 2;
+        start += line_break_len(&text.as_bytes()[start..close]);
+        let body = &text[start..close];
+        let needs_decoding = body.contains('\r');
+        Some((StringLiteral { kind: StringLiteralKind::Long, raw: &text[..len], body, needs_decoding }, len))
+    }
+
+    /// The bytes of the string, after decoding escape sequences and line breaks
+    pub fn value(&self) -> Cow<'a, [u8]> {
+        if !self.needs_decoding {
+            return Cow::Borrowed(self.body.as_bytes());
+        }
+        let bytes = self.body.as_bytes();
+        let mut s = Vec::with_capacity(bytes.len());
+        let mut idx = 0;
+        while idx < bytes.len() {
+            match (self.kind, bytes[idx]) {
+                (StringLiteralKind::Short, b'\\') => {
+                    idx = read_escape(bytes, idx + 1, &mut s).expect("Escapes are checked when lexing");
+                },
+                // any kind of line break is read as a newline
+                (StringLiteralKind::Long, b'\n' | b'\r') => {
+                    s.push(b'\n');
+                    idx += line_break_len(&bytes[idx..]);
+                },
+                (_, c) => {
+                    s.push(c);
+                    idx += 1;
                 }
-            })
-    }
-    
-    fn replace_escapes(s: String) -> String {
-        s.replace("\\n", "\n")
-            .replace("\\\\", "\\")
-            .replace("\\\'", "\'")
-            .replace("\\\"", "\"")
-            .replace("\\t", "\t")
-    }
-
-    pub fn from_str(s: &str) -> Option<StringLiteral> {
-        // try to match short string
-        if let Some(capture) = SHORT_LITERAL_STR_RE.captures(s) {
-            Some(StringLiteral { 
-                kind: StringLiteralKind::Short, 
-                s: Self::replace_escapes(capture
-                    .name("single_str")
-                    .unwrap_or_else(|| capture.name("double_str")
-                        .expect("One of single_str, double_str should be captured"))
-                    .as_str().to_string()), 
-                raw: capture[0].to_string()
-            })
-        } else { StringLiteral::match_long_str(s) }
-    }
-
-    pub fn value(&self) -> &str { &self.s }
-}
-
-impl Token for StringLiteral {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        Self::from_str(text).map(|s| {
-            let size = s.raw.len();
-            (s, size)
-        })
-    }
-    fn raw(&self) -> &str {
-        &self.raw
-    }
-}
-
-
-#[derive(Clone, PartialEq, Debug)]
-pub struct NumericLiteral {
-    value: f64,
-    raw: String,
-}
-
-impl NumericLiteral {
-    pub fn from_str(s: &str) -> Option<NumericLiteral> {
-        // attempt to parse decimal
-        DECIMAL_RE.captures(s)
-            .map(|captures| {
-                if captures.name("fraction").is_some_and(|s| s.len() > 1) || captures.name("base").is_some_and(|s| !s.is_empty()) {
-                    Some(NumericLiteral { 
-                        value: captures[0].parse().expect("Regex matched decimal float, but parsing failed"), 
-                        raw: captures[0].to_string() 
-                    })
-                } else { None }
-            })
-            .flatten()
-        .or_else(|| {
-            HEX_RE.captures(s)
-            .map(|captures| {
-                // seperate and convert
-                /* 
-                let base = captures.name("hex")
-                    .map(|m| i64::from_str_radix(&m.as_str()[2..], 16)
-                        .expect("Regex match found base hex, but parsing failed")
-                    );
-                let fraction: Option<f64> = captures.name("fraction")
-                    .map(|m| if m.is_empty() {0.0}
-                        else {
-                            i64::from_str_radix(&m.as_str()[1..], 16)
-                                .expect("Regex match found fraction hex, but parsing failed")
-                            as f64
-                        }
-                    )
-                    .map(|f| {
-                        // find where first nonzero is
-                        let num_leading = captures.name("fraction")
-                            .unwrap().as_str()[1..]
-                            .find(|c: char| c.is_numeric() && c != '0').unwrap_or(0);
-                        // then, for each space, we shift divide by 16
-                        f / (16.0 * (num_leading as f64))
-                    });
-                let power: Option<f64> = captures.name("exp")
-                    .map(|m| i64::from_str_radix(&m.as_str()[1..], 10)
-                        .expect("Regex match found exp hex, but parsing failed")
-                    )
-                    .map(|e| 2.0f64.powi(e as i32) );
-                
-                if fraction.is_some() || base.is_some() {
-                    if fraction.is_some() || power.is_some() {
-                        let value = NumericValue::Integer(base.unwrap());
-                        Some(NumericLiteral { value, raw: captures[0].to_string() })
-                    } else { 
-                        let value = NumericValue::Integer(base.unwrap());
-                        Some(NumericLiteral { value, raw: captures[0].to_string() })
+            }
+        }
+        Cow::Owned(s)
+    }
+
+    pub fn kind(&self) -> StringLiteralKind { self.kind }
+
+    pub fn raw(&self) -> &'a str { self.raw }
+}
+
+/// The level (the number of `=`) of the long bracket opening at the start of `bytes`, if there is one
+/// `bytes` has to start with `[`
+pub fn long_bracket_level(bytes: &[u8]) -> Option<usize> {
+    let level = bytes[1..].iter().take_while(|&&b| b == b'=').count();
+    (bytes.get(level + 1) == Some(&b'[')).then_some(level)
+}
+
+/// Find the long bracket closing the one of `level` at the start of `bytes`
+/// Returns the index where the closing bracket starts and the length up to the end of it
+pub fn find_long_bracket_close(bytes: &[u8], level: usize) -> Option<(usize, usize)> {
+    let mut idx = level + 2;
+    while let Some(offset) = bytes[idx..].iter().position(|&b| b == b']') {
+        let close = idx + offset;
+        let after = &bytes[close + 1..];
+        if after.len() > level && after[..level].iter().all(|&b| b == b'=') && after[level] == b']' {
+            return Some((close, close + level + 2));
+        }
+        idx = close + 1;
+    }
+    None
+}
+
+/// Where decoded bytes go
+/// Escapes are decoded twice: once to check them while lexing, where the output is thrown away, and once to get the value
+trait ByteSink {
+    fn push(&mut self, b: u8);
+}
+
+impl ByteSink for Vec<u8> {
+    fn push(&mut self, b: u8) {
+        Vec::push(self, b);
+    }
+}
+
+struct Discard;
+
+impl ByteSink for Discard {
+    fn push(&mut self, _b: u8) {}
+}
+
+/// Decode the escape sequence whose backslash is just before `idx` in `bytes`, and return the index after it
+/// Errors are measured from the start of `bytes`, up to and including the character that made the escape invalid
+fn read_escape(bytes: &[u8], mut idx: usize, out: &mut impl ByteSink) -> Result<usize, LexFailure> {
+    let err = |message, end: usize| LexFailure { message, len: Some(end.min(bytes.len())) };
+    let Some(&c) = bytes.get(idx) else {
+        return Err(err("unfinished string", bytes.len()));
+    };
+    idx += 1;
+    match c {
+        b'a' => out.push(0x07),
+        b'b' => out.push(0x08),
+        b'f' => out.push(0x0C),
+        b'n' => out.push(b'\n'),
+        b'r' => out.push(b'\r'),
+        b't' => out.push(b'\t'),
+        b'v' => out.push(0x0B),
+        b'\\' | b'"' | b'\'' => out.push(c),
+        b'\n' | b'\r' => {
+            // an escaped line break, which may be two characters long
+            out.push(b'\n');
+            idx += line_break_len(&bytes[idx - 1..]) - 1;
+        },
+        b'x' => {
+            let mut value = 0;
+            for _ in 0..2 {
+                let Some(digit) = bytes.get(idx).and_then(|&d| (d as char).to_digit(16)) else {
+                    return Err(err("hexadecimal digit expected", idx + 1));
+                };
+                value = value * 16 + digit;
+                idx += 1;
+            }
+            out.push(value as u8);
+        },
+        b'z' => {
+            while bytes.get(idx).is_some_and(|&b| b.is_ascii_whitespace() || b == 0x0B) {
+                idx += 1;
+            }
+        },
+        b'0'..=b'9' => {
+            let mut value = (c - b'0') as u32;
+            for _ in 0..2 {
+                match bytes.get(idx) {
+                    Some(d) if d.is_ascii_digit() => {
+                        value = value * 10 + (d - b'0') as u32;
+                        idx += 1;
+                    },
+                    _ => break,
+                }
+            }
+            if value > 255 {
+                return Err(err("decimal escape too large", idx + 1));
+            }
+            out.push(value as u8);
+        },
+        b'u' => {
+            if bytes.get(idx) != Some(&b'{') {
+                return Err(err("missing '{' in \\u{xxxx}", idx + 1));
+            }
+            idx += 1;
+            let mut value: u32 = 0;
+            let mut digits = 0;
+            while let Some(digit) = bytes.get(idx).and_then(|&d| (d as char).to_digit(16)) {
+                if value >= 0x8000000 {
+                    return Err(err("UTF-8 value too large", idx + 1));
+                }
+                value = value * 16 + digit;
+                digits += 1;
+                idx += 1;
+            }
+            if digits == 0 {
+                return Err(err("hexadecimal digit expected", idx + 1));
+            }
+            if bytes.get(idx) != Some(&b'}') {
+                return Err(err("missing '}' in \\u{xxxx}", idx + 1));
+            }
+            idx += 1;
+            encode_utf8(value, out);
+        },
+        _ => return Err(err("invalid escape sequence", idx)),
+    }
+    Ok(idx)
+}
+
+/// Encode `value` as UTF-8, using the original (up to 6 byte) scheme so that all values below 2^31 can be encoded
+fn encode_utf8(value: u32, out: &mut impl ByteSink) {
+    if value < 0x80 {
+        out.push(value as u8);
+        return;
+    }
+    let mut cont = [0; 5];
+    let mut num_cont = 0;
+    let mut rest = value;
+    // the most bits that fit in the first byte, given the number of continuation bytes so far
+    let mut first_max = 0x3F;
+    while rest > first_max {
+        cont[num_cont] = 0x80 | (rest & 0x3F) as u8;
+        num_cont += 1;
+        rest >>= 6;
+        first_max >>= 1;
+    }
+    let prefix = !((first_max << 1) | 1) as u8;
+    out.push(prefix | rest as u8);
+    for &b in cont[..num_cont].iter().rev() {
+        out.push(b);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NumericLiteral<'a> {
+    value: Number,
+    raw: &'a str,
+}
+
+impl<'a> NumericLiteral<'a> {
+    /// Read a numeral from the start of `text`, which has to start with a digit, or a dot followed by a digit
+    /// Like the reference implementation, everything that could continue the numeral is read before converting it,
+    /// so a numeral that runs into letters is malformed. The error is the length of the malformed text
+    pub fn lex(text: &'a str) -> Result<(NumericLiteral<'a>, usize), usize> {
+        let bytes = text.as_bytes();
+        let is_hex = bytes.len() >= 2 && bytes[0] == b'0' && matches!(bytes[1], b'x' | b'X');
+        let (mut idx, exp_markers) = if is_hex { (2, [b'p', b'P']) } else { (0, [b'e', b'E']) };
+        loop {
+            match bytes.get(idx) {
+                Some(c) if exp_markers.contains(c) => {
+                    idx += 1;
+                    if matches!(bytes.get(idx), Some(b'+' | b'-')) {
+                        idx += 1;
                     }
-                } else { None }
-                 */
-                todo!("Hex lex!")
-            }).flatten()
-        })
+                },
+                Some(c) if c.is_ascii_hexdigit() || *c == b'.' => idx += 1,
+                _ => break,
+            }
+        }
+        if bytes.get(idx).is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_') {
+            return Err(idx + 1);
+        }
+        let raw = &text[..idx];
+        str_to_number(raw).map(|value| (NumericLiteral { value, raw }, idx)).ok_or(idx)
     }
 
-    pub fn value(&self) -> f64 {
+    pub fn value(&self) -> Number {
         self.value
     }
 
-    pub fn new(value: f64, raw: String) -> NumericLiteral {
+    pub fn raw(&self) -> &'a str {
+        self.raw
+    }
+
+    pub fn new(value: Number, raw: &'a str) -> NumericLiteral<'a> {
         NumericLiteral { value, raw }
     }
 }
 
-impl Token for NumericLiteral {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        Self::from_str(text).map(|s| {
-            let size = s.raw.len();
-            (s, size)
-        })
+/// Convert the text of a numeral, without any sign or surrounding whitespace, to its value
+/// Returns `None` if the text isn't a valid numeral
+pub fn str_to_number(s: &str) -> Option<Number> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => hex_value(hex),
+        None => decimal_value(s),
+    }
+}
+
+/// Split `s` after the digits at its start
+fn split_digits(s: &str, is_digit: fn(&u8) -> bool) -> (&str, &str) {
+    s.split_at(s.bytes().position(|b| !is_digit(&b)).unwrap_or(s.len()))
+}
+
+/// Split a numeral into the digits before the point, the digits after the point (if there is one),
+/// and the rest of the numeral
+fn split_mantissa(s: &str, is_digit: fn(&u8) -> bool) -> (&str, Option<&str>, &str) {
+    let (int, rest) = split_digits(s, is_digit);
+    match rest.strip_prefix('.') {
+        Some(rest) => {
+            let (fraction, rest) = split_digits(rest, is_digit);
+            (int, Some(fraction), rest)
+        },
+        None => (int, None, rest),
+    }
+}
+
+/// Parse a decimal exponent with an optional sign, which saturates if it's too large to represent
+fn parse_exponent(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // a saturated exponent makes the result 0 or infinite
+    let exp = digits.bytes().fold(0i64, |acc, d| acc.saturating_mul(10).saturating_add((d - b'0') as i64).min(i64::MAX / 2));
+    Some(if negative { -exp } else { exp })
+}
+
+fn decimal_value(s: &str) -> Option<Number> {
+    let (int, fraction, rest) = split_mantissa(s, u8::is_ascii_digit);
+    if int.is_empty() && fraction.is_none_or(str::is_empty) {
+        return None;
+    }
+    let has_exp = match rest.strip_prefix(['e', 'E']) {
+        Some(exp) => { parse_exponent(exp)?; true },
+        None if rest.is_empty() => false,
+        None => return None,
+    };
+    if fraction.is_none() && !has_exp {
+        // decimal integers that don't fit are read as floats instead
+        if let Ok(i) = s.parse() {
+            return Some(Number::Integer(i));
+        }
+    }
+    Some(Number::Float(s.parse().expect("Checked decimal numeral should parse as a float")))
+}
+
+/// The value of the hex numeral `s`, after the `0x`
+fn hex_value(s: &str) -> Option<Number> {
+    let (int, fraction, rest) = split_mantissa(s, u8::is_ascii_hexdigit);
+    if int.is_empty() && fraction.is_none_or(str::is_empty) {
+        return None;
+    }
+    let exp = match rest.strip_prefix(['p', 'P']) {
+        Some(exp) => Some(parse_exponent(exp)?),
+        None if rest.is_empty() => None,
+        None => return None,
+    };
+    if fraction.is_none() && exp.is_none() {
+        // hex integers wrap around instead of overflowing
+        let value = int.bytes().fold(0u64, |acc, d| acc.wrapping_mul(16).wrapping_add(hex_digit(d) as u64));
+        return Some(Number::Integer(value as i64));
+    }
+    Some(Number::Float(hex_float(int, fraction.unwrap_or(""), exp.unwrap_or(0))))
+}
+
+fn hex_digit(d: u8) -> u32 {
+    (d as char).to_digit(16).expect("Checked hex digit should convert")
+}
+
+/// The value of the hex float `int.fraction` multiplied by `2^exp`
+fn hex_float(int: &str, fraction: &str, exp: i64) -> f64 {
+    // only this many significant digits are read, any after that only affect the exponent
+    const MAX_SIG_DIGITS: usize = 30;
+
+    let mut mantissa = 0.0;
+    let mut exp = exp;
+    let mut sig_digits = 0;
+    for (idx, d) in int.bytes().chain(fraction.bytes()).enumerate() {
+        let in_fraction = idx >= int.len();
+        if sig_digits == 0 && d == b'0' {
+            // leading zeros aren't significant
+        } else if sig_digits < MAX_SIG_DIGITS {
+            sig_digits += 1;
+            mantissa = mantissa * 16.0 + hex_digit(d) as f64;
+        } else if !in_fraction {
+            exp += 4;
+            continue;
+        } else { continue; }
+        if in_fraction {
+            exp -= 4;
+        }
+    }
+    ldexp(mantissa, exp)
+}
+
+/// Computes `x * 2^exp` without overflowing in the intermediate steps
+fn ldexp(mut x: f64, mut exp: i64) -> f64 {
+    if x == 0.0 { return x; }
+    while exp > 1000 && x.is_finite() {
+        x *= 2f64.powi(1000);
+        exp -= 1000;
     }
-    fn raw(&self) -> &str {
-        &self.raw
+    while exp < -1000 && x != 0.0 {
+        x *= 2f64.powi(-1000);
+        exp += 1000;
     }
+    x * 2f64.powi(exp as i32)
 }
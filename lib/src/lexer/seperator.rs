@@ -1,7 +1,5 @@
-use super::Token;
-
 /// TODO: Reuse Operator code
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Seperator {
     DoubleColon,
     OpenBracket,
@@ -11,31 +9,15 @@ pub enum Seperator {
     OpenCurly,
     CloseCurly,
     Dot,
+    /// `...`, the varargs of a function
+    Dots,
     Semicolon,
     Comma,
     Colon,
 }
 
-impl Token for Seperator {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        if text.len() > 1 && &text[0..2] == "::" { 
-            return Some((Self::DoubleColon, 2));
-        }
-        match &text[0..1] {
-            "[" => Some((Self::OpenBracket, 1)),
-            "]" => Some((Self::CloseBracket, 1)),
-            "(" => Some((Self::OpenParen, 1)),
-            ")" => Some((Self::CloseParen, 1)),
-            "{" => Some((Self::OpenCurly, 1)),
-            "}" => Some((Self::CloseCurly, 1)),
-            "." => Some((Self::Dot, 1)),
-            ";" => Some((Self::Semicolon, 1)),
-            "," => Some((Self::Comma, 1)),
-            ":" => Some((Self::Colon, 1)),
-            _ => None,
-        }
-    }
-    fn raw(&self) -> &str {
+impl Seperator {
+    pub fn raw(&self) -> &'static str {
         match self {
             Self::OpenBracket => "[",
             Self::CloseBracket => "]",
@@ -44,10 +26,11 @@ impl Token for Seperator {
             Self::OpenCurly => "{",
             Self::CloseCurly => "}",
             Self::Dot => ".",
+            Self::Dots => "...",
             Self::Semicolon => ";",
             Self::Comma => ",",
             Self::Colon => ":",
             Self::DoubleColon => "::",
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,97 @@
+//! Source locations attached to lexemes and syntax tree nodes
+
+use std::{fmt::Display, rc::Rc};
+
+/// A single point in the source text
+/// Lines and columns start at 1, columns count characters rather than bytes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Position {
+    pub offset: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Position {
+    pub fn start() -> Position {
+        Position { offset: 0, line: 1, column: 1 }
+    }
+
+    /// Returns the position reached after reading `text` from this position
+    pub fn advance(self, text: &str) -> Position {
+        let mut pos = self;
+        pos.offset += text.len();
+        let bytes = text.as_bytes();
+        let mut idx = 0;
+        while let Some(&b) = bytes.get(idx) {
+            match line_break_len(&bytes[idx..]) {
+                0 => {
+                    // UTF-8 continuation bytes don't start a new character
+                    if b & 0xC0 != 0x80 {
+                        pos.column += 1;
+                    }
+                    idx += 1;
+                },
+                len => {
+                    pos.line += 1;
+                    pos.column = 1;
+                    idx += len;
+                },
+            }
+        }
+        pos
+    }
+}
+
+/// The length of the line break at the start of `bytes`
+/// `\n`, `\r`, `\r\n` and `\n\r` each count as a single line break
+pub fn line_break_len(bytes: &[u8]) -> usize {
+    match bytes {
+        [b'\r', b'\n', ..] | [b'\n', b'\r', ..] => 2,
+        [b'\n' | b'\r', ..] => 1,
+        _ => 0,
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position::start()
+    }
+}
+
+/// A range of source text, from `start` (inclusive) to `end` (exclusive), in the file `file`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(file: Rc<str>, start: Position, end: Position) -> Span {
+        Span { file, start, end }
+    }
+
+    /// Returns a span covering everything from the start of `self` to the end of `other`
+    pub fn to(&self, other: &Span) -> Span {
+        Span { file: self.file.clone(), start: self.start, end: other.end }
+    }
+
+    pub fn line(&self) -> u32 {
+        self.start.line
+    }
+
+    pub fn column(&self) -> u32 {
+        self.start.column
+    }
+
+    /// The part of `source` covered by this span
+    pub fn slice<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start.offset..self.end.offset]
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.start.line, self.start.column)
+    }
+}
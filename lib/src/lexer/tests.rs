@@ -1,33 +1,88 @@
 // test basic lexing
 
-use crate::lexer::{literal::NumericLiteral, operator::Operator, Lexeme};
+use crate::{lexer::{assignment::Assignment, keyword::Keyword, literal::NumericLiteral, operator::Operator, seperator::Seperator, Lexeme}, value::number::Number};
 
 use super::Lexer;
 
 mod lexemes {
-    use crate::lexer::{Lexeme, Lexer, Token};
+    use crate::{lexer::{Lexeme, Lexer}, value::number::Number};
 
     fn test_str_single_output(s: &str, value: &str) {
         let mut lexer = Lexer::new(s);
-        let parsed = lexer.next();
+        let parsed = lexer.next_lexeme();
         assert!(parsed.is_some());
         let parsed_value = match parsed.unwrap() {
             Lexeme::StringLiteral(p) => p,
             _ => panic!("String literal matched a different lexeme")
         };
-        assert_eq!(parsed_value.value(), value);
+        assert_eq!(parsed_value.value(), value.as_bytes());
         assert_eq!(parsed_value.raw(), s);
     }
 
-    fn test_numeral_single_output(s: &str, value: f64) {
+    fn test_escapes(s: &str, value: &[u8]) {
+        let mut lexer = Lexer::new(s);
+        match lexer.next_lexeme() {
+            Some(Lexeme::StringLiteral(p)) => assert_eq!(p.value(), value),
+            other => panic!("String literal lexed as {other:?}"),
+        }
+        assert!(lexer.next().is_none());
+    }
+
+    fn test_lex_error(s: &str, message: &str, near: &str) {
+        let mut lexer = Lexer::new(s);
+        assert!(lexer.next().is_none());
+        let err = lexer.error().expect("invalid input should produce an error");
+        assert_eq!(err.message(), message);
+        assert_eq!(err.found(), Some(near));
+    }
+
+    #[test]
+    fn simple_escapes() {
+        test_escapes(r#""\a\b\f\n\r\t\v\\\"\'""#, b"\x07\x08\x0C\n\r\t\x0B\\\"'");
+        // escapes are decoded in a single pass
+        test_escapes(r#"'\\n'"#, b"\\n");
+    }
+
+    #[test]
+    fn numeric_escapes() {
+        test_escapes(r#""\65\066\0677\x41\xfF""#, &[b'A', b'B', b'C', b'7', b'A', 0xFF]);
+        test_escapes(r#""\0\255""#, &[0, 255]);
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        test_escapes(r#""\u{48}\u{e9}\u{20AC}\u{1F600}""#, "H\u{e9}\u{20AC}\u{1F600}".as_bytes());
+        test_escapes(r#""\u{7FFFFFFF}""#, &[0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]);
+    }
+
+    #[test]
+    fn line_break_escapes() {
+        test_escapes("'a\\z  \n\t  b'", b"ab");
+        test_escapes("'a\\\nb'", b"a\nb");
+        test_escapes("'a\\\r\nb'", b"a\nb");
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        test_lex_error(r#""abc\q""#, "invalid escape sequence", r#""abc\q"#);
+        test_lex_error(r#""\xZZ""#, "hexadecimal digit expected", r#""\xZ"#);
+        test_lex_error(r#""\256""#, "decimal escape too large", r#""\256""#);
+        test_lex_error(r#""\u48""#, "missing '{' in \\u{xxxx}", r#""\u4"#);
+        test_lex_error(r#""\u{48""#, "missing '}' in \\u{xxxx}", r#""\u{48""#);
+        test_lex_error(r#""\u{80000000}""#, "UTF-8 value too large", r#""\u{80000000"#);
+    }
+
+    fn test_numeral_single_output(s: &str, value: impl Into<Number>) {
+        let value = value.into();
         let mut lexer = Lexer::new(s);
-        let parsed = lexer.next();
+        let parsed = lexer.next_lexeme();
         assert!(parsed.is_some());
         let parsed_value = match parsed.unwrap() {
             Lexeme::NumericLiteral(p) => p,
             _ => panic!("Numeric literal matched a different lexeme")
         };
         assert_eq!(parsed_value.value(), value);
+        assert_eq!(parsed_value.value().type_name(), value.type_name());
         assert_eq!(parsed_value.raw(), s);
     }
 
@@ -55,32 +110,80 @@ mod lexemes {
     #[test]
     fn basic_decimal() {
         let s = "115";
-        let val = 115;
-        let wrapped = val as f64;
-        test_numeral_single_output(s, wrapped);
+        let val: i64 = 115;
+        test_numeral_single_output(s, val);
     }
 
     #[test]
     fn float_decimal() {
-        let s = "3.1415";
-        let val = 3.1415;
+        let s = "2.5625";
+        let val = 2.5625;
         test_numeral_single_output(s, val);
     }
 
     #[test]
     fn exp_decimal() {
-        let s = "0.31415e1";
-        let val = 3.1415;
+        let s = "0.25625e1";
+        let val = 2.5625;
         test_numeral_single_output(s, val);
     }
 
+    #[test]
+    fn integer_or_float() {
+        test_numeral_single_output("3", 3);
+        test_numeral_single_output("3.0", 3.0);
+        test_numeral_single_output("3.", 3.0);
+        test_numeral_single_output("3e0", 3.0);
+        // decimal integers that overflow become floats
+        test_numeral_single_output("9223372036854775807", i64::MAX);
+        test_numeral_single_output("9223372036854775808", 9223372036854775808.0);
+    }
+
     #[test]
     fn basic_hex() {
-        // test fails, need to work on hex parsing
         let s = "0xA1";
-        let val = 0xA1;
-        let wrapped = val as f64;
-        test_numeral_single_output(s, wrapped);
+        let val: i64 = 0xA1;
+        test_numeral_single_output(s, val);
+    }
+
+    #[test]
+    fn hex_wraparound() {
+        test_numeral_single_output("0xffffffffffffffff", -1);
+        test_numeral_single_output("0x10000000000000001", 1);
+        test_numeral_single_output("0x7fffffffffffffff", i64::MAX);
+    }
+
+    #[test]
+    fn hex_float() {
+        test_numeral_single_output("0x1.8p3", 12.0);
+        test_numeral_single_output("0xA.8", 10.5);
+        test_numeral_single_output("0x.1", 0.0625);
+        test_numeral_single_output("0x1p-2", 0.25);
+        test_numeral_single_output("0X1P+4", 16.0);
+        test_numeral_single_output("0x1p99999", f64::INFINITY);
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        test_lex_error("0x", "malformed number", "0x");
+        test_lex_error("0x.p1", "malformed number", "0x.p1");
+        test_lex_error("3x", "malformed number", "3x");
+        test_lex_error("0x1g", "malformed number", "0x1g");
+    }
+
+    #[test]
+    fn long_strings() {
+        // a line break right after the opening bracket is skipped, and line breaks are normalized
+        test_str_value("[[\nfirst\r\nsecond]]", b"first\nsecond");
+        test_str_value("[=[a]]b]=]", b"a]]b");
+        test_lex_error("[==x", "invalid long string delimiter", "[==");
+    }
+
+    fn test_str_value(s: &str, value: &[u8]) {
+        match Lexer::new(s).next_lexeme() {
+            Some(Lexeme::StringLiteral(p)) => assert_eq!(p.value(), value),
+            other => panic!("String literal lexed as {other:?}"),
+        }
     }
 }
 
@@ -88,12 +191,128 @@ mod lexemes {
 fn add_2() {
     let s = "5+2";
     let mut l = Lexer::new(s);
-    let five = l.next().unwrap();
-    let plus = l.next().unwrap();
-    let two = l.next().unwrap();
+    let five = l.next_lexeme().unwrap();
+    let plus = l.next_lexeme().unwrap();
+    let two = l.next_lexeme().unwrap();
 
-    assert!(five == Lexeme::NumericLiteral(NumericLiteral::new(5 as f64, "5".to_string())));
+    assert!(five == Lexeme::NumericLiteral(NumericLiteral::new(Number::Integer(5), "5")));
     println!("{:?}", plus);
     assert!(plus == Lexeme::Operator(Operator::Plus));
-    assert!(two == Lexeme::NumericLiteral(NumericLiteral::new(2 as f64, "2".to_string())));
-}
\ No newline at end of file
+    assert!(two == Lexeme::NumericLiteral(NumericLiteral::new(Number::Integer(2), "2")));
+}
+
+#[test]
+fn spans() {
+    let s = "local x = 1\n  print(x)";
+    let spans = Lexer::with_file(s, "test.lua").map(|l| l.span).collect::<Vec<_>>();
+
+    assert_eq!(spans.len(), 8);
+    assert_eq!(spans[0].to_string(), "test.lua:1:1");
+    assert_eq!(spans[0].slice(s), "local");
+    assert_eq!(spans[3].to_string(), "test.lua:1:11");
+    assert_eq!(spans[4].to_string(), "test.lua:2:3");
+    assert_eq!(spans[4].slice(s), "print");
+    assert_eq!(spans[7].to_string(), "test.lua:2:10");
+}
+
+#[test]
+fn line_endings() {
+    // `\n`, `\r`, `\r\n` and `\n\r` are each one line break
+    let s = "a\rb\r\nc\n\rd\n\ne\r\rf";
+    let lines = Lexer::new(s).map(|l| l.span.line()).collect::<Vec<_>>();
+    assert_eq!(lines, [1, 2, 3, 4, 6, 8]);
+    let f = Lexer::new(s).last().unwrap();
+    assert_eq!(f.span.column(), 1);
+}
+
+#[test]
+fn spans_skip_comments() {
+    let s = "--[[ a\ncomment ]] x -- trailing\ny";
+    let mut l = Lexer::new(s);
+    let x = l.next().unwrap();
+    let y = l.next().unwrap();
+
+    assert_eq!((x.span.line(), x.span.column()), (2, 12));
+    assert_eq!((y.span.line(), y.span.column()), (3, 1));
+    assert!(l.next().is_none());
+}
+
+#[test]
+fn long_comments() {
+    let s = "--[==[ ]] ]=] ]==] x --[ not long\ny";
+    let names = Lexer::new(s).map(|l| l.lexeme).collect::<Vec<_>>();
+    assert_eq!(names, [Lexeme::Identifier("x"), Lexeme::Identifier("y")]);
+}
+
+#[test]
+fn keywords_and_names() {
+    let s = "android or_else not ornot end ends";
+    let lexemes = Lexer::new(s).map(|l| l.lexeme).collect::<Vec<_>>();
+    assert_eq!(lexemes, [
+        Lexeme::Identifier("android"),
+        Lexeme::Identifier("or_else"),
+        Lexeme::Operator(Operator::LogicalNot),
+        Lexeme::Identifier("ornot"),
+        Lexeme::Keyword(Keyword::End),
+        Lexeme::Identifier("ends"),
+    ]);
+}
+
+#[test]
+fn lookahead() {
+    let mut l = Lexer::new("a.b = 1");
+    assert_eq!(l.peek_nth_lexeme(3), Some(Lexeme::Assignment(Assignment {})));
+    assert_eq!(l.peek_nth_lexeme(1), Some(Lexeme::Seperator(Seperator::Dot)));
+    assert_eq!(l.peek_nth_lexeme(5), None);
+    assert_eq!(l.next_lexeme(), Some(Lexeme::Identifier("a")));
+    assert_eq!(l.last_span().column(), 1);
+    assert_eq!(l.peek_span().column(), 2);
+}
+
+#[test]
+fn dots() {
+    let lexemes = Lexer::new("a.b..c...").map(|l| l.lexeme).collect::<Vec<_>>();
+    assert_eq!(lexemes, [
+        Lexeme::Identifier("a"),
+        Lexeme::Seperator(Seperator::Dot),
+        Lexeme::Identifier("b"),
+        Lexeme::Operator(Operator::Concat),
+        Lexeme::Identifier("c"),
+        Lexeme::Seperator(Seperator::Dots),
+    ]);
+}
+
+/// Lex `s` keeping trivia, and check that the lexemes cover the source exactly
+fn test_lossless(s: &str) -> Vec<Lexeme<'_>> {
+    let mut l = Lexer::with_trivia(s, "test.lua");
+    l.skip_shebang();
+    let tokens = l.by_ref().collect::<Vec<_>>();
+    assert!(l.error().is_none());
+
+    let mut offset = 0;
+    for tok in &tokens {
+        assert_eq!(tok.span.start.offset, offset);
+        assert_eq!(tok.span.slice(s), tok.lexeme.raw());
+        offset = tok.span.end.offset;
+    }
+    assert_eq!(offset, s.len());
+    assert_eq!(tokens.iter().map(|t| t.lexeme.raw()).collect::<String>(), s);
+    tokens.into_iter().map(|t| t.lexeme).collect()
+}
+
+#[test]
+fn trivia() {
+    let lexemes = test_lossless("#!/usr/bin/lua\nx = 1 -- one\n--[=[ long\r\n]=]\ty");
+    let comments = lexemes.iter().filter_map(|l| match l {
+        Lexeme::Comment(c) => Some((c.body(), c.is_long())),
+        _ => None,
+    }).collect::<Vec<_>>();
+    assert_eq!(comments, [("!/usr/bin/lua", false), (" one", false), (" long\r\n", true)]);
+    assert_eq!(lexemes.last(), Some(&Lexeme::Identifier("y")));
+    assert!(matches!(lexemes[1], Lexeme::Whitespace("\n")));
+}
+
+#[test]
+fn lossless_source_file() {
+    test_lossless(include_str!("../../../sources/argparse.lua"));
+}
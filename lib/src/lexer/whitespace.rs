@@ -1,24 +1,11 @@
-use lazy_static::lazy_static;
-use regex::Regex;
+//! Whitespace between lexemes
 
-use super::Token;
-
-const WHITESPACE_RE_STR: &str = r#"(?m)\A\s+"#;
-
-lazy_static! {
-    static ref WHITESPACE_RE: Regex = Regex::new(WHITESPACE_RE_STR).expect("Error parsing whitespace regex");
+/// Whether `b` is whitespace as far as Lua is concerned
+pub fn is_whitespace(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r' | 0x0B | 0x0C)
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub struct Whitespace(String);
-
-impl Token for Whitespace {
-    fn parse(text: &str) -> Option<(Self, usize)> {
-        if let Some(captures) = WHITESPACE_RE.captures(text) {
-            Some((Whitespace(captures[0].to_string()), captures[0].len()))
-        } else { None }
-    }
-    fn raw(&self) -> &str {
-        self.0.as_str()
-    }
-}
\ No newline at end of file
+/// The length of the whitespace at the start of `bytes`
+pub fn len(bytes: &[u8]) -> usize {
+    bytes.iter().position(|&b| !is_whitespace(b)).unwrap_or(bytes.len())
+}
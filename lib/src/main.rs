@@ -1,12 +1,58 @@
+use std::{process::ExitCode, thread};
+
 use clap::Parser;
-use lua::{ast::context::Ctx, builtins::prelude, lexer::Lexer, parser::parse};
+use lua::{ast::context::Ctx, builtins::prelude, lexer::identifier::Identifier, parser::parse, value::{table::Table, Value}};
+
+/// How much stack to allow for each Lua call, as every call nests several Rust calls
+const STACK_PER_CALL: usize = 64 * 1024;
+/// The smallest and largest stacks the interpreter thread gets, whatever the maximum call depth
+/// Calls deeper than the thread's stack allows grow the stack as they go
+const MIN_STACK: usize = 8 * 1024 * 1024;
+const MAX_STACK: usize = 256 * 1024 * 1024;
 
-fn main() {
+fn main() -> ExitCode {
     let cli = cmd::Cli::parse();
+    // the chunk runs on a thread with room for the deepest calls allowed
+    let max_depth = cli.max_depth.unwrap_or(Ctx::MAX_DEPTH);
+    let interpreter = thread::Builder::new()
+        .stack_size(max_depth.saturating_mul(STACK_PER_CALL).clamp(MIN_STACK, MAX_STACK))
+        .spawn(move || run(&cli, max_depth));
+    match interpreter {
+        Ok(interpreter) => interpreter.join().unwrap_or(ExitCode::FAILURE),
+        Err(err) => {
+            eprintln!("lua: couldn't start the interpreter: {err}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn run(cli: &cmd::Cli, max_depth: usize) -> ExitCode {
     if let Some(source) = cli.read() {
-        let block = parse(&source).unwrap();
+        let chunk_name = cli.path().map(|p| p.display().to_string()).unwrap_or_default();
+        let block = match parse(&source, &chunk_name) {
+            Ok(block) => block,
+            Err(errors) => {
+                for err in errors {
+                    eprintln!("lua: {err}");
+                }
+                return ExitCode::FAILURE;
+            }
+        };
         let mut context = Ctx::new();
+        context.set_max_depth(max_depth);
         prelude(&mut context);
-        block.walk(&mut context);
+        // the script's arguments are the main chunk's varargs, and are also in the `arg` table with the script at index 0
+        context.set_varargs(cli.script_args().iter().map(|a| Value::String(a.clone().into())).collect());
+        let arg = Table::new();
+        for (idx, a) in cli.args() {
+            arg.borrow_mut().insert(&Value::Integer(idx), Value::String(a.into()));
+        }
+        context.new_global(Identifier::from("arg"), Value::Table(arg));
+        if let Err(err) = block.walk(&mut context) {
+            eprintln!("lua: {err}");
+            eprintln!("{}", context.traceback(0));
+            return ExitCode::FAILURE;
+        }
     }
+    ExitCode::SUCCESS
 }
@@ -13,6 +13,7 @@
             kw_while <exp> kw_do <block> kw_end |
             kw_repeat <block> kw_until <exp> |
             kw_if <exp> kw_then <block> {kw_elseif <exp> kw_then <block>} [kw_else <block>] kw_end |
+            kw_break |
             kw_goto <Name> |
             <label> |
             kw_for <Name> '=' <exp> ',' <exp> [',' <exp>] kw_do <block> kw_end |
@@ -26,7 +27,7 @@
             kw_nil | kw_false | kw_true |
             <Numeral> |
             <LiteralString> |
-            <functiondef> | // ???
+            <functiondef> |
             <tableconstructor> |
             '...' |
             <exp> <binop> <exp> |
@@ -34,8 +35,7 @@
 
     prefixexp ::= <var> | <functioncall> | '(' <exp> ')'
 
-    laststat ::=  kw_break |
-                  kw_return [<explist>] [';']
+    laststat ::= kw_return [<explist>] [';']
 
     varlist ::= <var> {',' <var>}
 
@@ -85,20 +85,145 @@
     unop ::= TODO
 */
 
-use crate::{ast::*, lexer::Lexer};
+use std::ops::{Deref, DerefMut};
 
-pub fn parse(mut source: &str) -> Option<Block> {
-    // do other things?
-    if &source[..2] == "#!" {
-        // get rid of of shebang
-        let nextl = source.find('\n').expect("source file with shebang should be non-empty");
-        source = &source[nextl + 1..]; 
+use crate::{ast::{statement::parse_statement, *}, lexer::{identifier::Identifier, keyword::Keyword, Lexeme, Lexer}};
+
+use error::ParseError;
+
+pub mod error;
+
+#[cfg(test)]
+mod tests;
+
+/// A lexer, along with what the parser knows about the scope it's parsing
+/// The lexer's methods are called through the parser
+pub struct Parser<'a> {
+    lex: Lexer<'a>,
+    /// Whether the function being parsed takes varargs
+    vararg: bool,
+    /// The locals in scope where the parser is, and whether each is read-only (`<const>` or `<close>`)
+    locals: Vec<(Identifier, bool)>,
+}
+
+impl<'a> Parser<'a> {
+    /// Parse the lexemes of `lex` as a main chunk, which takes varargs
+    pub fn new(lex: Lexer<'a>) -> Parser<'a> {
+        Parser { lex, vararg: true, locals: Vec::new() }
+    }
+
+    /// Whether `...` can be used in the function being parsed
+    pub fn is_vararg(&self) -> bool {
+        self.vararg
     }
-    let mut lex = Lexer::new(source);
-    let block = Block::parse(&mut lex);
-    while let Some(lexeme) = lex.next() {
-        println!("remaining lexeme: {:?}", lexeme);
+
+    /// Set whether the function being parsed takes varargs, returning the previous setting
+    pub fn set_vararg(&mut self, vararg: bool) -> bool {
+        std::mem::replace(&mut self.vararg, vararg)
+    }
+
+    /// Declare a local in the scope being parsed
+    pub fn declare_local(&mut self, name: Identifier, read_only: bool) {
+        self.locals.push((name, read_only));
+    }
+
+    /// How many locals are in scope, to pass to `end_scope` when the current scope ends
+    pub fn scope_len(&self) -> usize {
+        self.locals.len()
+    }
+
+    /// Forget the locals declared since `scope_len` returned `len`
+    pub fn end_scope(&mut self, len: usize) {
+        self.locals.truncate(len);
+    }
+
+    /// Whether `name` refers to a local that can't be assigned to
+    pub fn is_read_only(&self, name: &Identifier) -> bool {
+        self.locals.iter().rev().find(|(local, _)| local == name).is_some_and(|(_, read_only)| *read_only)
     }
-    block
 }
 
+impl<'a> Deref for Parser<'a> {
+    type Target = Lexer<'a>;
+
+    fn deref(&self) -> &Lexer<'a> {
+        &self.lex
+    }
+}
+
+impl<'a> DerefMut for Parser<'a> {
+    fn deref_mut(&mut self) -> &mut Lexer<'a> {
+        &mut self.lex
+    }
+}
+
+/// Parse a chunk of source code
+/// `chunk_name` is used as the file name in the spans of the resulting tree
+/// Parsing continues after a syntax error, so that every error in the chunk can be reported at once
+pub fn parse(source: &str, chunk_name: &str) -> Result<Block, Vec<ParseError>> {
+    let mut lex = Parser::new(Lexer::with_file(source, chunk_name));
+    // get rid of of shebang
+    lex.skip_shebang();
+
+    let mut block = Block::empty();
+    let mut errors = Vec::new();
+    loop {
+        match parse_statement(&mut lex) {
+            Ok(Some(st)) => {
+                let is_return = matches!(st.kind(), statement::StatementKind::Return(_));
+                block.push_statement(st);
+                // return has to be the last statement in the chunk
+                if is_return && (lex.peek().is_some() || lex.peek_error().is_some()) {
+                    let err = ParseError::expected("'<eof>'", &mut lex);
+                    synchronize(&mut lex, err.span().line());
+                    errors.push(err);
+                }
+            },
+            Ok(None) => {
+                if lex.peek().is_none() && lex.peek_error().is_none() { break; }
+                let err = if is_block_end(lex.peek_lexeme()) {
+                    ParseError::expected("'<eof>'", &mut lex)
+                } else { ParseError::unexpected(&mut lex) };
+                synchronize(&mut lex, err.span().line());
+                errors.push(err);
+            },
+            Err(err) => {
+                synchronize(&mut lex, err.span().line());
+                errors.push(err);
+            }
+        }
+    }
+
+    if errors.is_empty() && let Err(err) = statement::check_jumps(&block) {
+        errors.push(err);
+    }
+    if errors.is_empty() { Ok(block) } else { Err(errors) }
+}
+
+/// Whether `lexeme` closes a block (or is the end of the input)
+pub fn is_block_end(lexeme: Option<Lexeme>) -> bool {
+    matches!(lexeme, None | Some(Lexeme::Keyword(Keyword::End | Keyword::Else | Keyword::Elseif | Keyword::Until)))
+}
+
+/// Skip ahead to the first statement keyword of the chunk's outermost block on a line after `line`,
+/// so that parsing can resume after an error, even one in a nested block
+fn synchronize(lex: &mut Parser, line: u32) {
+    // always make progress
+    if lex.next().is_none() { lex.skip_invalid(); }
+    loop {
+        match lex.peek().map(|tok| (tok.span.line(), tok.lexeme)) {
+            Some((tok_line, lexeme)) => {
+                if lex.block_depth() == 0 && tok_line > line && matches!(lexeme, Lexeme::Keyword(
+                    Keyword::Local | Keyword::Function | Keyword::If | Keyword::While | Keyword::For 
+                    | Keyword::Repeat | Keyword::Return | Keyword::Do
+                )) { return; }
+                lex.next();
+            },
+            None if lex.peek_error().is_some() => {
+                lex.next();
+                lex.skip_invalid();
+            },
+            None => return,
+        }
+    }
+}
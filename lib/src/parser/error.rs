@@ -0,0 +1,115 @@
+//! Errors produced while lexing and parsing source code
+
+use std::fmt::Display;
+
+use crate::lexer::{span::Span, Lexer};
+
+/// A syntax error, located at the lexeme the parser couldn't make sense of
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParseError {
+    span: Span,
+    message: String,
+    /// What the parser wanted to see, e.g. `'end'` or `<name>`
+    expected: Option<String>,
+    near: Near,
+}
+
+/// Where a syntax error was found
+#[derive(Clone, PartialEq, Debug)]
+enum Near {
+    /// The source text of the offending lexeme
+    Lexeme(String),
+    Eof,
+    /// Semantic errors don't point at a lexeme
+    Nothing,
+}
+
+impl Near {
+    fn from_found(found: Option<String>) -> Near {
+        found.map_or(Near::Eof, Near::Lexeme)
+    }
+}
+
+impl ParseError {
+    pub fn new(message: impl Into<String>, span: Span, found: Option<String>) -> ParseError {
+        ParseError { span, message: message.into(), expected: None, near: Near::from_found(found) }
+    }
+
+    /// An error at the next lexeme in `lex`
+    /// If the lexer can't read that lexeme at all, the lexing error is returned instead
+    fn at_next(message: String, expected: Option<String>, lex: &mut Lexer) -> ParseError {
+        if let Some(err) = lex.peek_error() {
+            return err;
+        }
+        let (span, found) = lex.near();
+        ParseError { span, message, expected, near: Near::from_found(found.map(|s| s.to_string())) }
+    }
+
+    /// The next lexeme in `lex` can't be used here, as explained by `message`
+    pub fn at_next_lexeme(message: &str, lex: &mut Lexer) -> ParseError {
+        Self::at_next(message.to_string(), None, lex)
+    }
+
+    /// `what` was expected, but the next lexeme in `lex` was found instead
+    pub fn expected(what: &str, lex: &mut Lexer) -> ParseError {
+        Self::at_next(format!("{what} expected"), Some(what.to_string()), lex)
+    }
+
+    /// Like `expected`, but notes which construct opened at `opener` is left unclosed
+    pub fn expected_closing(what: &str, opener: &str, opener_span: &Span, lex: &mut Lexer) -> ParseError {
+        let (span, _) = lex.near();
+        if span.line() == opener_span.line() {
+            return Self::expected(what, lex);
+        }
+        let message = format!("{what} expected (to close {opener} at line {})", opener_span.line());
+        Self::at_next(message, Some(what.to_string()), lex)
+    }
+
+    /// An error in a syntactically valid construct at `span`, such as a `goto` without a label
+    pub fn semantic(message: impl Into<String>, span: Span) -> ParseError {
+        ParseError { span, message: message.into(), expected: None, near: Near::Nothing }
+    }
+
+    /// The next lexeme in `lex` can't appear here
+    pub fn unexpected(lex: &mut Lexer) -> ParseError {
+        Self::at_next("unexpected symbol".to_string(), None, lex)
+    }
+
+    /// The statement before the next lexeme in `lex` isn't complete
+    pub fn syntax_error(lex: &mut Lexer) -> ParseError {
+        Self::at_next("syntax error".to_string(), None, lex)
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn expected_token(&self) -> Option<&str> {
+        self.expected.as_deref()
+    }
+
+    /// The source text of the offending lexeme, or `None` at the end of the input
+    pub fn found(&self) -> Option<&str> {
+        match &self.near {
+            Near::Lexeme(found) => Some(found),
+            Near::Eof | Near::Nothing => None,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.span.file, self.span.line(), self.message)?;
+        match &self.near {
+            Near::Lexeme(found) => write!(f, " near '{found}'"),
+            Near::Eof => write!(f, " near <eof>"),
+            Near::Nothing => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
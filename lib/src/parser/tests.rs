@@ -0,0 +1,162 @@
+// test syntax error reporting
+
+use super::parse;
+
+fn errors(s: &str) -> Vec<String> {
+    match parse(s, "test.lua") {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+#[test]
+fn valid_chunk() {
+    assert!(parse("local x = 1\nfunction f(a, b) return a end\nf(x, 2)", "test.lua").is_ok());
+}
+
+#[test]
+fn missing_end() {
+    assert_eq!(errors("if x then\n  f(1)\n"), vec!["test.lua:3: 'end' expected (to close 'if' at line 1) near <eof>"]);
+    assert_eq!(errors("do f(1)"), vec!["test.lua:1: 'end' expected near <eof>"]);
+}
+
+#[test]
+fn missing_then() {
+    assert_eq!(errors("if x f(1) end"), vec!["test.lua:1: 'then' expected near 'f'"]);
+}
+
+#[test]
+fn unexpected_symbol() {
+    assert_eq!(errors("x = = 2"), vec!["test.lua:1: unexpected symbol near '='"]);
+    assert_eq!(errors("x = 3 @"), vec!["test.lua:1: unexpected symbol near '@'"]);
+    assert_eq!(errors("end"), vec!["test.lua:1: '<eof>' expected near 'end'"]);
+}
+
+#[test]
+fn unfinished_string() {
+    assert_eq!(errors("x = 'abc\ny = 2"), vec!["test.lua:1: unfinished string near ''abc'"]);
+}
+
+#[test]
+fn error_fields() {
+    let err = parse("f(a b)", "test.lua").err().unwrap().remove(0);
+    assert_eq!(err.expected_token(), Some("')'"));
+    assert_eq!(err.found(), Some("b"));
+    assert_eq!((err.span().line(), err.span().column()), (1, 5));
+}
+
+#[test]
+fn reports_every_error() {
+    let errs = errors("f(\ny = 2\nif x then\n  local a =\nend\nreturn 1 f()");
+    assert_eq!(errs, vec![
+        "test.lua:2: ')' expected near '='",
+        "test.lua:5: unexpected symbol near 'end'",
+        "test.lua:6: '<eof>' expected near 'f'",
+    ]);
+    // an error in a nested block resumes after the block, not at the next statement inside it
+    assert_eq!(errors("function f()\n  local y = x +\n  local z = 1\n  return z\nend\nx = 1"), vec!["test.lua:3: unexpected symbol near 'local'"]);
+    let errs = errors("if a then\n  while b do\n    c = = 1\n  end\n  d()\nend\nlocal e = = 2");
+    assert_eq!(errs, vec!["test.lua:3: unexpected symbol near '='", "test.lua:7: unexpected symbol near '='"]);
+}
+
+#[test]
+fn prefix_expressions() {
+    assert!(parse("f()(1)\nt[1]:m(2)\n(f)(x)\nx = a.b[c](d).e", "test.lua").is_ok());
+    assert_eq!(errors("x = (1 + 2"), vec!["test.lua:1: ')' expected near <eof>"]);
+    assert_eq!(errors("x"), vec!["test.lua:1: syntax error near <eof>"]);
+    assert_eq!(errors("(x) = 1"), vec!["test.lua:1: syntax error near '='"]);
+}
+
+#[test]
+fn loops() {
+    assert!(parse("while x do f() end\nrepeat local y = g() until y", "test.lua").is_ok());
+    assert_eq!(errors("while x f() end"), vec!["test.lua:1: 'do' expected near 'f'"]);
+    assert_eq!(errors("repeat\nf()\n"), vec!["test.lua:3: 'until' expected (to close 'repeat' at line 1) near <eof>"]);
+}
+
+#[test]
+fn for_loops() {
+    assert!(parse("for i = 1, 10 do f(i) end\nfor i = 10, 1, -1 do end\nfor k, v in pairs(t) do end", "test.lua").is_ok());
+    assert_eq!(errors("for i do end"), vec!["test.lua:1: '=' or 'in' expected near 'do'"]);
+    assert_eq!(errors("for i = 1 do end"), vec!["test.lua:1: ',' expected near 'do'"]);
+    assert_eq!(errors("for a, b = 1, 2 do end"), vec!["test.lua:1: 'in' expected near '='"]);
+    assert_eq!(errors("for i = 1, 2\nf()"), vec!["test.lua:2: 'do' expected near 'f'"]);
+    assert_eq!(errors("for k in t do\n"), vec!["test.lua:2: 'end' expected (to close 'for' at line 1) near <eof>"]);
+}
+
+#[test]
+fn labels() {
+    assert!(parse("goto a\n::a::\ndo goto a end\nwhile x do break end", "test.lua").is_ok());
+    assert_eq!(errors("break"), vec!["test.lua:1: break outside a loop at line 1"]);
+    assert_eq!(errors("do\n  goto a\nend\ndo ::a:: end"), vec!["test.lua:2: no visible label 'a' for <goto> at line 2"]);
+    assert_eq!(errors("::a::\ndo\n  ::a::\nend"), vec!["test.lua:3: label 'a' already defined on line 1"]);
+    assert_eq!(errors("function f() goto a end\n::a::"), vec!["test.lua:1: no visible label 'a' for <goto> at line 1"]);
+    assert_eq!(errors("goto a\nlocal x\n::a::\nf(x)"), vec!["test.lua:1: <goto a> at line 1 jumps into the scope of local 'x'"]);
+    // a label at the end of a block is outside the scope of its locals
+    assert!(parse("while x do\n  goto continue\n  local y = 1\n  ::continue::\nend", "test.lua").is_ok());
+    // but the locals of a repeat body are still in scope in its condition
+    assert_eq!(errors("repeat goto cont; local x; ::cont:: until x"), vec!["test.lua:1: <goto cont> at line 1 jumps into the scope of local 'x'"]);
+    assert_eq!(errors(":: a"), vec!["test.lua:1: '::' expected near <eof>"]);
+}
+
+#[test]
+fn function_definitions() {
+    assert!(parse("function a.b.c:m(x) end\nlocal function f() end\nx = function(a, b) return a end", "test.lua").is_ok());
+    assert_eq!(errors("function a.(x) end"), vec!["test.lua:1: <name> expected near '('"]);
+    assert_eq!(errors("local function a.b() end"), vec!["test.lua:1: '(' expected near '.'"]);
+    assert_eq!(errors("x = function() return 1\n"), vec!["test.lua:2: 'end' expected (to close 'function' at line 1) near <eof>"]);
+}
+
+#[test]
+fn varargs() {
+    assert!(parse("local a, b = ...
+function f(x, ...) return ... end
+function g(...) end", "test.lua").is_ok());
+    assert_eq!(errors("function f() return ... end"), vec!["test.lua:1: cannot use '...' outside a vararg function near '...'"]);
+    assert_eq!(errors("function f(...)
+  return function() return ... end
+end"), vec!["test.lua:2: cannot use '...' outside a vararg function near '...'"]);
+    assert_eq!(errors("function f(..., x) end"), vec!["test.lua:1: ')' expected near ','"]);
+    assert_eq!(errors("function f(x,) end"), vec!["test.lua:1: <name> expected near ')'"]);
+}
+
+#[test]
+fn assignments() {
+    assert!(parse("a.b[c].d, x, t[1] = 1, 2, 3\nf().x = 1\n(t).y = 2", "test.lua").is_ok());
+    assert_eq!(errors("f() = 1"), vec!["test.lua:1: syntax error near '='"]);
+    assert_eq!(errors("a, f() = 1"), vec!["test.lua:1: syntax error near '='"]);
+    assert_eq!(errors("a, b"), vec!["test.lua:1: '=' expected near <eof>"]);
+    assert_eq!(errors("a.b"), vec!["test.lua:1: syntax error near <eof>"]);
+    assert_eq!(errors("a, 1 = 2"), vec!["test.lua:1: syntax error near '1'"]);
+}
+
+#[test]
+fn call_arguments() {
+    assert!(parse("require 'x'\nf{1, 2}\nf[[long]]\na.b:c 'x'\nobj:m{}\nf()()\nt[i](x)", "test.lua").is_ok());
+    assert_eq!(errors("a:m"), vec!["test.lua:1: function arguments expected near <eof>"]);
+    assert_eq!(errors("a:m + 1"), vec!["test.lua:1: function arguments expected near '+'"]);
+}
+
+#[test]
+fn local_attributes() {
+    assert!(parse("local a <const>, b <close>, c = 1\nlocal x = a < b", "test.lua").is_ok());
+    assert_eq!(errors("local x <const> = 1\nx = 2"), vec!["test.lua:2: attempt to assign to const variable 'x'"]);
+    assert_eq!(errors("local f <close>\nfunction g() f, y = 1 end"), vec!["test.lua:2: attempt to assign to const variable 'f'"]);
+    assert_eq!(errors("local f <const> = 1\nfunction f() end"), vec!["test.lua:2: attempt to assign to const variable 'f'"]);
+    assert_eq!(errors("local x <mutable> = 1"), vec!["test.lua:1: unknown attribute 'mutable'"]);
+    assert_eq!(errors("local a <close>, b <close>"), vec!["test.lua:1: multiple to-be-closed variables in local list"]);
+    assert_eq!(errors("local x <const = 1"), vec!["test.lua:1: '>' expected near '='"]);
+    // shadowing locals and locals that went out of scope can be assigned to
+    let src = "local x <const> = 1\ndo local x = 2 x = 3 end\nfor x = 1, 2 do x = 0 end\nfunction f(x) x = 1 end\n\
+        do local y <const> = 1 end\ny = 2\nlocal z <const> = z\nz = 1";
+    assert_eq!(errors(src), vec!["test.lua:8: attempt to assign to const variable 'z'"]);
+}
+
+#[test]
+fn table_constructors() {
+    assert!(parse("t = {}\nt = {1; 2, a = 3; [4] = 5;}\nt = {x, y = y, [z] = {}}", "test.lua").is_ok());
+    assert_eq!(errors("t = {1 2}"), vec!["test.lua:1: '}' expected near '2'"]);
+    assert_eq!(errors("t = {\n  a = 1,\n  b = 2\n  c = 3\n}"), vec!["test.lua:4: '}' expected (to close '{' at line 1) near 'c'"]);
+    assert_eq!(errors("t = {1,,}"), vec!["test.lua:1: unexpected symbol near ','"]);
+    assert_eq!(errors("t = {[1] 2}"), vec!["test.lua:1: '=' expected near '2'"]);
+}
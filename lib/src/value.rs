@@ -1,22 +1,26 @@
 use std::{cell::RefCell, fmt::Debug, hash::Hash, rc::Rc};
 
-use crate::{ast::{function::{Function, LuaFunction}, Block}, value::table::Table};
+use crate::{ast::function::Function, value::{number::Number, string::LuaString, table::Table}};
 
 pub mod meta;
+pub mod number;
+pub mod string;
 pub mod table;
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Clone)]
 pub enum Value {
     Nil,
     Boolean(Boolean),
-    Number(f64),
-    String(String),
+    Integer(i64),
+    Float(f64),
+    String(LuaString),
     Userdata,
     Function(Rc<Function>),
     Thread,
     Table(Rc<RefCell<Table>>),
-    // fixme?
-    RetVals(Vec<Value>),
 }
 
 impl Value {
@@ -24,52 +28,59 @@ impl Value {
         match self {
             Value::Nil => "Nil",
             Value::Boolean(_) => "Boolean",
-            Value::Number(_) => "Number",
+            Value::Integer(_) => "Integer",
+            Value::Float(_) => "Float",
             Value::String(_) => "String",
             Value::Userdata => "Userdata",
             Value::Function(_) => "Function",
             Value::Thread => "Thread",
             Value::Table(_) => "Table",
-            Value::RetVals(_) => "Multiple return values"
         }
     }
-    pub fn as_number(&self) -> Option<f64> {
+    /// The name of the value's type, as `type` gives it
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::Number(n) => Some(*n),
-            Value::String(s) => s.trim().parse().ok(),
-            Value::RetVals(rv) => rv.first().and_then(|v| v.as_number()),
-            _ => None
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Float(_) => "number",
+            Value::String(_) => "string",
+            Value::Userdata => "userdata",
+            Value::Function(_) => "function",
+            Value::Thread => "thread",
+            Value::Table(_) => "table",
         }
     }
 
-    pub fn as_bool(&self) -> bool {
+    /// The number this value converts to in arithmetic, where strings are converted to numbers
+    pub fn as_number(&self) -> Option<Number> {
         match self {
-            Value::Nil | Value::Boolean(Boolean::False) => false,
-            _ => true,
+            Value::Integer(i) => Some(Number::Integer(*i)),
+            Value::Float(f) => Some(Number::Float(*f)),
+            Value::String(s) => s.to_str().and_then(|s| s.parse().ok()),
+            _ => None
         }
     }
 
-    pub fn as_string(&self) -> Option<String> {
+    pub fn as_bool(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(Boolean::False))
+    }
+
+    pub fn as_string(&self) -> Option<LuaString> {
         match self {
             Value::String(s) => Some(s.clone()),
-            Value::Number(n) => Some(n.to_string()),
+            Value::Integer(_) | Value::Float(_) => self.as_number().map(|n| n.to_string().into()),
             _ => None,
         }
     }
 }
 
-pub fn flatten_values(vals: Vec<Value>) -> Vec<Value> {
-    let mut flat = Vec::with_capacity(vals.len());
-    for val in vals {
-        match val {
-            Value::RetVals(rv) => {
-                let mut flat_rv = flatten_values(rv);
-                flat.append(&mut flat_rv);
-            },
-            _ => flat.push(val),
+impl From<Number> for Value {
+    fn from(value: Number) -> Self {
+        match value {
+            Number::Integer(i) => Value::Integer(i),
+            Number::Float(f) => Value::Float(f),
         }
     }
-    flat
 }
 
 impl Debug for Value {
@@ -78,13 +89,13 @@ impl Debug for Value {
         match self {
             Value::Nil => write!(f, "Nil"),
             Value::Boolean(b) => write!(f, "Bool( {b:?} )"),
-            Value::Number(n) => write!(f, "Number( {n} )"),
+            Value::Integer(i) => write!(f, "Integer( {i} )"),
+            Value::Float(n) => write!(f, "Float( {n} )"),
             Value::String(s) => write!(f, "String( {s} )"),
             Value::Userdata => write!(f, "Userdata"),
-            Value::Function(func) => write!(f, "Function", ),
+            Value::Function(_) => write!(f, "Function"),
             Value::Thread => write!(f, "Thread"),
             Value::Table(_) => write!(f, "Table"),
-            Value::RetVals(rv) => write!(f, "Return values: {rv:?}")
         }?;
         write!(f, " ]")
     }
@@ -95,13 +106,11 @@ impl PartialEq for Value {
         match (self, other) {
             (Value::Nil, Value::Nil) => true,
             (Value::Boolean(b1), Value::Boolean(b2)) => b1 == b2,
-            (Value::Number(n1), Value::Number(n2)) => n1 == n2,
+            (Value::Integer(_) | Value::Float(_), Value::Integer(_) | Value::Float(_)) => self.as_number() == other.as_number(),
             (Value::String(s1), Value::String(s2)) => s1 == s2,
-            (Value::Userdata, Value::Userdata) => todo!(),
             (Value::Function(f1), Value::Function(f2)) => Rc::ptr_eq(f1, f2),
-            (Value::Thread, Value::Thread) => todo!(),
+            // FIXME: userdata and threads don't have an identity yet, so no two of them are equal
             (Value::Table(t1), Value::Table(t2)) => Rc::ptr_eq(t1, t2),
-            (Value::RetVals(rv1), Value::RetVals(rv2)) => rv1 == rv2,
             _ => false,
         }
     }
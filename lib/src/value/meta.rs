@@ -0,0 +1,25 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{ast::{context::Ctx, error::LuaError, function::call_value}, value::{table::Table, Value}};
+
+/// The metatable of `val`: tables have their own, and all strings share one
+pub fn get_metatable(val: &Value, ctx: &Ctx) -> Option<Rc<RefCell<Table>>> {
+    match val {
+        Value::Table(t) => t.borrow().metatable(),
+        Value::String(_) => ctx.string_metatable(),
+        _ => None,
+    }
+}
+
+/// The metamethod of `val` for `event` (such as `"__close"`), if it has one
+pub fn metamethod(val: &Value, event: &str, ctx: &Ctx) -> Option<Value> {
+    let metatable = get_metatable(val, ctx)?;
+    metatable.borrow().get(&Value::String(event.into()))
+}
+
+/// Call the metamethod `event` of the first operand that has one with both operands, as binary operators do
+/// Returns the first result, or `None` if neither operand has the metamethod
+pub fn binary_metamethod(event: &str, lhs: &Value, rhs: &Value, ctx: &mut Ctx) -> Result<Option<Value>, LuaError> {
+    let Some(method) = metamethod(lhs, event, ctx).or_else(|| metamethod(rhs, event, ctx)) else { return Ok(None) };
+    Ok(Some(call_value(&method, vec![lhs.clone(), rhs.clone()], ctx)?.into_iter().next().unwrap_or(Value::Nil)))
+}
@@ -0,0 +1,282 @@
+//! Lua numbers are either 64 bit integers or double precision floats
+//! Arithmetic follows the Lua 5.4 rules: integers are kept when both operands are integers,
+//! and mixed operations are done on floats
+
+use std::{cmp::Ordering, fmt::Display, ops::{Add, Div, Mul, Neg, Sub}, str::FromStr};
+
+use crate::lexer::literal::str_to_number;
+
+/// 2^63, the first float too large to convert to an integer
+const TWO_POW_63: f64 = 9_223_372_036_854_775_808.0;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// The name `math.type` gives to this kind of number
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Number::Integer(_) => "integer",
+            Number::Float(_) => "float",
+        }
+    }
+
+    pub fn as_float(self) -> f64 {
+        match self {
+            Number::Integer(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    /// The integer with the same value, if there is one
+    /// Floats are only converted if they have an exact integer representation
+    pub fn as_integer(self) -> Option<i64> {
+        match self {
+            Number::Integer(i) => Some(i),
+            Number::Float(f) => float_to_integer(f),
+        }
+    }
+
+    /// `^` always produces a float
+    pub fn pow(self, other: Number) -> Number {
+        Number::Float(self.as_float().powf(other.as_float()))
+    }
+
+    /// `//`, rounding the quotient towards minus infinity
+    /// Returns `None` for an integer division by zero
+    pub fn floor_div(self, other: Number) -> Option<Number> {
+        match (self, other) {
+            (Number::Integer(_), Number::Integer(0)) => None,
+            (Number::Integer(a), Number::Integer(b)) => {
+                let q = a.wrapping_div(b);
+                // truncation rounded towards zero, so correct negative quotients
+                Some(Number::Integer(if a.wrapping_rem(b) != 0 && (a ^ b) < 0 { q - 1 } else { q }))
+            },
+            (a, b) => Some(Number::Float((a.as_float() / b.as_float()).floor())),
+        }
+    }
+
+    /// `%`, where the result has the sign of the divisor
+    /// Returns `None` for an integer modulo by zero
+    pub fn modulo(self, other: Number) -> Option<Number> {
+        match (self, other) {
+            (Number::Integer(_), Number::Integer(0)) => None,
+            (Number::Integer(a), Number::Integer(b)) => {
+                let r = a.wrapping_rem(b);
+                Some(Number::Integer(if r != 0 && (r ^ b) < 0 { r + b } else { r }))
+            },
+            (a, b) => {
+                let (a, b) = (a.as_float(), b.as_float());
+                let m = a % b;
+                let needs_adjust = if m > 0.0 { b < 0.0 } else { m < 0.0 && b != m };
+                Some(Number::Float(if needs_adjust { m + b } else { m }))
+            }
+        }
+    }
+}
+
+/// Converts `f` to an integer if it has an exact integer representation
+pub fn float_to_integer(f: f64) -> Option<i64> {
+    (f.fract() == 0.0 && (-TWO_POW_63..TWO_POW_63).contains(&f)).then_some(f as i64)
+}
+
+/// `x << y` as Lua defines it: negative shifts go the other way, and shifting 64 or more bits gives 0
+pub fn shift_left(x: i64, y: i64) -> i64 {
+    if y <= -64 || y >= 64 {
+        0
+    } else if y < 0 {
+        ((x as u64) >> -y) as i64
+    } else {
+        ((x as u64) << y) as i64
+    }
+}
+
+/// `i < f`
+fn int_lt_float(i: i64, f: f64) -> bool {
+    if f.is_nan() {
+        false
+    } else if f >= TWO_POW_63 {
+        true
+    } else {
+        // the cast saturates for floats below the integer range, which gives the right answer
+        i < f.ceil() as i64
+    }
+}
+
+/// `i <= f`
+fn int_le_float(i: i64, f: f64) -> bool {
+    if f.is_nan() || f < -TWO_POW_63 {
+        false
+    } else if f >= TWO_POW_63 {
+        true
+    } else {
+        i <= f.floor() as i64
+    }
+}
+
+impl FromStr for Number {
+    type Err = ();
+
+    /// Convert a string to a number, the way Lua does for arithmetic on strings and `tonumber`
+    /// Surrounding whitespace and a leading sign are allowed
+    fn from_str(s: &str) -> Result<Number, ()> {
+        let s = s.trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0B');
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let n = str_to_number(digits).ok_or(())?;
+        Ok(if negative { -n } else { n })
+    }
+}
+
+impl Add for Number {
+    type Output = Number;
+
+    fn add(self, other: Number) -> Number {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => Number::Integer(a.wrapping_add(b)),
+            (a, b) => Number::Float(a.as_float() + b.as_float()),
+        }
+    }
+}
+
+impl Sub for Number {
+    type Output = Number;
+
+    fn sub(self, other: Number) -> Number {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => Number::Integer(a.wrapping_sub(b)),
+            (a, b) => Number::Float(a.as_float() - b.as_float()),
+        }
+    }
+}
+
+impl Mul for Number {
+    type Output = Number;
+
+    fn mul(self, other: Number) -> Number {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => Number::Integer(a.wrapping_mul(b)),
+            (a, b) => Number::Float(a.as_float() * b.as_float()),
+        }
+    }
+}
+
+/// `/` always produces a float
+impl Div for Number {
+    type Output = Number;
+
+    fn div(self, other: Number) -> Number {
+        Number::Float(self.as_float() / other.as_float())
+    }
+}
+
+impl Neg for Number {
+    type Output = Number;
+
+    fn neg(self) -> Number {
+        match self {
+            Number::Integer(i) => Number::Integer(i.wrapping_neg()),
+            Number::Float(f) => Number::Float(-f),
+        }
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        match (*self, *other) {
+            (Number::Integer(a), Number::Integer(b)) => a == b,
+            (Number::Float(a), Number::Float(b)) => a == b,
+            (Number::Integer(i), Number::Float(f)) | (Number::Float(f), Number::Integer(i)) => {
+                float_to_integer(f) == Some(i)
+            }
+        }
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (*self, *other) {
+            (Number::Integer(a), Number::Integer(b)) => Some(a.cmp(&b)),
+            (Number::Float(a), Number::Float(b)) => a.partial_cmp(&b),
+            (Number::Integer(i), Number::Float(f)) => int_cmp_float(i, f),
+            (Number::Float(f), Number::Integer(i)) => int_cmp_float(i, f).map(Ordering::reverse),
+        }
+    }
+}
+
+/// Compares `i` and `f` exactly, rather than by converting `i` to a float
+fn int_cmp_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if int_lt_float(i, f) {
+        Some(Ordering::Less)
+    } else if int_le_float(i, f) {
+        Some(Ordering::Equal)
+    } else {
+        Some(Ordering::Greater)
+    }
+}
+
+impl From<i64> for Number {
+    fn from(value: i64) -> Self {
+        Number::Integer(value)
+    }
+}
+
+impl From<f64> for Number {
+    fn from(value: f64) -> Self {
+        Number::Float(value)
+    }
+}
+
+/// Format `f` like C's `%.{precision}g`
+pub fn format_g(f: f64, precision: usize) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let precision = precision.max(1);
+    // the exponent has to be taken after rounding to the precision
+    let scientific = format!("{:.*e}", precision - 1, f);
+    let (mantissa, exp) = scientific.split_once('e').expect("Scientific format always has an exponent");
+    let exp: i32 = exp.parse().expect("Scientific format exponent wasn't an integer");
+    if exp < -4 || exp >= precision as i32 {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{sign}{:02}", trim_fraction_zeros(mantissa), exp.abs())
+    } else {
+        let fixed = format!("{:.*}", (precision as i32 - 1 - exp) as usize, f);
+        trim_fraction_zeros(&fixed).to_string()
+    }
+}
+
+/// Remove trailing zeros after a decimal point, and the point itself if nothing is left after it
+fn trim_fraction_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else { s }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Number::Integer(i) => write!(f, "{i}"),
+            Number::Float(n) => {
+                let s = format_g(n, 14);
+                // floats that look like integers get a ".0" so they can be told apart
+                if s.bytes().all(|c| c.is_ascii_digit() || c == b'-') {
+                    write!(f, "{s}.0")
+                } else {
+                    write!(f, "{s}")
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Lua strings are immutable sequences of bytes, which don't have to be valid UTF-8
+
+use std::{borrow::Cow, fmt::{Debug, Display}, rc::Rc};
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The string as text, with invalid UTF-8 replaced
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// The string as text, if it is valid UTF-8
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(value: &[u8]) -> Self {
+        LuaString(Rc::from(value))
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(value: Vec<u8>) -> Self {
+        LuaString(Rc::from(value))
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(value: &str) -> Self {
+        LuaString::from(value.as_bytes())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(value: String) -> Self {
+        LuaString::from(value.into_bytes())
+    }
+}
+
+impl Display for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
+    }
+}
+
+impl Debug for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_str_lossy())
+    }
+}
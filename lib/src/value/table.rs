@@ -1,6 +1,8 @@
-use std::{cell::RefCell, collections::HashMap, fmt::Display, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
-use crate::{ast::{context::Ctx, expression::{parse_expression, Expression}}, lexer::{assignment::Assignment, identifier::Identifier, seperator::Seperator, Lexeme, Lexer}, value::Value};
+use indexmap::IndexMap;
+
+use crate::{ast::{context::Ctx, error::LuaError, expression::{expect_expression, Expression}, function::call_value}, lexer::{assignment::Assignment, identifier::Identifier, seperator::Seperator, span::Span, Lexeme}, parser::{error::ParseError, Parser}, value::{meta::{get_metatable, metamethod}, number::float_to_integer, Value}};
 
 #[derive(Clone, PartialEq)]
 struct ValWrapper {
@@ -13,9 +15,9 @@ impl From<Value> for ValWrapper {
     }
 }
 
-impl Into<Value> for ValWrapper {
-    fn into(self) -> Value {
-        self.inner
+impl From<ValWrapper> for Value {
+    fn from(value: ValWrapper) -> Self {
+        value.inner
     }
 }
 
@@ -30,10 +32,20 @@ impl std::hash::Hash for ValWrapper {
                 state.write_u8(2);
                 b.hash(state);
             },
-            Value::Number(n) => {
+            Value::Integer(i) => {
                 state.write_u8(3);
-                if n.is_nan() { panic!("hashed value was nan") }
-                n.to_bits().hash(state);
+                i.hash(state);
+            },
+            Value::Float(n) => {
+                // floats equal to an integer have to hash the same way as that integer
+                if let Some(i) = float_to_integer(*n) {
+                    state.write_u8(3);
+                    i.hash(state);
+                } else {
+                    state.write_u8(10);
+                    if n.is_nan() { panic!("hashed value was nan") }
+                    n.to_bits().hash(state);
+                }
             },
             Value::String(s) => {
                 state.write_u8(4);
@@ -44,19 +56,14 @@ impl std::hash::Hash for ValWrapper {
             },
             Value::Function(f) => {
                 state.write_u8(6);
-                todo!()
+                Rc::as_ptr(f).hash(state);
             },
             Value::Thread => {
                 state.write_u8(7);
             },
             Value::Table(tb) => {
                 state.write_u8(8);
-                Rc::as_ptr(&tb).hash(state);
-            },
-            Value::RetVals(rv) => {
-                state.write_u8(9);
-                let rv_wrap = rv.iter().map(|v| ValWrapper::from(v.clone())).collect::<Vec<_>>();
-                rv_wrap.hash(state);
+                Rc::as_ptr(tb).hash(state);
             },
         }
     }
@@ -68,12 +75,28 @@ impl Eq for ValWrapper {}
 
 #[derive(Clone)]
 pub struct Table {
-    inner: HashMap<ValWrapper, Value>
+    /// The fields, in traversal order
+    /// A field set to nil stays as a nil tombstone, so it can still be passed to `next` while traversing
+    inner: IndexMap<ValWrapper, Value>,
+    /// How many of the entries are tombstones
+    tombstones: usize,
+    /// A border of the table, kept up to date as fields are set, so the length operator doesn't have to search for one
+    /// `t[1]` to `t[border]` are all non-nil, and `t[border + 1]` is nil
+    border: i64,
+    metatable: Option<Rc<RefCell<Table>>>,
 }
 
 impl Table {
     pub fn new() -> Rc<RefCell<Table>> {
-        Rc::new(RefCell::new(Table { inner: HashMap::new() }))
+        Rc::new(RefCell::new(Table { inner: IndexMap::new(), tombstones: 0, border: 0, metatable: None }))
+    }
+
+    pub fn metatable(&self) -> Option<Rc<RefCell<Table>>> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<Rc<RefCell<Table>>>) {
+        self.metatable = metatable;
     }
 
     pub fn insert(&mut self, key: &Value, val: Value) {
@@ -81,13 +104,40 @@ impl Table {
             panic!("key shouldnt be null!")
         }
 
-        if val == Value::Nil {
-            // FIXME: SUCKS, but we can fix
-            self.inner.remove(&ValWrapper::from(key.clone()));
+        // float keys with an integer value are stored as integers
+        let key = match key {
+            Value::Float(f) => float_to_integer(*f).map_or_else(|| key.clone(), Value::Integer),
+            _ => key.clone(),
+        };
+        if let Value::Integer(i) = key && val == Value::Nil && (1..=self.border).contains(&i) {
+            self.border = i - 1;
+        }
+        let extends_border = val != Value::Nil && self.border.checked_add(1).is_some_and(|next| key == Value::Integer(next));
+        let key = ValWrapper::from(key);
+        match self.inner.get_mut(&key) {
+            Some(slot) => {
+                match (*slot == Value::Nil, val == Value::Nil) {
+                    (false, true) => self.tombstones += 1,
+                    (true, false) => self.tombstones -= 1,
+                    _ => {},
+                }
+                *slot = val;
+            },
+            None if val == Value::Nil => {},
+            None => {
+                // new keys can't be added during a traversal, so this is when tombstones can go
+                if self.tombstones > self.inner.len() / 2 {
+                    self.inner.retain(|_, v| *v != Value::Nil);
+                    self.tombstones = 0;
+                }
+                self.inner.insert(key, val);
+            },
         }
-        else {
-            // FIXME
-            self.inner.insert(ValWrapper::from(key.clone()), val);
+        if extends_border {
+            self.border += 1;
+            while self.border < i64::MAX && self.get(&Value::Integer(self.border + 1)).is_some() {
+                self.border += 1;
+            }
         }
     }
 
@@ -96,67 +146,231 @@ impl Table {
             panic!("key shouldnt be null!")
         }
 
-        self.inner.get(&ValWrapper::from(key.clone())).map(|v| v.clone())
+        self.inner.get(&ValWrapper::from(key.clone())).filter(|v| **v != Value::Nil).cloned()
+    }
+
+    /// The entry after `key` in the table's traversal order, or the first entry if `key` is nil
+    /// Returns `None` once the traversal is over
+    /// Fields cleared during the traversal are still valid keys
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, LuaError> {
+        let start = match key {
+            Value::Nil => 0,
+            _ => {
+                let invalid = || LuaError::new("invalid key to 'next'");
+                if matches!(key, Value::Float(f) if f.is_nan()) { return Err(invalid()); }
+                self.inner.get_index_of(&ValWrapper::from(key.clone())).ok_or_else(invalid)? + 1
+            },
+        };
+        let entry = self.inner.as_slice()[start..].iter().find(|(_, v)| **v != Value::Nil);
+        Ok(entry.map(|(k, v)| (k.inner.clone(), v.clone())))
     }
+
+    /// The length of the sequence part of the table, as given by the length operator
+    /// This is a border: `t[n]` is not nil and `t[n + 1]` is, or 0 if `t[1]` is nil
+    pub fn border(&self) -> i64 {
+        self.border
+    }
+}
+
+/// `t[key]`, without using metamethods
+pub fn raw_get(t: &Rc<RefCell<Table>>, key: &Value) -> Value {
+    match key {
+        // nil and NaN can't be keys, so no field has them
+        Value::Nil => Value::Nil,
+        Value::Float(f) if f.is_nan() => Value::Nil,
+        _ => t.borrow().get(key).unwrap_or(Value::Nil),
+    }
+}
+
+/// `t[key] = val`, without using metamethods
+pub fn raw_set(t: &Rc<RefCell<Table>>, key: &Value, val: Value) -> Result<(), LuaError> {
+    match key {
+        Value::Nil => Err(LuaError::new("index is nil")),
+        Value::Float(f) if f.is_nan() => Err(LuaError::new("index is NaN")),
+        _ => {
+            t.borrow_mut().insert(key, val);
+            Ok(())
+        },
+    }
+}
+
+/// How many `__index` or `__newindex` metamethods can be followed for one access, to catch loops
+const MAX_META_CHAIN: usize = 2000;
+
+/// `obj[key]`
+/// Fields a table doesn't have, and indexes of other values, go to the `__index` metamethod, which is either
+/// called or indexed in turn
+pub fn index(obj: &Value, key: &Value, ctx: &mut Ctx) -> Result<Value, LuaError> {
+    let mut obj = obj.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match &obj {
+            Value::Table(t) => {
+                let val = raw_get(t, key);
+                if val != Value::Nil { return Ok(val); }
+                match metamethod(&obj, "__index", ctx) {
+                    Some(handler) => handler,
+                    None => return Ok(Value::Nil),
+                }
+            },
+            _ => metamethod(&obj, "__index", ctx).ok_or_else(|| index_error(&obj, None))?,
+        };
+        if let Value::Function(_) = handler {
+            return Ok(call_value(&handler, vec![obj, key.clone()], ctx)?.into_iter().next().unwrap_or(Value::Nil));
+        }
+        obj = handler;
+    }
+    Err(LuaError::new("'__index' chain too long; possible loop"))
+}
+
+/// `obj[key] = val`
+/// Assignments to fields a table doesn't have, and to fields of other values, go to the `__newindex` metamethod,
+/// which is either called or assigned to in turn
+pub fn set_index(obj: &Value, key: &Value, val: Value, ctx: &mut Ctx) -> Result<(), LuaError> {
+    let mut obj = obj.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match &obj {
+            Value::Table(t) => match metamethod(&obj, "__newindex", ctx) {
+                Some(handler) if raw_get(t, key) == Value::Nil => handler,
+                _ => return raw_set(t, key, val),
+            },
+            _ => metamethod(&obj, "__newindex", ctx).ok_or_else(|| index_error(&obj, None))?,
+        };
+        if let Value::Function(_) = handler {
+            call_value(&handler, vec![obj, key.clone(), val], ctx)?;
+            return Ok(());
+        }
+        obj = handler;
+    }
+    Err(LuaError::new("'__newindex' chain too long; possible loop"))
+}
+
+/// The error for indexing `obj`, which can't be indexed, where `var` describes the variable it came from
+pub fn index_error(obj: &Value, var: Option<String>) -> LuaError {
+    let var = var.map(|var| format!(" ({var})")).unwrap_or_default();
+    LuaError::new(format!("attempt to index a {} value{var}", obj.type_name()))
+}
+
+/// Check that `obj`, the value of `exp`, can be indexed at all, so the error can name the variable `obj` came from
+/// Only tables and values with a metatable can be indexed
+pub fn check_indexable(obj: &Value, exp: &Expression, ctx: &Ctx) -> Result<(), LuaError> {
+    check_indexable_as(obj, || exp.describe(ctx), ctx)
+}
+
+/// Like `check_indexable`, where `describe` names the variable `obj` came from, as `Expression::describe` does
+pub fn check_indexable_as(obj: &Value, describe: impl FnOnce() -> Option<String>, ctx: &Ctx) -> Result<(), LuaError> {
+    match (obj, get_metatable(obj, ctx)) {
+        (Value::Table(_), _) | (_, Some(_)) => Ok(()),
+        _ => Err(index_error(obj, describe())),
+    }
+}
+
+/// A field of a table constructor
+#[derive(Clone, Debug)]
+pub enum Field {
+    /// `name = exp`
+    Named(Identifier, Expression),
+    /// `[key] = exp`
+    Keyed(Expression, Expression),
+    /// `exp`, which gets the next integer key
+    Positional(Expression),
 }
 
 #[derive(Clone, Debug)]
 pub struct TableConstructor {
-    record: Vec<(Identifier, Expression)>,
-    sequence: Vec<Expression>,
-    general: Vec<(Expression, Expression)>,
+    /// The fields in source order
+    fields: Vec<Field>,
+    span: Span,
 }
 
 impl TableConstructor {
-    pub fn parse(lex: &mut Lexer) -> Option<TableConstructor> {
-        let mut record = Vec::new();
-        let mut sequence = Vec::new();
-        let mut general = Vec::new();
-
-        if lex.next() == Some(Lexeme::Seperator(Seperator::OpenCurly)) {
-            while lex.clone().next() != Some(Lexeme::Seperator(Seperator::CloseCurly)) {
-                if lex.clone().next().is_none() { return None }
-
-                // try to parse expression (won't capture record assigment)
-                let lex_clone = lex.clone();
-                if let Some(exp) = parse_expression(lex) {
-                    sequence.push(exp);
-                    continue;
-                }
-                *lex = lex_clone;
-                if let Some(Lexeme::Identifier(ident)) = lex.next() 
-                && lex.next() == Some(Lexeme::Assignment(Assignment {})) 
-                && let Some(exp) = parse_expression(lex)
-                {
-                    // parsing record
-                    record.push((ident, exp));
-                    continue;
+    /// Parse a table constructor, returning `None` if the lexer isn't at one
+    pub fn parse(lex: &mut Parser) -> Result<Option<TableConstructor>, ParseError> {
+        if lex.peek_lexeme() != Some(Lexeme::Seperator(Seperator::OpenCurly)) {
+            return Ok(None);
+        }
+        lex.next();
+        let start = lex.last_span().clone();
+        let mut fields = Vec::new();
+        while lex.peek_lexeme() != Some(Lexeme::Seperator(Seperator::CloseCurly)) {
+            fields.push(Self::parse_field(lex)?);
+            // fields are seperated by ',' or ';', and there can be one after the last field
+            match lex.peek_lexeme() {
+                Some(Lexeme::Seperator(Seperator::Comma | Seperator::Semicolon)) => { lex.next(); },
+                Some(Lexeme::Seperator(Seperator::CloseCurly)) => {},
+                _ => return Err(ParseError::expected_closing("'}'", "'{'", &start, lex)),
+            }
+        }
+        lex.next();
+        Ok(Some(TableConstructor { fields, span: start.to(lex.last_span()) }))
+    }
+
+    fn parse_field(lex: &mut Parser) -> Result<Field, ParseError> {
+        match (lex.peek_lexeme(), lex.peek_nth_lexeme(1)) {
+            (Some(Lexeme::Identifier(name)), Some(Lexeme::Assignment(_))) => {
+                lex.next();
+                lex.next();
+                Ok(Field::Named(Identifier::from(name), expect_expression(lex)?))
+            },
+            (Some(Lexeme::Seperator(Seperator::OpenBracket)), _) => {
+                lex.next();
+                let key = expect_expression(lex)?;
+                if lex.peek_lexeme() != Some(Lexeme::Seperator(Seperator::CloseBracket)) {
+                    return Err(ParseError::expected("']'", lex));
                 }
-                *lex = lex_clone;
-                if lex.next() == Some(Lexeme::Seperator(Seperator::OpenBracket))
-                && let Some(lhs) = parse_expression(lex)
-                && lex.next() == Some(Lexeme::Seperator(Seperator::CloseBracket))
-                && lex.next() == Some(Lexeme::Assignment(Assignment {})) 
-                && let Some(rhs) = parse_expression(lex)
-                {
-                    general.push((lhs, rhs));
+                lex.next();
+                if lex.peek_lexeme() != Some(Lexeme::Assignment(Assignment {})) {
+                    return Err(ParseError::expected("'='", lex));
                 }
-            } 
-            lex.next();
+                lex.next();
+                Ok(Field::Keyed(key, expect_expression(lex)?))
+            },
+            _ => Ok(Field::Positional(expect_expression(lex)?)),
+        }
+    }
+
+    /// Create the table, evaluating the fields in order
+    /// Positional fields get the keys 1, 2, ..., and if the last field is positional all its values are used
+    /// Like the reference implementation, positional values are stored after the other fields, so they win over
+    /// explicit integer keys
+    pub fn eval(&self, ctx: &mut Ctx) -> Result<Value, LuaError> {
+        let table = Table::new();
+        let mut positional = Vec::new();
+        for (i, field) in self.fields.iter().enumerate() {
+            match field {
+                Field::Named(name, exp) => {
+                    let val = exp.eval(ctx)?;
+                    raw_set(&table, &Value::String(name.0.as_str().into()), val)?;
+                },
+                Field::Keyed(key, exp) => {
+                    let key = key.eval(ctx)?;
+                    let val = exp.eval(ctx)?;
+                    raw_set(&table, &key, val)?;
+                },
+                Field::Positional(exp) if i + 1 == self.fields.len() => positional.append(&mut exp.eval_multi(ctx)?),
+                Field::Positional(exp) => positional.push(exp.eval(ctx)?),
+            }
+        }
+        for (key, val) in (1..).zip(positional) {
+            raw_set(&table, &Value::Integer(key), val)?;
+        }
+        Ok(Value::Table(table))
+    }
 
-            Some(TableConstructor { record, sequence, general })
-        } else { None }
+    pub fn span(&self) -> &Span {
+        &self.span
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum TableAccess {
-    DotAccess(Box<Expression>, Identifier)
+    DotAccess(Box<Expression>, Identifier),
+    /// `table[key]`
+    Index(Box<Expression>, Box<Expression>),
 }
 
 impl TableAccess {
     /* 
-    pub fn parse(lex: &mut Lexer) -> Option<TableAccess> {
+    pub fn parse(lex: &mut Parser) -> Option<TableAccess> {
         if let Some(Lexeme::Identifier(tname)) = lex.next()
             && lex.next() == Some(Lexeme::Seperator(Seperator::Dot))
             && let Some(Lexeme::Identifier(vname)) = lex.next() 
@@ -169,53 +383,26 @@ impl TableAccess {
     pub fn new_dot(obj: Expression, field: Identifier) -> TableAccess {
         TableAccess::DotAccess(Box::new(obj), field)
     }
-}
 
-#[derive(Clone, Debug)]
-pub enum TableAssign {
-    DotAssign(Identifier, Identifier, Expression)
-}
-
-impl TableAssign {
-    pub fn parse(lex: &mut Lexer) -> Option<TableAssign> {
-        if let Some(Lexeme::Identifier(tname)) = lex.next()
-            && lex.next() == Some(Lexeme::Seperator(Seperator::Dot))
-            && let Some(Lexeme::Identifier(vname)) = lex.next() 
-            && lex.next() == Some(Lexeme::Assignment(Assignment {  })) 
-            && let Some(exp) = parse_expression(lex) 
-        {
-            Some(TableAssign::DotAssign(tname, vname, exp))
-        } else { None }
-    }
-
-    pub fn print_tree(&self, depth: usize) {
-        let tabs = "\t".repeat(depth);
-        println!("{tabs}Table Assignment [");
-        match self {
-            TableAssign::DotAssign(obj, field, exp) => {
-                println!("{tabs}\ttable: {}", obj.0);
-                println!("{tabs}\tfield: {}", field.0);
-                println!("{tabs}\tvalue: {}", exp);
-            }
-        }
-        println!("{tabs}]");
+    pub fn new_index(obj: Expression, key: Expression) -> TableAccess {
+        TableAccess::Index(Box::new(obj), Box::new(key))
     }
 
-    pub fn walk(&self, ctx: &mut Ctx) {
-        todo!()
+    /// Evaluate the indexed object and the key
+    pub fn resolve(&self, ctx: &mut Ctx) -> Result<(Value, Value), LuaError> {
+        let (obj_exp, obj, key) = match self {
+            TableAccess::DotAccess(obj_exp, field) => (obj_exp, obj_exp.eval(ctx)?, Value::String(field.0.as_str().into())),
+            TableAccess::Index(obj_exp, key) => {
+                let obj = obj_exp.eval(ctx)?;
+                (obj_exp, obj, key.eval(ctx)?)
+            },
+        };
+        check_indexable(&obj, obj_exp, ctx)?;
+        Ok((obj, key))
     }
-}
 
-impl Display for TableAssign {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Table Assignment [")?;
-        match self {
-            TableAssign::DotAssign(obj, field, exp) => {
-                writeln!(f, "\ttable: {}", obj.0)?;
-                writeln!(f, "\tfield: {}", field.0)?;
-                writeln!(f, "\tvalue: {}", exp)?;
-            }
-        }
-        write!(f, "]")
+    pub fn eval(&self, ctx: &mut Ctx) -> Result<Value, LuaError> {
+        let (obj, key) = self.resolve(ctx)?;
+        index(&obj, &key, ctx)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,68 @@
+use super::{number::Number, Value};
+
+fn number(s: &str) -> Number {
+    s.parse().unwrap_or_else(|_| panic!("{s} should convert to a number"))
+}
+
+#[test]
+fn number_formatting() {
+    assert_eq!(Number::Integer(1).to_string(), "1");
+    assert_eq!(Number::Float(1.0).to_string(), "1.0");
+    assert_eq!(Number::Float(-0.0).to_string(), "-0.0");
+    assert_eq!(Number::Float(0.1).to_string(), "0.1");
+    assert_eq!(Number::Float(1.0 / 3.0).to_string(), "0.33333333333333");
+    assert_eq!(Number::Float(1e15).to_string(), "1e+15");
+    assert_eq!(Number::Float(123456789012345.0).to_string(), "1.2345678901234e+14");
+    assert_eq!(Number::Float(2.5e-5).to_string(), "2.5e-05");
+    assert_eq!(Number::Float(f64::INFINITY).to_string(), "inf");
+    assert_eq!(Number::Float(f64::NEG_INFINITY).to_string(), "-inf");
+}
+
+#[test]
+fn string_conversion() {
+    assert_eq!(number(" 10 ").type_name(), "integer");
+    assert_eq!(number("-0x10"), Number::Integer(-16));
+    assert_eq!(number("1e2").type_name(), "float");
+    assert!("10a".parse::<Number>().is_err());
+    assert!("".parse::<Number>().is_err());
+    assert!("- 1".parse::<Number>().is_err());
+}
+
+#[test]
+fn integer_arithmetic() {
+    assert_eq!(Number::Integer(i64::MAX) + Number::Integer(1), Number::Integer(i64::MIN));
+    assert_eq!(Number::Integer(7).floor_div(Number::Integer(-2)), Some(Number::Integer(-4)));
+    assert_eq!(Number::Integer(-7).modulo(Number::Integer(3)), Some(Number::Integer(2)));
+    assert_eq!(Number::Integer(1).floor_div(Number::Integer(0)), None);
+    assert_eq!(Number::Integer(1).modulo(Number::Integer(0)), None);
+    assert_eq!(Number::Integer(i64::MIN).floor_div(Number::Integer(-1)), Some(Number::Integer(i64::MIN)));
+    assert_eq!((Number::Integer(3) / Number::Integer(1)).type_name(), "float");
+}
+
+#[test]
+fn float_arithmetic() {
+    assert_eq!(Number::Float(7.0).floor_div(Number::Integer(-2)).map(Number::type_name), Some("float"));
+    assert_eq!(Number::Float(-7.5).modulo(Number::Integer(2)), Some(Number::Float(0.5)));
+    assert_eq!(Number::Float(5.0).modulo(Number::Float(f64::INFINITY)), Some(Number::Float(5.0)));
+    assert_eq!(Number::Float(-5.0).modulo(Number::Float(f64::INFINITY)), Some(Number::Float(f64::INFINITY)));
+}
+
+#[test]
+fn mixed_comparisons() {
+    // 2^53 + 1 can't be represented as a float
+    let big = Number::Integer((1 << 53) + 1);
+    let float = Number::Float((1u64 << 53) as f64);
+    assert!(big != float);
+    assert!(float < big);
+    assert!(Number::Integer(1) == Number::Float(1.0));
+    assert!(Number::Integer(i64::MAX) < Number::Float(9223372036854775808.0));
+    assert!(Number::Integer(1).partial_cmp(&Number::Float(f64::NAN)).is_none());
+}
+
+#[test]
+fn float_keys_are_normalized() {
+    let t = super::table::Table::new();
+    t.borrow_mut().insert(&Value::Float(2.0), Value::Boolean(true.into()));
+    assert!(t.borrow().get(&Value::Integer(2)).is_some());
+    assert!(t.borrow().get(&Value::Float(2.0)).is_some());
+}
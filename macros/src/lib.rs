@@ -2,7 +2,6 @@
 
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, Ident};
 
 #[proc_macro_derive(VariantsToStr)]
 pub fn variants(item: TokenStream) -> TokenStream {
@@ -13,29 +12,26 @@ pub fn variants(item: TokenStream) -> TokenStream {
             enum_item.variants.into_iter().map(|v| v.ident)
         }
         _ => panic!("VariantsToStr only works on enums"),
-    };
-
-    let variants_str = variants.clone().map(|ident| ident.to_string());
-    let variants_str_lower = variants_str.clone().map(|str| str.to_lowercase());
-    let variants_lens = variants_str.clone().map(|s| s.len());
+    }.collect::<Vec<_>>();
 
+    let variants_str = variants.iter().map(|ident| ident.to_string());
+    let variants_str_lower = variants_str.clone().map(|str| str.to_lowercase()).collect::<Vec<_>>();
     let enum_name = syn_item.ident;
-    let num = variants.clone().count();
 
     let expanded = quote! {
         impl #enum_name {
-            const fn all_variants_str() -> [&'static str; #num] 
-            {
-                [ #(#variants_str),* ]
+            /// The variant whose lowercase name is exactly `s`
+            pub fn from_name(s: &str) -> Option<#enum_name> {
+                match s {
+                    #(#variants_str_lower => Some(Self::#variants),)*
+                    _ => None,
+                }
             }
 
-            fn str_to_variant(s: &str) -> Option<(#enum_name, usize)> {
-                #(
-                    if s.len() >= #variants_lens && &s[..#variants_lens] == #variants_str_lower {
-                        return Some((Self::#variants, #variants_lens));
-                    }
-                )*
-                return None;
+            fn as_str(&self) -> &'static str {
+                match self {
+                    #(Self::#variants => #variants_str_lower),*
+                }
             }
         }
     };